@@ -0,0 +1,2898 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Assertions over the behavior of the subsystem, driven through its handle.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use polkadot_node_subsystem::{ActivatedLeaf, ActiveLeavesUpdate, FromOverseer, OverseerSignal, jaeger};
+use polkadot_node_subsystem::messages::AllMessages;
+use futures::{SinkExt, StreamExt};
+use futures::channel::oneshot;
+use futures::future::{self, Either, Future};
+use polkadot_node_subsystem_util::TimeoutExt;
+use polkadot_primitives::v1::{BlockNumber, Hash, SessionIndex, ValidatorIndex};
+
+use crate::{TestSubsystemContextHandle, assertion_timeout};
+use crate::fixtures::{self, comm, duplicate, signal};
+use crate::kind::{MessageKind, MessageKindExt};
+use crate::projection::{self, Projection};
+#[cfg(any(test, feature = "structural-eq"))]
+use crate::structural_eq::project;
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Assert the next messages are an interleaving of the expected per-leaf sequences.
+	///
+	/// Messages of concurrently processed leaves may be interleaved arbitrarily, but the messages
+	/// of each leaf must appear in the given relative order. Messages are compared structurally.
+	#[cfg(any(test, feature = "structural-eq"))]
+	pub async fn assert_interleaving(&mut self, per_leaf_expected: HashMap<Hash, Vec<AllMessages>>) {
+		self.assert_interleaving_with(per_leaf_expected, projection::relay_parent).await
+	}
+
+	/// Like [`Self::assert_interleaving`], locating the leaf of a message with `projection`.
+	#[cfg(any(test, feature = "structural-eq"))]
+	pub async fn assert_interleaving_with(
+		&mut self,
+		per_leaf_expected: HashMap<Hash, Vec<AllMessages>>,
+		projection: impl Projection<Hash>,
+	) {
+		let total = per_leaf_expected.values().map(Vec::len).sum::<usize>();
+		let mut remaining = per_leaf_expected
+			.into_iter()
+			.map(|(leaf, expected)| (leaf, expected.into_iter().collect::<VecDeque<_>>()))
+			.collect::<HashMap<_, _>>();
+
+		for position in 0..total {
+			let msg = self.recv().await;
+			let leaf = match projection.project(&msg) {
+				Some(leaf) => leaf,
+				None => panic!("Message #{} is not bound to any leaf: {:?}", position, msg),
+			};
+
+			let expected = match remaining.get_mut(&leaf).and_then(VecDeque::pop_front) {
+				Some(expected) => expected,
+				None => panic!("Unexpected message #{} for leaf {}: {:?}", position, leaf, msg),
+			};
+
+			if project(&expected) != project(&msg) {
+				panic!(
+					"Subsequence of leaf {} broke at message #{}: expected {}, received {}",
+					leaf,
+					position,
+					project(&expected),
+					project(&msg),
+				);
+			}
+		}
+
+		let extra = self.drain();
+		assert!(extra.is_empty(), "Unexpected additional messages: {:?}", extra);
+	}
+}
+
+/// Keep receiving messages until those received so far satisfy `predicate`.
+///
+/// The predicate is checked before the first receive and after each one. Panics listing the
+/// accumulated messages if it doesn't hold within `timeout`. Returns the accumulated messages.
+pub async fn assert_eventually<M>(
+	handle: &mut TestSubsystemContextHandle<M>,
+	timeout: Duration,
+	predicate: impl Fn(&[AllMessages]) -> bool,
+) -> Vec<AllMessages> {
+	let deadline = Instant::now() + timeout;
+	let mut received = Vec::new();
+
+	while !predicate(&received) {
+		let remaining = deadline.saturating_duration_since(Instant::now());
+		match handle.try_recv().timeout(remaining).await {
+			Some(Some(msg)) => received.push(msg),
+			Some(None) => panic!(
+				"The subsystem hung up before the predicate held, received {} messages: {:?}",
+				received.len(),
+				received,
+			),
+			None => panic!(
+				"The predicate didn't hold within {:?}, received {} messages: {:?}",
+				timeout,
+				received.len(),
+				received,
+			),
+		}
+	}
+
+	received
+}
+
+/// How long the subsystem may take to exit once it read `Conclude`, half the
+/// [`harness_timeout`](crate::harness_timeout).
+pub fn exit_timeout() -> Duration {
+	assertion_timeout()
+}
+
+/// What a subsystem does with inputs still queued when it reads `Conclude`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainPolicy {
+	/// The subsystem processes every queued input, sending `outputs_per_input` messages each.
+	Drains {
+		/// The number of messages the subsystem sends per input processed.
+		outputs_per_input: usize,
+	},
+	/// The subsystem abandons the queued inputs and sends nothing.
+	Abandons,
+}
+
+/// How long the subsystem must stay silent for its reaction to an input to be considered complete.
+pub(crate) const QUIET_PERIOD: Duration = Duration::from_millis(50);
+
+/// The subsystem kept sending instead of falling silent, see
+/// [`TestSubsystemContextHandle::drain_when_quiet`].
+#[derive(Debug)]
+pub(crate) struct NotQuiet {
+	/// The messages received until giving up.
+	pub(crate) received: Vec<AllMessages>,
+}
+
+impl NotQuiet {
+	/// Panic with the messages received, describing what the subsystem didn't settle `after`.
+	pub(crate) fn report(self, after: impl fmt::Display) -> ! {
+		panic!(
+			"The subsystem didn't fall silent within {:?} {}, received {} messages: {:?}",
+			assertion_timeout(),
+			after,
+			self.received.len(),
+			self.received,
+		)
+	}
+}
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Deliver `work` immediately followed by `Conclude`, asserting the subsystem exits.
+	///
+	/// `Conclude` is queued as soon as `work` has been handed over, without waiting for the
+	/// subsystem to react to it, to provoke races between processing and shutdown. The
+	/// subsystem may finish or abandon the work, but must exit within [`exit_timeout`], which is
+	/// detected by it dropping all of its senders. Run on a [`LocalPoolExecutor`] to make the
+	/// interleaving reproducible.
+	///
+	/// Returns the messages the subsystem sent until it exited.
+	///
+	/// [`LocalPoolExecutor`]: crate::LocalPoolExecutor
+	pub async fn send_work_then_conclude(&mut self, work: FromOverseer<M>) -> Vec<AllMessages> {
+		self.observe(&work);
+		self.tx.feed(work).await.expect("Test subsystem no longer live");
+		self.send(signal(OverseerSignal::Conclude)).await;
+
+		self.await_exit().await
+	}
+
+	/// Queue all of `pending` followed by `Conclude` at once and assert the subsystem treats the
+	/// queued inputs according to `policy` before exiting.
+	///
+	/// The inputs are queued on the sink without waiting for the subsystem to read them, so they
+	/// are all pending when it starts processing. The outputs the subsystem sent until it exited
+	/// must match `policy`, and a draining subsystem must have read every input, `Conclude`
+	/// included. Returns the messages the subsystem sent until it exited.
+	pub async fn assert_drains_before_conclude(
+		&mut self,
+		pending: Vec<FromOverseer<M>>,
+		policy: DrainPolicy,
+	) -> Vec<AllMessages> {
+		let inputs = pending.len();
+		let queued = pending
+			.into_iter()
+			.chain(std::iter::once(signal(OverseerSignal::Conclude)))
+			.collect::<Vec<_>>();
+		for from_overseer in &queued {
+			self.observe(from_overseer);
+		}
+		self.tx.enqueue_all(queued);
+
+		let sent = self.await_exit().await;
+		let unread = self.tx.len();
+
+		let expected = match policy {
+			DrainPolicy::Drains { outputs_per_input } => {
+				assert_eq!(unread, 0, "The subsystem exited with {} of {} inputs unread", unread, inputs + 1);
+				inputs * outputs_per_input
+			}
+			DrainPolicy::Abandons => 0,
+		};
+
+		assert!(
+			sent.len() == expected,
+			"Expected {:?} for {} queued inputs, but the subsystem sent {} messages: {:?}",
+			policy,
+			inputs,
+			sent.len(),
+			sent,
+		);
+
+		sent
+	}
+
+	/// Send `Conclude` and assert the subsystem exits within [`exit_timeout`], having sent a final
+	/// message satisfying `expected_final` last, if given, e.g. a flush it performs on shutdown.
+	///
+	/// Without a final message to expect, pass `None::<fn(&AllMessages) -> bool>`. Panics if
+	/// messages the test didn't take are pending before `Conclude`, so they aren't mistaken for
+	/// the reaction to it, or if the subsystem hung or skipped the final message. Returns the
+	/// messages the subsystem sent in reaction to `Conclude`.
+	pub async fn assert_clean_shutdown(
+		&mut self,
+		expected_final: Option<impl Fn(&AllMessages) -> bool>,
+	) -> Vec<AllMessages> {
+		let pending = self.drain();
+		assert!(
+			pending.is_empty(),
+			"{} messages were pending before Conclude: {:?}",
+			pending.len(),
+			pending,
+		);
+
+		self.send(signal(OverseerSignal::Conclude)).await;
+		let sent = self.await_exit().await;
+
+		if let Some(final_matcher) = expected_final {
+			match sent.last() {
+				Some(last) if final_matcher(last) => {}
+				_ => panic!(
+					"The subsystem exited without sending the expected final message, sent {} messages: {:?}",
+					sent.len(),
+					sent,
+				),
+			}
+		}
+
+		sent
+	}
+
+	/// Deliver `request`, wait for the subsystem to start processing it, then deliver
+	/// `interleaved_signal`.
+	///
+	/// The subsystem is considered to have started once it sends its first message in reaction
+	/// to `request`, which is returned once the signal has been read, e.g. to answer it and
+	/// assert how the subsystem resumes. Panics if the subsystem doesn't react within half
+	/// the harness timeout.
+	pub async fn send_signal_during_request(
+		&mut self,
+		request: FromOverseer<M>,
+		interleaved_signal: OverseerSignal,
+	) -> AllMessages {
+		self.send(request).await;
+
+		let timeout = assertion_timeout();
+		let first = match self.try_recv().timeout(timeout).await {
+			Some(Some(msg)) => msg,
+			Some(None) => panic!("The subsystem hung up instead of processing the request"),
+			None => panic!("The subsystem didn't start processing the request within {:?}", timeout),
+		};
+
+		self.send(signal(interleaved_signal)).await;
+		first
+	}
+
+	/// Send `request` and assert the subsystem acknowledges it before doing the work it asks for.
+	///
+	/// The first message the subsystem sends must satisfy `ack_matcher`, a later one
+	/// `work_matcher`. Messages in between are kept, to be handed out by subsequent receives.
+	/// Returns the acknowledgement and the work message, or panics naming the ordering
+	/// expectation which failed.
+	pub async fn assert_acks_first(
+		&mut self,
+		request: M,
+		ack_matcher: impl Fn(&AllMessages) -> bool,
+		work_matcher: impl Fn(&AllMessages) -> bool,
+	) -> (AllMessages, AllMessages) {
+		self.send(comm(request)).await;
+
+		let timeout = assertion_timeout();
+		let deadline = Instant::now() + timeout;
+		let ack = match self.try_recv().timeout(timeout).await {
+			Some(Some(msg)) if ack_matcher(&msg) => msg,
+			Some(Some(msg)) if work_matcher(&msg) => panic!(
+				"Expected the acknowledgement first, but the work came before it: {:?}",
+				msg,
+			),
+			Some(Some(msg)) => panic!("Expected the acknowledgement first, received {:?}", msg),
+			Some(None) => panic!("Expected the acknowledgement first, but the subsystem hung up"),
+			None => panic!("Expected the acknowledgement first, received nothing within {:?}", timeout),
+		};
+
+		let mut skipped = Vec::new();
+		let work = loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			match self.try_recv().timeout(remaining).await {
+				Some(Some(msg)) if work_matcher(&msg) => break msg,
+				Some(Some(msg)) => skipped.push(msg),
+				Some(None) => panic!(
+					"Expected the work after the acknowledgement, but the subsystem hung up after sending {:?}",
+					skipped,
+				),
+				None => panic!(
+					"Expected the work after the acknowledgement, received only {:?} within {:?}",
+					skipped,
+					timeout,
+				),
+			}
+		};
+
+		for msg in skipped.into_iter().rev() {
+			self.buffered.push_front(msg);
+		}
+
+		(ack, work)
+	}
+
+	/// Finalize the block `hash` at height `number` and assert the subsystem propagates the
+	/// finality with a message satisfying `downstream_matcher`.
+	///
+	/// Messages sent before the matching one are kept, to be handed out by subsequent receives.
+	/// Panics with the messages received instead if none matches within half the harness timeout.
+	/// Returns the matching message.
+	pub async fn assert_propagates_finality(
+		&mut self,
+		hash: Hash,
+		number: BlockNumber,
+		downstream_matcher: impl Fn(&AllMessages) -> bool,
+	) -> AllMessages {
+		self.send(fixtures::finalized(hash, number)).await;
+
+		let timeout = assertion_timeout();
+		let deadline = Instant::now() + timeout;
+		let mut skipped = Vec::new();
+		let propagated = loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			match self.try_recv().timeout(remaining).await {
+				Some(Some(msg)) if downstream_matcher(&msg) => break msg,
+				Some(Some(msg)) => skipped.push(msg),
+				Some(None) => panic!(
+					"The subsystem hung up instead of propagating the finality of {}, after sending {:?}",
+					hash,
+					skipped,
+				),
+				None => panic!(
+					"The subsystem didn't propagate the finality of {} within {:?}, received only {:?}",
+					hash,
+					timeout,
+					skipped,
+				),
+			}
+		};
+
+		for msg in skipped.into_iter().rev() {
+			self.buffered.push_front(msg);
+		}
+
+		propagated
+	}
+
+	/// Advance the virtual clock by `period` `count` times, asserting the subsystem sends a
+	/// message satisfying `matcher` in each cycle.
+	///
+	/// After each advance, messages are collected until the subsystem falls silent. Panics
+	/// naming the first cycle without a matching message. Returns the matching messages, one or
+	/// more per cycle.
+	pub async fn assert_periodic(
+		&mut self,
+		period: Duration,
+		matcher: impl Fn(&AllMessages) -> bool,
+		count: usize,
+	) -> Vec<AllMessages> {
+		let mut periodic = Vec::new();
+
+		for cycle in 0..count {
+			self.clock.advance(period);
+			let (matching, other): (Vec<_>, Vec<_>) = self
+				.drain_settled(&format!("in cycle #{} of {}", cycle, count))
+				.await
+				.into_iter()
+				.partition(|msg| matcher(msg));
+
+			assert!(
+				!matching.is_empty(),
+				"No periodic message in cycle #{} of {}, {:?} into the test, received {:?}",
+				cycle,
+				count,
+				self.clock.now(),
+				other,
+			);
+			periodic.extend(matching);
+		}
+
+		periodic
+	}
+
+	/// Queue all `inputs` at once and assert the subsystem reacts with at most `max_downstream`
+	/// messages before falling silent, e.g. because it coalesces requests into batches.
+	///
+	/// The inputs are queued on the sink without waiting for the subsystem to read them, so they
+	/// are all pending when it starts processing. Returns the messages.
+	pub async fn assert_coalesced(
+		&mut self,
+		inputs: Vec<FromOverseer<M>>,
+		max_downstream: usize,
+	) -> Vec<AllMessages> {
+		let count = inputs.len();
+		for from_overseer in inputs {
+			self.observe(&from_overseer);
+			self.tx.enqueue(from_overseer);
+		}
+
+		let sent = self.drain_settled("after the inputs to coalesce").await;
+		assert!(
+			sent.len() <= max_downstream,
+			"The subsystem sent {} messages for {} inputs, expected at most {}: {:?}",
+			sent.len(),
+			count,
+			max_downstream,
+			sent,
+		);
+
+		sent
+	}
+
+	/// Wait for the subsystem to fall silent, asserting the sequence numbers `projection` finds in
+	/// the messages it sent strictly increase.
+	///
+	/// Messages without a sequence number are skipped. Panics with the first pair of messages
+	/// whose sequence numbers don't increase. Returns all messages received.
+	pub async fn assert_monotonic_sequence(&mut self, projection: impl Projection<u64>) -> Vec<AllMessages> {
+		let received = self.drain_settled("before its sequence numbers could be checked").await;
+
+		let numbered = received.iter()
+			.filter_map(|msg| projection.project(msg).map(|number| (number, msg)))
+			.collect::<Vec<_>>();
+		if let Some(pair) = numbered.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+			panic!(
+				"Sequence number {} follows {}, in {:?} after {:?}",
+				pair[1].0,
+				pair[0].0,
+				pair[1].1,
+				pair[0].1,
+			);
+		}
+
+		received
+	}
+
+	/// Wait for the subsystem to fall silent, asserting no two messages it sent are identical by
+	/// the key `projection` finds in them, e.g. the gossiped payload ignoring responders.
+	///
+	/// Messages without a key are not checked. Panics with the first duplicate pair. Returns all
+	/// messages received.
+	pub async fn assert_no_duplicate_output<K>(&mut self, projection: impl Projection<K>) -> Vec<AllMessages>
+	where
+		K: Eq + std::hash::Hash + fmt::Debug,
+	{
+		let received = self.drain_settled("before its output could be checked for duplicates").await;
+
+		let mut seen = HashMap::new();
+		for (i, msg) in received.iter().enumerate() {
+			if let Some(key) = projection.project(msg) {
+				if let Some(first) = seen.get(&key) {
+					panic!(
+						"Messages #{} and #{} are duplicates by {:?}: {:?}, {:?}",
+						first,
+						i,
+						key,
+						received[*first],
+						msg,
+					);
+				}
+				seen.insert(key, i);
+			}
+		}
+
+		received
+	}
+
+	/// Queue all of `burst` at once and assert the subsystem settles within `quiescent` of
+	/// virtual time, rather than processing forever.
+	///
+	/// The virtual clock is advanced in tenths of `quiescent`, so timers of the subsystem fire
+	/// along the way. The subsystem has settled once it read all inputs and stayed silent for a
+	/// short while after an advance. Panics if it hasn't by the time `quiescent` elapsed, or if it
+	/// keeps sending for half the harness timeout in real time. Returns the messages sent until it
+	/// settled.
+	pub async fn assert_recovers_from_burst(
+		&mut self,
+		burst: Vec<FromOverseer<M>>,
+		quiescent: Duration,
+	) -> Vec<AllMessages> {
+		const STEPS: u32 = 10;
+
+		let inputs = burst.len();
+		for from_overseer in burst {
+			self.observe(&from_overseer);
+			self.tx.enqueue(from_overseer);
+		}
+
+		let deadline = Instant::now() + assertion_timeout();
+		let mut sent = Vec::new();
+		for step in 0..=STEPS {
+			if step > 0 {
+				self.clock.advance(quiescent / STEPS);
+			}
+
+			let reaction = match self.drain_when_quiet_by(QUIET_PERIOD, deadline).await {
+				Ok(reaction) => reaction,
+				Err(NotQuiet { received }) => panic!(
+					"The subsystem kept sending for {:?} after a burst of {} inputs, {:?} of virtual time \
+					into settling, {} remain unread, last of {} sent {:?}",
+					assertion_timeout(),
+					inputs,
+					quiescent / STEPS * step,
+					self.tx.len(),
+					sent.len() + received.len(),
+					received.last(),
+				),
+			};
+			if reaction.is_empty() && self.tx.is_empty() {
+				return sent;
+			}
+			sent.extend(reaction);
+		}
+
+		panic!(
+			"The subsystem didn't settle within {:?} after a burst of {} inputs, {} remain unread, last of {} sent {:?}",
+			quiescent,
+			inputs,
+			self.tx.len(),
+			sent.len(),
+			sent.last(),
+		);
+	}
+
+	/// Queue `trigger_burst` while the outbound channel is congested, asserting the subsystem
+	/// gets through it once the congestion clears, sending `expected_outputs` messages.
+	///
+	/// The handle stops reading and limits the outbound channel to the configured capacity, or
+	/// to a single message if none is configured, see [`Self::set_outbound_capacity`]. The
+	/// subsystem may block on its sends meanwhile, or handle the congestion otherwise, but must
+	/// not drop or duplicate messages. Once reading resumed and the previous capacity has been
+	/// restored, it must read all inputs of the burst. Panics naming the number of dropped or
+	/// duplicated messages. Returns the messages.
+	pub async fn assert_handles_backpressure(
+		&mut self,
+		trigger_burst: Vec<FromOverseer<M>>,
+		expected_outputs: usize,
+	) -> Vec<AllMessages> {
+		let previous = self.outbound.lock().capacity;
+		let inputs = trigger_burst.len();
+
+		self.stop_reading();
+		self.set_outbound_capacity(Some(previous.unwrap_or(1)));
+		for from_overseer in trigger_burst {
+			self.observe(&from_overseer);
+			self.tx.enqueue(from_overseer);
+		}
+		futures_timer::Delay::new(QUIET_PERIOD).await;
+
+		self.resume_reading();
+		self.set_outbound_capacity(previous);
+		let sent = self.drain_settled("after the congestion cleared").await;
+
+		let unread = self.tx.len();
+		assert!(
+			unread == 0,
+			"The subsystem didn't recover from the congestion, {} of {} inputs remain unread",
+			unread,
+			inputs,
+		);
+		assert!(
+			sent.len() >= expected_outputs,
+			"The subsystem dropped {} of {} messages under congestion: {:?}",
+			expected_outputs - sent.len(),
+			expected_outputs,
+			sent,
+		);
+		assert!(
+			sent.len() <= expected_outputs,
+			"The subsystem duplicated {} messages under congestion, sent {} instead of {}: {:?}",
+			sent.len() - expected_outputs,
+			sent.len(),
+			expected_outputs,
+			sent,
+		);
+
+		sent
+	}
+
+	/// Queue all `inputs` at once and assert the subsystem never has more than `max` downstream
+	/// requests, those satisfying `downstream_matcher`, outstanding at the same time.
+	///
+	/// Requests are kept outstanding until the subsystem falls silent, then the oldest one is
+	/// answered with `reply`, which hands it back if it has no response channel to reply on.
+	/// This repeats until no request is outstanding anymore, so a throttling subsystem gets to
+	/// issue all of its work. Panics with the peak observed if it exceeds `max`, or if the
+	/// subsystem keeps sending for half the harness timeout in real time. Returns the peak.
+	pub async fn assert_max_concurrent(
+		&mut self,
+		inputs: Vec<FromOverseer<M>>,
+		downstream_matcher: impl Fn(&AllMessages) -> bool,
+		mut reply: impl FnMut(AllMessages) -> Result<(), AllMessages>,
+		max: usize,
+	) -> usize {
+		for from_overseer in inputs {
+			self.observe(&from_overseer);
+			self.tx.enqueue(from_overseer);
+		}
+
+		let deadline = Instant::now() + assertion_timeout();
+		let mut outstanding = VecDeque::new();
+		let mut answered = 0;
+		let mut peak = 0;
+		loop {
+			let sent = match self.drain_when_quiet_by(QUIET_PERIOD, deadline).await {
+				Ok(sent) => sent,
+				Err(NotQuiet { received }) => panic!(
+					"The subsystem didn't fall silent within {:?} while throttling, {} downstream \
+					requests answered, {} outstanding, last received {:?}",
+					assertion_timeout(),
+					answered,
+					outstanding.len() + received.iter().filter(|msg| downstream_matcher(msg)).count(),
+					received.last(),
+				),
+			};
+			outstanding.extend(sent.into_iter().filter(|msg| downstream_matcher(msg)));
+			peak = std::cmp::max(peak, outstanding.len());
+			assert!(
+				peak <= max,
+				"The subsystem had {} downstream requests outstanding at once, more than {}",
+				peak,
+				max,
+			);
+
+			match outstanding.pop_front() {
+				Some(request) => if let Err(request) = reply(request) {
+					panic!("No response channel to reply on in {:?}", request);
+				},
+				None => break,
+			}
+			answered += 1;
+		}
+
+		peak
+	}
+
+	/// Assert every message the subsystem sends until it falls silent references an active leaf.
+	///
+	/// Leaves are tracked from the signals sent through the handle. Messages `projection` finds
+	/// no relay parent in are not checked. Messages still queued from before a deactivation are
+	/// checked against the leaves active now, so take them, e.g. with this assertion, before
+	/// deactivating. Returns the messages.
+	pub async fn assert_outputs_reference_known_leaves(
+		&mut self,
+		projection: impl Projection<Hash>,
+	) -> Vec<AllMessages> {
+		let sent = self.drain_settled("before the leaves it references could be checked").await;
+
+		for (position, msg) in sent.iter().enumerate() {
+			if let Some(relay_parent) = projection.project(msg) {
+				assert!(
+					self.active_leaves.contains(&relay_parent),
+					"Message #{} references {}, which is not an active leaf, active leaves are {:?}: {:?}",
+					position,
+					relay_parent,
+					self.active_leaves,
+					msg,
+				);
+			}
+		}
+
+		sent
+	}
+
+	/// Wait for the subsystem to drop all of its senders, returning the messages sent until then.
+	pub(crate) async fn await_exit(&mut self) -> Vec<AllMessages> {
+		let timeout = exit_timeout();
+		let deadline = Instant::now() + timeout;
+		let mut sent = self.drain();
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			match self.try_recv().timeout(remaining).await {
+				Some(Some(msg)) => sent.push(msg),
+				Some(None) => return sent,
+				None => panic!(
+					"The subsystem didn't exit within {:?} after Conclude, sent {} messages: {:?}",
+					timeout,
+					sent.len(),
+					sent,
+				),
+			}
+		}
+	}
+
+	/// Receive as many messages as `kinds` has entries, asserting each is addressed to the
+	/// corresponding subsystem. Returns the messages.
+	pub async fn assert_addressed_to(&mut self, kinds: &[MessageKind]) -> Vec<AllMessages> {
+		let mut received = Vec::with_capacity(kinds.len());
+		for _ in kinds {
+			received.push(self.recv().await);
+		}
+
+		let actual = received.iter().map(MessageKindExt::kind).collect::<Vec<_>>();
+		assert_eq!(actual, kinds, "Messages were not addressed as expected");
+
+		received
+	}
+
+	/// Receive the next message and assert it targets exactly the `expected` validators, in any
+	/// order. Returns the message.
+	pub async fn assert_next_targets_validators(&mut self, expected: &[ValidatorIndex]) -> AllMessages {
+		self.assert_next_targets_validators_with(expected, projection::validator_indices).await
+	}
+
+	/// Like [`Self::assert_next_targets_validators`], locating the targeted validators with
+	/// `projection`.
+	pub async fn assert_next_targets_validators_with(
+		&mut self,
+		expected: &[ValidatorIndex],
+		projection: impl Projection<Vec<ValidatorIndex>>,
+	) -> AllMessages {
+		let msg = self.recv().await;
+		let mut targeted = match projection.project(&msg) {
+			Some(targeted) => targeted,
+			None => panic!("The message doesn't target any validators: {:?}", msg),
+		};
+
+		let mut expected = expected.to_vec();
+		targeted.sort();
+		expected.sort();
+		assert_eq!(targeted, expected, "The message targets the wrong validators: {:?}", msg);
+
+		msg
+	}
+
+	/// Wait for the subsystem to fall silent, asserting all messages it sent which refer to a
+	/// session refer to the `expected` one, e.g. after a session change.
+	///
+	/// Messages without a session index are ignored. Panics naming the messages referring to
+	/// another session. Returns all messages received.
+	pub async fn assert_consistent_session(&mut self, expected: SessionIndex) -> Vec<AllMessages> {
+		self.assert_consistent_session_with(expected, projection::session_index).await
+	}
+
+	/// Like [`Self::assert_consistent_session`], locating the session of a message with
+	/// `projection`.
+	pub async fn assert_consistent_session_with(
+		&mut self,
+		expected: SessionIndex,
+		projection: impl Projection<SessionIndex>,
+	) -> Vec<AllMessages> {
+		let received = self.drain_settled("before its sessions could be checked").await;
+
+		let stale = received.iter()
+			.filter_map(|msg| projection.project(msg).map(|session| (session, msg)))
+			.filter(|(session, _)| *session != expected)
+			.collect::<Vec<_>>();
+		assert!(
+			stale.is_empty(),
+			"{} messages refer to another session than {}: {:?}",
+			stale.len(),
+			expected,
+			stale,
+		);
+
+		received
+	}
+
+	/// Drive a full request cycle: send the request built by `make_req` around a response
+	/// channel, expect the subsystem to acknowledge it with a message satisfying `ack_matcher`,
+	/// then to answer on the channel with a response satisfying `response_matcher`.
+	///
+	/// Each step must complete within the default timeout. Panics naming the step of the cycle
+	/// which failed and what was received instead. Returns the acknowledgement and the response.
+	pub async fn request_expect_ack_and_response<R: fmt::Debug>(
+		&mut self,
+		make_req: impl FnOnce(oneshot::Sender<R>) -> M,
+		ack_matcher: impl Fn(&AllMessages) -> bool,
+		response_matcher: impl Fn(&R) -> bool,
+	) -> (AllMessages, R) {
+		let (tx, rx) = oneshot::channel();
+		self.send(comm(make_req(tx))).await;
+
+		let timeout = assertion_timeout();
+		let ack = match self.try_recv().timeout(timeout).await {
+			Some(Some(msg)) if ack_matcher(&msg) => msg,
+			Some(Some(msg)) => panic!("The cycle failed at the acknowledgement: received {:?}", msg),
+			Some(None) => panic!("The cycle failed at the acknowledgement: the subsystem hung up"),
+			None => panic!("The cycle failed at the acknowledgement: nothing received within {:?}", timeout),
+		};
+
+		let response = match rx.timeout(timeout).await {
+			Some(Ok(response)) => response,
+			Some(Err(_)) => panic!(
+				"The cycle failed at the response: the subsystem dropped the response channel after {:?}",
+				ack,
+			),
+			None => panic!("The cycle failed at the response: nothing received within {:?}", timeout),
+		};
+
+		assert!(
+			response_matcher(&response),
+			"The cycle failed at the response: received unexpected {:?}",
+			response,
+		);
+
+		(ack, response)
+	}
+
+	/// Send the introspection request built by `make_probe` around a response channel and
+	/// return the state the subsystem reports on it, e.g. for assertions at the end of a test.
+	///
+	/// Messages the subsystem sends meanwhile are left to subsequent receives. Panics if the
+	/// subsystem doesn't answer within the default timeout, or drops the response channel.
+	pub async fn probe_state<T>(&mut self, make_probe: impl FnOnce(oneshot::Sender<T>) -> M) -> T {
+		let (tx, rx) = oneshot::channel();
+		self.send(comm(make_probe(tx))).await;
+
+		match rx.timeout(assertion_timeout()).await {
+			Some(Ok(state)) => state,
+			Some(Err(_)) => panic!("The subsystem dropped the response channel of the probe"),
+			None => panic!("The subsystem didn't answer the probe within {:?}", assertion_timeout()),
+		}
+	}
+
+	/// Send one request per entry of `requests`, built by its function around a response
+	/// channel, and assert each response arrives on the channel of the request it answers.
+	///
+	/// All requests are sent before any response is awaited, so the subsystem juggles them
+	/// concurrently. `reply_key` tells which key a response answers. Panics naming the first
+	/// misrouted response, or a request left unanswered within the default timeout. Returns the
+	/// responses, in the order of `requests`.
+	pub async fn assert_correct_responder_routing<K, R>(
+		&mut self,
+		requests: Vec<(K, impl FnOnce(oneshot::Sender<R>) -> M)>,
+		reply_key: impl Fn(&R) -> K,
+	) -> Vec<R>
+	where
+		K: PartialEq + fmt::Debug,
+		R: fmt::Debug,
+	{
+		let mut pending = Vec::with_capacity(requests.len());
+		for (key, make_req) in requests {
+			let (tx, rx) = oneshot::channel();
+			self.send(comm(make_req(tx))).await;
+			pending.push((key, rx));
+		}
+
+		let mut responses = Vec::with_capacity(pending.len());
+		for (key, rx) in pending {
+			let response = match rx.timeout(assertion_timeout()).await {
+				Some(Ok(response)) => response,
+				Some(Err(_)) => panic!("The subsystem dropped the responder of request {:?}", key),
+				None => panic!("The subsystem didn't answer request {:?} within {:?}", key, assertion_timeout()),
+			};
+
+			let answered = reply_key(&response);
+			assert!(
+				answered == key,
+				"The response {:?} to request {:?} was routed to the responder of request {:?}",
+				response,
+				answered,
+				key,
+			);
+			responses.push(response);
+		}
+
+		responses
+	}
+
+	/// Assert the subsystem forwards the failure of a downstream query to the requester.
+	///
+	/// Sends the request built by `request_setup` around a response channel. The next message
+	/// the subsystem sends is handed to `inject_downstream_error`, to answer it with an error, or
+	/// to hand it back if it isn't the expected downstream query. The response on the channel
+	/// must then satisfy `expected_error_matcher`. Panics naming the failed step, in particular if
+	/// the subsystem dropped the response channel instead of reporting the error. Returns the
+	/// response.
+	pub async fn assert_error_propagated<R: fmt::Debug>(
+		&mut self,
+		request_setup: impl FnOnce(oneshot::Sender<R>) -> M,
+		inject_downstream_error: impl FnOnce(AllMessages) -> Result<(), AllMessages>,
+		expected_error_matcher: impl Fn(&R) -> bool,
+	) -> R {
+		let (tx, rx) = oneshot::channel();
+		self.send(comm(request_setup(tx))).await;
+
+		match self.try_recv().timeout(assertion_timeout()).await {
+			Some(Some(query)) => if let Err(msg) = inject_downstream_error(query) {
+				panic!("Expected a downstream query to fail, received {:?}", msg);
+			},
+			Some(None) => panic!("Expected a downstream query to fail, but the subsystem hung up"),
+			None => panic!("Expected a downstream query to fail, received nothing within {:?}", assertion_timeout()),
+		}
+
+		let response = match rx.timeout(assertion_timeout()).await {
+			Some(Ok(response)) => response,
+			Some(Err(_)) => panic!("The subsystem dropped the responder instead of propagating the error"),
+			None => panic!("The subsystem didn't respond within {:?} after the downstream error", assertion_timeout()),
+		};
+
+		assert!(
+			expected_error_matcher(&response),
+			"The subsystem responded with {:?} instead of the expected error",
+			response,
+		);
+
+		response
+	}
+
+	/// Send `request`, which refers to a relay-parent the subsystem never saw activated, and
+	/// assert the subsystem ignores it rather than emitting downstream work.
+	///
+	/// The reaction is considered complete once the subsystem has been silent for a short while.
+	/// Panics naming the messages sent in reaction. For requests carrying a responder, see
+	/// [`Self::assert_rejects_unknown_relay_parent`].
+	pub async fn assert_ignores_unknown_relay_parent(&mut self, request: M) {
+		self.send(comm(request)).await;
+		self.assert_no_downstream_work().await;
+	}
+
+	/// Like [`Self::assert_ignores_unknown_relay_parent`], for a request built by `make_request`
+	/// around a response channel. The subsystem must answer it with a response satisfying
+	/// `response_matcher`, e.g. an error or an empty response, which is returned.
+	pub async fn assert_rejects_unknown_relay_parent<R: fmt::Debug>(
+		&mut self,
+		make_request: impl FnOnce(oneshot::Sender<R>) -> M,
+		response_matcher: impl Fn(&R) -> bool,
+	) -> R {
+		let (tx, rx) = oneshot::channel();
+		self.send(comm(make_request(tx))).await;
+		self.assert_no_downstream_work().await;
+
+		let response = match rx.timeout(assertion_timeout()).await {
+			Some(Ok(response)) => response,
+			Some(Err(_)) => panic!("The subsystem dropped the responder instead of rejecting the request"),
+			None => panic!("The subsystem didn't respond within {:?}", assertion_timeout()),
+		};
+
+		assert!(
+			response_matcher(&response),
+			"The subsystem responded with {:?} to a request for an unknown relay-parent",
+			response,
+		);
+
+		response
+	}
+
+	async fn assert_no_downstream_work(&mut self) {
+		let spurious = self.drain_settled("after a request about an unknown relay-parent").await;
+		assert!(
+			spurious.is_empty(),
+			"The subsystem sent {} messages for an unknown relay-parent: {:?}",
+			spurious.len(),
+			spurious,
+		);
+	}
+
+	/// Run `fut` until it completes, asserting the subsystem doesn't send anything meanwhile.
+	///
+	/// `fut` is polled before the subsystem's messages, so it wins if both are ready at the same
+	/// time. To make the race independent of wall-clock scheduling, have the subsystem wait on
+	/// the [`VirtualClock`](crate::VirtualClock) and advance a clone of [`Self::clock`] from
+	/// `fut`. Panics with the message if the subsystem sent one first. Returns the output of
+	/// `fut`.
+	pub async fn assert_completes_before_output<F: Future>(&mut self, fut: F) -> F::Output {
+		futures::pin_mut!(fut);
+
+		match future::select(fut, self.messages().next()).await {
+			Either::Left((output, _)) => output,
+			Either::Right((Some(msg), _)) => panic!("The subsystem sent {:?} before the future completed", msg),
+			Either::Right((None, fut)) => fut.await,
+		}
+	}
+
+	/// Send `inputs` one at a time, asserting the subsystem only sends a message satisfying
+	/// `output_matcher` once it received at least `threshold` of them.
+	///
+	/// After each input, the subsystem is given time to fall silent before the next one is sent.
+	/// Inputs after the one the matching message reacted to are not sent. Panics with the number
+	/// of inputs after which the first matching message appeared if that is fewer than
+	/// `threshold`, or if none appeared after all inputs. Returns all messages the subsystem sent.
+	pub async fn assert_threshold(
+		&mut self,
+		inputs: Vec<FromOverseer<M>>,
+		threshold: usize,
+		output_matcher: impl Fn(&AllMessages) -> bool,
+	) -> Vec<AllMessages> {
+		let total = inputs.len();
+		let mut sent = Vec::new();
+
+		for (count, input) in (1..).zip(inputs) {
+			self.send(input).await;
+			let reaction = self.drain_settled(&format!("after input #{} towards the threshold", count)).await;
+
+			if let Some(output) = reaction.iter().find(|msg| output_matcher(msg)) {
+				assert!(
+					count >= threshold,
+					"The output appeared after {} inputs, before the threshold of {}: {:?}",
+					count,
+					threshold,
+					output,
+				);
+				sent.extend(reaction);
+				return sent;
+			}
+
+			sent.extend(reaction);
+		}
+
+		panic!(
+			"The output didn't appear after {} inputs, expected it after {}, received {:?}",
+			total,
+			threshold,
+			sent,
+		)
+	}
+
+	/// Send `setup` one input at a time, then `trigger`, asserting the subsystem sends a message
+	/// satisfying `output_matcher` in reaction to `trigger` only.
+	///
+	/// After each input, the subsystem is given time to fall silent before the next one is sent.
+	/// Panics with the spurious message and the number of setup inputs sent if a matching
+	/// message appears early, or if none appears in reaction to `trigger`. Returns all messages
+	/// the subsystem sent.
+	pub async fn assert_silent_until_input(
+		&mut self,
+		setup: Vec<FromOverseer<M>>,
+		trigger: FromOverseer<M>,
+		output_matcher: impl Fn(&AllMessages) -> bool,
+	) -> Vec<AllMessages> {
+		let total = setup.len();
+		let mut sent = Vec::new();
+
+		for (count, input) in (1..).zip(setup) {
+			self.send(input).await;
+			let reaction = self.drain_settled(&format!("after setup input #{}", count)).await;
+
+			if let Some(spurious) = reaction.iter().find(|msg| output_matcher(msg)) {
+				panic!(
+					"The subsystem sent {:?} after {} of {} setup inputs, before the trigger",
+					spurious,
+					count,
+					total,
+				);
+			}
+
+			sent.extend(reaction);
+		}
+
+		self.send(trigger).await;
+		let reaction = self.drain_settled("after the trigger").await;
+		assert!(
+			reaction.iter().any(|msg| output_matcher(msg)),
+			"The subsystem didn't react to the trigger, it sent {:?}",
+			reaction,
+		);
+
+		sent.extend(reaction);
+		sent
+	}
+
+	/// Assert an `ActiveLeavesUpdate` without activations and deactivations is a no-op.
+	///
+	/// Sends the empty update and waits for the subsystem to fall silent, panicking with any
+	/// message it sent meanwhile. Then activates `follow_up` and asserts the subsystem still
+	/// processes updates, by sending a message satisfying `reaction_matcher` before falling
+	/// silent again. Returns the messages sent in reaction to `follow_up`, e.g. to answer them.
+	pub async fn assert_handles_empty_update(
+		&mut self,
+		follow_up: ActivatedLeaf,
+		reaction_matcher: impl Fn(&AllMessages) -> bool,
+	) -> Vec<AllMessages> {
+		self.send(signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::default()))).await;
+
+		let spurious = self.drain_settled("after an empty update").await;
+		assert!(
+			spurious.is_empty(),
+			"The subsystem sent {} messages in reaction to an empty update: {:?}",
+			spurious.len(),
+			spurious,
+		);
+
+		let hash = follow_up.hash;
+		self.send(signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(follow_up)))).await;
+
+		let reaction = self.drain_settled("after the activation following an empty update").await;
+		assert!(
+			reaction.iter().any(|msg| reaction_matcher(msg)),
+			"The subsystem didn't react to the activation of {} after an empty update, it sent {:?}",
+			hash,
+			reaction,
+		);
+
+		reaction
+	}
+
+	/// Send `signals` in the order given by `permutation`, asserting the subsystem survives them.
+	///
+	/// `permutation[i]` is the index in `signals` of the `i`th signal to send, e.g. `vec![1, 0]`
+	/// swaps two signals. Production overseers never reorder signals, so this is a defensive test
+	/// of the subsystem's tolerance to unexpected sequencing, e.g. a finalization preceding the
+	/// activation of the block. Panics if `permutation` isn't a permutation of the indices of
+	/// `signals`, or if the subsystem doesn't read one of them within half the harness timeout.
+	/// Returns the messages sent until the subsystem fell silent.
+	pub async fn send_reordered_signals(
+		&mut self,
+		signals: Vec<OverseerSignal>,
+		permutation: Vec<usize>,
+	) -> Vec<AllMessages> {
+		let mut sorted = permutation.clone();
+		sorted.sort_unstable();
+		assert!(
+			sorted.into_iter().eq(0..signals.len()),
+			"{:?} isn't a permutation of the indices of {} signals",
+			permutation,
+			signals.len(),
+		);
+
+		let mut signals = signals.into_iter().map(Some).collect::<Vec<_>>();
+		for (position, index) in permutation.into_iter().enumerate() {
+			let reordered = signal(signals[index].take().expect("indices are unique; qed"));
+			self.observe(&reordered);
+			let description = format!("{:?}", reordered);
+			let timeout = assertion_timeout();
+			if self.tx.send(reordered).timeout(timeout).await.is_none() {
+				panic!(
+					"The subsystem didn't read reordered signal #{} (signal #{}) within {:?}: {}",
+					position,
+					index,
+					timeout,
+					description,
+				);
+			}
+		}
+
+		self.drain_settled("after the reordered signals").await
+	}
+
+	/// Activate and deactivate `leaf` `times` times in a row, asserting the subsystem gets
+	/// through all of the toggles.
+	///
+	/// The toggles are queued on the sink at once, so the leaf is typically deactivated before
+	/// the subsystem got to process its activation. Panics with the number of toggles read if the
+	/// subsystem gets stuck. Panics of the subsystem fail the test through the harness. Returns
+	/// the messages sent until the subsystem fell silent after the last toggle.
+	pub async fn flap_leaf(&mut self, leaf: ActivatedLeaf, times: usize) -> Vec<AllMessages> {
+		let hash = leaf.hash;
+		for _ in 0..times {
+			let activation = signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf.clone())));
+			let deactivation = signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::stop_work(hash)));
+			for toggle in vec![activation, deactivation] {
+				self.observe(&toggle);
+				self.tx.enqueue(toggle);
+			}
+		}
+
+		let toggles = 2 * times;
+		let tx = &mut self.tx;
+		if future::poll_fn(|cx| tx.poll_ready_unpin(cx)).timeout(assertion_timeout()).await.is_none() {
+			panic!(
+				"The subsystem got stuck after reading {} of {} toggles of leaf {}",
+				toggles - self.tx.len(),
+				toggles,
+				hash,
+			);
+		}
+
+		self.drain_settled("after flapping the leaf").await
+	}
+
+	/// Assert activating `leaf` a second time makes the subsystem send nothing further for it.
+	///
+	/// Activates `leaf`, waits for the subsystem to fall silent, then activates it again. The
+	/// second activation must not cause any message bound to `leaf`. Returns the messages sent in
+	/// reaction to the first activation.
+	pub async fn assert_tolerates_duplicate_leaf(&mut self, leaf: ActivatedLeaf) -> Vec<AllMessages> {
+		self.assert_tolerates_duplicate_leaf_with(leaf, projection::relay_parent).await
+	}
+
+	/// Like [`Self::assert_tolerates_duplicate_leaf`], locating the leaf of a message with
+	/// `projection`.
+	pub async fn assert_tolerates_duplicate_leaf_with(
+		&mut self,
+		leaf: ActivatedLeaf,
+		projection: impl Projection<Hash>,
+	) -> Vec<AllMessages> {
+		let hash = leaf.hash;
+		let activate = |leaf| signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)));
+
+		self.send(activate(leaf.clone())).await;
+		let first = self.drain_settled("after activating the leaf").await;
+
+		self.send(activate(leaf)).await;
+		let duplicates = self.drain_settled("after activating the leaf a second time").await
+			.into_iter()
+			.filter(|msg| projection.project(msg) == Some(hash))
+			.collect::<Vec<_>>();
+
+		assert!(
+			duplicates.is_empty(),
+			"Duplicate activation of leaf {} caused {} more messages for it, after {} for the first: {:?}",
+			hash,
+			duplicates.len(),
+			first.len(),
+			duplicates,
+		);
+
+		first
+	}
+
+	/// Assert the subsystem stops working on `leaf` once it is deactivated.
+	///
+	/// Activates `leaf`, delivers `trigger_work` and then `stop_signal`, usually the
+	/// deactivation of `leaf`. Once the subsystem has read `stop_signal`, it must not send any
+	/// further messages bound to `leaf` before falling silent. Messages sent while the subsystem
+	/// is still busy with reading `stop_signal` count as sent before it.
+	///
+	/// Returns all messages the subsystem sent, except the ones leaked after deactivation.
+	pub async fn assert_stops_on_deactivate(
+		&mut self,
+		leaf: ActivatedLeaf,
+		trigger_work: FromOverseer<M>,
+		stop_signal: FromOverseer<M>,
+	) -> Vec<AllMessages> {
+		self.assert_stops_on_deactivate_with(leaf, trigger_work, stop_signal, projection::relay_parent)
+			.await
+	}
+
+	/// Like [`Self::assert_stops_on_deactivate`], locating the leaf of a message with `projection`.
+	pub async fn assert_stops_on_deactivate_with(
+		&mut self,
+		leaf: ActivatedLeaf,
+		trigger_work: FromOverseer<M>,
+		stop_signal: FromOverseer<M>,
+		projection: impl Projection<Hash>,
+	) -> Vec<AllMessages> {
+		let hash = leaf.hash;
+		self.send(signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)))).await;
+		self.send(trigger_work).await;
+		self.send(stop_signal).await;
+
+		let mut sent = self.drain();
+		let (leaked, after): (Vec<_>, Vec<_>) = self.drain_settled("after deactivating the leaf").await
+			.into_iter()
+			.partition(|msg| projection.project(msg) == Some(hash));
+
+		assert!(
+			leaked.is_empty(),
+			"Leaf {} still produced {} messages after deactivation: {:?}",
+			hash,
+			leaked.len(),
+			leaked,
+		);
+
+		sent.extend(after);
+		sent
+	}
+
+	/// Replace `old_leaf` by `new_leaf` in a single update, as on a reorg, asserting the subsystem
+	/// sends nothing further for the old fork.
+	///
+	/// Waits for the subsystem to fall silent after the update and panics listing any message
+	/// bound to `old_leaf`. Returns the other messages, e.g. to assert the subsystem started
+	/// working on `new_leaf`.
+	pub async fn simulate_reorg(&mut self, old_leaf: Hash, new_leaf: ActivatedLeaf) -> Vec<AllMessages> {
+		self.simulate_reorg_with(old_leaf, new_leaf, projection::relay_parent).await
+	}
+
+	/// Like [`Self::simulate_reorg`], locating the leaf of a message with `projection`.
+	pub async fn simulate_reorg_with(
+		&mut self,
+		old_leaf: Hash,
+		new_leaf: ActivatedLeaf,
+		projection: impl Projection<Hash>,
+	) -> Vec<AllMessages> {
+		let mut update = ActiveLeavesUpdate::start_work(new_leaf);
+		update.deactivated.push(old_leaf);
+		self.send(signal(OverseerSignal::ActiveLeaves(update))).await;
+
+		let (leaked, sent): (Vec<_>, Vec<_>) = self.drain_settled("after the reorg").await
+			.into_iter()
+			.partition(|msg| projection.project(msg) == Some(old_leaf));
+
+		assert!(
+			leaked.is_empty(),
+			"The replaced leaf {} still produced {} messages after the reorg: {:?}",
+			old_leaf,
+			leaked.len(),
+			leaked,
+		);
+
+		sent
+	}
+}
+
+impl<M: fmt::Debug> TestSubsystemContextHandle<M> {
+	/// Deliver `original` and assert the next message the subsystem sends satisfies
+	/// `expected_downstream`, e.g. because it forwards the request to another subsystem.
+	///
+	/// The original is the subsystem's own message type, as that's what the handle delivers.
+	/// Panics with the actual message if it doesn't match, or if none arrives in time. Returns
+	/// the forwarded message, e.g. to answer it.
+	pub async fn assert_forwarded(
+		&mut self,
+		original: M,
+		expected_downstream: impl Fn(&AllMessages) -> bool,
+	) -> AllMessages {
+		let description = format!("{:?}", original);
+		self.send(comm(original)).await;
+
+		let forwarded = match self.try_recv().timeout(assertion_timeout()).await {
+			Some(Some(msg)) => msg,
+			Some(None) => panic!("The subsystem hung up instead of forwarding {}", description),
+			None => panic!("The subsystem didn't forward {} within {:?}", description, assertion_timeout()),
+		};
+
+		assert!(
+			expected_downstream(&forwarded),
+			"The subsystem didn't forward {} as expected, it sent {:?}",
+			description,
+			forwarded,
+		);
+
+		forwarded
+	}
+}
+
+impl<M: Clone> TestSubsystemContextHandle<M> {
+	/// Deliver `input` twice, asserting the second delivery makes the subsystem send nothing.
+	///
+	/// Subsystems must tolerate at-least-once delivery of e.g. repeated leaf activations. The
+	/// reaction to each delivery is considered complete once the subsystem has been silent for a
+	/// short while. Returns the messages sent in reaction to the first delivery.
+	pub async fn assert_idempotent(&mut self, input: FromOverseer<M>) -> Vec<AllMessages> {
+		let duplicate = duplicate(&input);
+
+		self.send(input).await;
+		let first = self.drain_settled("after the input").await;
+
+		self.send(duplicate).await;
+		let extra = self.drain_settled("after the duplicate input").await;
+		assert!(
+			extra.is_empty(),
+			"Second delivery was not a no-op, {} messages were sent after the first one's {}: {:?}",
+			extra.len(),
+			first.len(),
+			extra,
+		);
+
+		first
+	}
+
+	/// Send `request` twice, asserting the subsystem queries downstream for the first one only,
+	/// serving the second one from its cache.
+	///
+	/// The downstream query is the first message satisfying `downstream_matcher` in reaction to
+	/// the first request. It is handed to `reply`, to answer it so the subsystem can cache the
+	/// result. The reaction to each request is considered complete once the subsystem has been
+	/// silent for a short while. Panics if the downstream query didn't fire for the first request,
+	/// or if it fired again. Returns all other messages sent in reaction to both requests.
+	pub async fn assert_cache_hit(
+		&mut self,
+		request: M,
+		downstream_matcher: impl Fn(&AllMessages) -> bool,
+		reply: impl FnOnce(AllMessages),
+	) -> Vec<AllMessages> {
+		self.send(comm(request.clone())).await;
+
+		let timeout = assertion_timeout();
+		let deadline = Instant::now() + timeout;
+		let mut sent = Vec::new();
+		let query = loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			match self.try_recv().timeout(remaining).await {
+				Some(Some(msg)) if downstream_matcher(&msg) => break msg,
+				Some(Some(msg)) => sent.push(msg),
+				Some(None) => panic!("The subsystem hung up before querying downstream, after sending {:?}", sent),
+				None => panic!(
+					"The subsystem didn't query downstream for the first request, received only {:?} within {:?}",
+					sent,
+					timeout,
+				),
+			}
+		};
+		reply(query);
+		sent.extend(self.drain_settled("after the response to the query").await);
+
+		self.send(comm(request)).await;
+		sent.extend(self.drain_settled("after the repeated request").await);
+
+		let repeated = sent.iter().filter(|msg| downstream_matcher(msg)).collect::<Vec<_>>();
+		assert!(
+			repeated.is_empty(),
+			"The downstream query fired {} more times instead of being served from cache: {:?}",
+			repeated.len(),
+			repeated,
+		);
+
+		sent
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::channel::oneshot;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use polkadot_node_subsystem::messages::{RuntimeApiMessage, RuntimeApiRequest};
+	use crate::subsystem_test_harness;
+
+	fn validators(relay_parent: Hash) -> AllMessages {
+		RuntimeApiMessage::Request(relay_parent, RuntimeApiRequest::Validators(oneshot::channel().0)).into()
+	}
+
+	fn session_index(relay_parent: Hash) -> AllMessages {
+		RuntimeApiMessage::Request(relay_parent, RuntimeApiRequest::SessionIndexForChild(oneshot::channel().0)).into()
+	}
+
+	fn interleaving_test(expected: HashMap<Hash, Vec<AllMessages>>, sent: fn() -> Vec<AllMessages>) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+				handle.assert_interleaving(expected).await;
+			},
+			|mut ctx| async move {
+				ctx.send_messages(sent()).await;
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	fn expected() -> HashMap<Hash, Vec<AllMessages>> {
+		let (a, b) = (Hash::repeat_byte(1), Hash::repeat_byte(2));
+		vec![
+			(a, vec![validators(a), session_index(a)]),
+			(b, vec![validators(b), session_index(b)]),
+		].into_iter().collect()
+	}
+
+	#[test]
+	fn any_interleaving_is_accepted() {
+		interleaving_test(expected(), || {
+			let (a, b) = (Hash::repeat_byte(1), Hash::repeat_byte(2));
+			vec![validators(b), validators(a), session_index(b), session_index(a)]
+		});
+	}
+
+	#[test]
+	#[should_panic(expected = "broke at message #1")]
+	fn per_leaf_order_is_enforced() {
+		interleaving_test(expected(), || {
+			let (a, b) = (Hash::repeat_byte(1), Hash::repeat_byte(2));
+			vec![validators(a), session_index(b), validators(b), session_index(a)]
+		});
+	}
+}
+
+#[cfg(test)]
+mod handle_tests {
+	use super::*;
+	use std::collections::HashSet;
+	use std::sync::Arc;
+	use futures::channel::oneshot;
+	use futures::future::select;
+	use polkadot_node_subsystem::{SubsystemContext, SubsystemSender, jaeger};
+	use polkadot_node_subsystem::messages::{
+		AvailabilityStoreMessage, ChainApiMessage, RuntimeApiMessage, RuntimeApiRequest,
+	};
+	use sp_core::testing::TaskExecutor;
+	use crate::{TestSubsystemContext, subsystem_test_harness};
+
+	fn activate_twice(deduplicate: bool) {
+		let leaf = ActivatedLeaf {
+			hash: Hash::repeat_byte(1),
+			number: 1,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let signal = OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf));
+				assert_eq!(handle.assert_idempotent(FromOverseer::Signal(signal)).await.len(), 1);
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut seen = HashSet::new();
+				while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+					for leaf in update.activated {
+						if seen.insert(leaf.hash) || !deduplicate {
+							ctx.send_message(request_validators(leaf.hash)).await;
+						}
+					}
+				}
+			},
+		);
+	}
+
+	/// A subsystem sending one message per input until `Conclude`, or exiting on its first input
+	/// if `abandon`.
+	fn drain_test(policy: DrainPolicy, abandon: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let pending = (0..3).map(|_| FromOverseer::Communication { msg: () }).collect();
+				handle.assert_drains_before_conclude(pending, policy).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {
+					if abandon {
+						break;
+					}
+					ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn draining_subsystem_processes_queued_inputs() {
+		drain_test(DrainPolicy::Drains { outputs_per_input: 1 }, false);
+	}
+
+	#[test]
+	fn abandoning_subsystem_sends_nothing() {
+		drain_test(DrainPolicy::Abandons, true);
+	}
+
+	/// A subsystem sending a final message on `Conclude` if `farewell`.
+	fn shutdown_test(farewell: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send_dummy().await;
+				handle.recv().await;
+				let sent = handle.assert_clean_shutdown(Some(
+					|msg: &AllMessages| matches!(msg, AllMessages::ChainApi(ChainApiMessage::FinalizedBlockNumber(_))),
+				)).await;
+				assert_eq!(sent.len(), 1);
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {
+					ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+				}
+				if farewell {
+					ctx.send_message(ChainApiMessage::FinalizedBlockNumber(oneshot::channel().0).into()).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn subsystems_sending_their_final_message_shut_down_cleanly() {
+		shutdown_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem exited without sending the expected final message, sent 0 messages")]
+	fn skipped_final_messages_are_reported() {
+		shutdown_test(false);
+	}
+
+	#[test]
+	#[should_panic(expected = "1 messages were pending before Conclude")]
+	fn messages_pending_on_shutdown_are_reported() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send_dummy().await;
+				handle.assert_clean_shutdown(None::<fn(&AllMessages) -> bool>).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {
+					ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem exited with 3 of 4 inputs unread")]
+	fn abandoned_inputs_are_reported() {
+		drain_test(DrainPolicy::Drains { outputs_per_input: 1 }, true);
+	}
+
+	#[test]
+	#[should_panic(expected = "Expected Abandons for 3 queued inputs, but the subsystem sent 3 messages")]
+	fn unexpected_processing_is_reported() {
+		drain_test(DrainPolicy::Abandons, false);
+	}
+
+	#[test]
+	fn signals_are_interleaved_with_pending_requests() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let finalized = OverseerSignal::BlockFinalized(Hash::repeat_byte(2), 2);
+				let request = handle
+					.send_signal_during_request(FromOverseer::Communication { msg: () }, finalized)
+					.await;
+				match request {
+					AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) => tx.send(Ok(Some(1))).unwrap(),
+					msg => panic!("Unexpected {:?}", msg),
+				}
+
+				match handle.recv().await {
+					AllMessages::ChainApi(ChainApiMessage::FinalizedBlockHash(number, _)) => assert_eq!(number, 1),
+					msg => panic!("Unexpected {:?}", msg),
+				}
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Communication { .. })));
+				let (tx, rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx).into()).await;
+
+				// The signal arrives while the response is still pending.
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::BlockFinalized(..)))));
+				let number = rx.await.unwrap().unwrap().unwrap();
+				ctx.send_message(ChainApiMessage::FinalizedBlockHash(number, oneshot::channel().0).into()).await;
+
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	fn heartbeat_test(heartbeat_every: Duration) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let beats = handle.assert_periodic(
+					Duration::from_secs(6),
+					|msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..))),
+					3,
+				).await;
+				assert_eq!(beats.len(), 3);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let clock = ctx.clock().clone();
+				loop {
+					let beat = match select(clock.sleep(heartbeat_every), ctx.recv()).await {
+						Either::Left(_) => true,
+						Either::Right((Ok(FromOverseer::Signal(OverseerSignal::Conclude)), _)) => break,
+						Either::Right(_) => false,
+					};
+
+					if beat {
+						let (tx, _) = oneshot::channel();
+						ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx).into()).await;
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn heartbeats_on_schedule_are_accepted() {
+		heartbeat_test(Duration::from_secs(6));
+	}
+
+	#[test]
+	#[should_panic(expected = "No periodic message in cycle #0 of 3, 6s into the test")]
+	fn missing_heartbeats_are_reported() {
+		heartbeat_test(Duration::from_secs(12));
+	}
+
+	/// Requests the validators of each input's relay-parent, and once more every second of
+	/// virtual time if `retry_forever`.
+	fn burst_test(retry_forever: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				let sent = handle.assert_recovers_from_burst((0..20).map(comm).collect(), Duration::from_secs(10)).await;
+				assert_eq!(sent.len(), 20);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				let clock = ctx.clock().clone();
+				loop {
+					let input = match future::select(ctx.recv(), clock.sleep(Duration::from_secs(1))).await {
+						Either::Left((input, _)) => Some(input),
+						Either::Right(_) => None,
+					};
+
+					match input {
+						Some(Ok(FromOverseer::Communication { msg })) =>
+							ctx.send_message(request_validators(Hash::repeat_byte(msg))).await,
+						Some(_) => break,
+						None if retry_forever => ctx.send_message(request_validators(Hash::repeat_byte(0))).await,
+						None => {}
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn settling_subsystems_pass() {
+		burst_test(false);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem didn't settle within 10s after a burst of 20 inputs, 0 remain unread")]
+	fn unsettled_subsystems_are_reported() {
+		burst_test(true);
+	}
+
+	/// Sends a message every 10ms of real time until `Conclude`, never falling silent.
+	async fn babble(mut ctx: TestSubsystemContext<u8, TaskExecutor>) {
+		loop {
+			let tick = match future::select(ctx.recv(), futures_timer::Delay::new(Duration::from_millis(10))).await {
+				Either::Left((Ok(FromOverseer::Communication { .. }), _)) => false,
+				Either::Left(_) => break,
+				Either::Right(_) => true,
+			};
+			if tick {
+				ctx.send_message(request_validators(Hash::repeat_byte(0))).await;
+			}
+		}
+	}
+
+	#[test]
+	#[should_panic(expected = "after a burst of 3 inputs, 0ns of virtual time into settling")]
+	fn subsystems_never_falling_silent_after_a_burst_are_reported() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				handle.assert_recovers_from_burst((0..3).map(comm).collect(), Duration::from_secs(10)).await;
+			},
+			babble,
+		);
+	}
+
+	/// Collects three requests, then answers each with its key, shifting the responders by
+	/// `shift`.
+	fn routing_test(shift: usize) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<(u8, oneshot::Sender<u8>)>| async move {
+				let requests = (1..=3).map(|key| (key, move |tx: oneshot::Sender<u8>| (key, tx))).collect();
+				let responses = handle.assert_correct_responder_routing(requests, |response| *response).await;
+				assert_eq!(responses, vec![1, 2, 3]);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				let mut requests = Vec::new();
+				while requests.len() < 3 {
+					if let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+						requests.push(msg);
+					}
+				}
+
+				let (keys, mut responders): (Vec<_>, Vec<_>) = requests.into_iter().unzip();
+				responders.rotate_left(shift);
+				for (key, tx) in keys.into_iter().zip(responders).rev() {
+					let _ = tx.send(key);
+				}
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {}
+			},
+		);
+	}
+
+	#[test]
+	fn correctly_routed_responses_pass() {
+		routing_test(0);
+	}
+
+	#[test]
+	#[should_panic(expected = "The response 3 to request 3 was routed to the responder of request 1")]
+	fn misrouted_responses_are_reported() {
+		routing_test(1);
+	}
+
+	/// Requests the validators of each input's relay-parent twice, giving up on sends which
+	/// don't complete immediately if `impatient`.
+	fn backpressure_test(impatient: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				handle.assert_handles_backpressure((1..=3).map(comm).collect(), 6).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					for _ in 0..2 {
+						let send = ctx.send_message(request_validators(Hash::repeat_byte(msg)));
+						if impatient {
+							let _ = send.timeout(Duration::from_millis(1)).await;
+						} else {
+							send.await;
+						}
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn blocking_sends_recover_from_congestion() {
+		backpressure_test(false);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem dropped 5 of 6 messages under congestion")]
+	fn messages_dropped_under_congestion_are_reported() {
+		backpressure_test(true);
+	}
+
+	/// Requests the validators of the relay-parent of each input.
+	fn duplicate_output_test(relay_parents: Vec<u8>) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				for relay_parent in relay_parents {
+					handle.send(comm(relay_parent)).await;
+				}
+				handle.assert_no_duplicate_output(projection::relay_parent).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					ctx.send_message(request_validators(Hash::repeat_byte(msg))).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn distinct_outputs_pass() {
+		duplicate_output_test(vec![1, 2, 3]);
+	}
+
+	#[test]
+	#[should_panic(expected = "Messages #0 and #2 are duplicates by")]
+	fn duplicate_outputs_are_reported() {
+		duplicate_output_test(vec![1, 2, 1]);
+	}
+
+	/// Requests the validators of every finalized block if `propagate`, after an unrelated request.
+	fn finality_test(propagate: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let finalized = Hash::repeat_byte(1);
+				handle.assert_propagates_finality(finalized, 1, |msg| {
+					projection::relay_parent(msg) == Some(finalized)
+				}).await;
+				assert_eq!(projection::relay_parent(&handle.recv().await), Some(Hash::repeat_byte(2)));
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				while let Ok(FromOverseer::Signal(OverseerSignal::BlockFinalized(hash, _))) = ctx.recv().await {
+					ctx.send_message(request_validators(Hash::repeat_byte(2))).await;
+					if propagate {
+						ctx.send_message(request_validators(hash)).await;
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn propagated_finality_passes() {
+		finality_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem didn't propagate the finality of")]
+	fn missing_finality_propagation_is_reported() {
+		finality_test(false);
+	}
+
+	/// Counts the leaves activated so far, reporting the count on probes if `answer`.
+	fn probe_test(answer: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<oneshot::Sender<usize>>| async move {
+				handle.activate_leaves(vec![(Hash::repeat_byte(1), 1), (Hash::repeat_byte(2), 2)]).await;
+				assert_eq!(handle.probe_state(|tx| tx).await, 2);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				let mut activated = 0;
+				loop {
+					match ctx.recv().await {
+						Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) =>
+							activated += update.activated.len(),
+						Ok(FromOverseer::Communication { msg: tx }) => if answer {
+							let _ = tx.send(activated);
+						},
+						_ => break,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn probes_report_the_state() {
+		probe_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem dropped the response channel of the probe")]
+	fn unanswered_probes_are_reported() {
+		probe_test(false);
+	}
+
+	/// Requests the finalized block hash at each input height, among other messages.
+	fn sequence_test(heights: Vec<BlockNumber>) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<BlockNumber>| async move {
+				for height in heights {
+					handle.send(comm(height)).await;
+				}
+				handle.assert_monotonic_sequence(|msg: &AllMessages| match msg {
+					AllMessages::ChainApi(ChainApiMessage::FinalizedBlockHash(number, _)) => Some(*number as u64),
+					_ => None,
+				}).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+					ctx.send_message(ChainApiMessage::FinalizedBlockHash(msg, oneshot::channel().0).into()).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn increasing_sequences_pass() {
+		sequence_test(vec![1, 2, 5]);
+	}
+
+	#[test]
+	#[should_panic(expected = "Sequence number 2 follows 2")]
+	fn repeated_sequence_numbers_are_reported() {
+		sequence_test(vec![1, 2, 2]);
+	}
+
+	/// Requests the validators of each activated leaf, waiting for an answer if `blocking`.
+	fn flapping_test(blocking: bool) {
+		let leaf = ActivatedLeaf {
+			hash: Hash::repeat_byte(1),
+			number: 1,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let sent = handle.flap_leaf(leaf, 3).await;
+				assert_eq!(sent.len(), 3);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+					for leaf in update.activated {
+						let (tx, rx) = oneshot::channel();
+						let request = RuntimeApiRequest::Validators(tx);
+						ctx.send_message(RuntimeApiMessage::Request(leaf.hash, request).into()).await;
+						if blocking {
+							let _ = rx.await;
+						}
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn flapping_leaves_are_processed() {
+		flapping_test(false);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem got stuck after reading 1 of 6 toggles of leaf")]
+	fn stuck_subsystems_are_reported() {
+		flapping_test(true);
+	}
+
+	/// Queries the block number of each input, with at most `limit` queries in flight.
+	fn concurrency_test(limit: usize) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				let peak = handle.assert_max_concurrent(
+					(0..5).map(comm).collect(),
+					|msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..))),
+					|msg| match msg {
+						AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) => {
+							let _ = tx.send(Ok(Some(1)));
+							Ok(())
+						}
+						msg => Err(msg),
+					},
+					2,
+				).await;
+				assert_eq!(peak, 2);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				let mut in_flight = futures::stream::FuturesUnordered::new();
+				loop {
+					if in_flight.len() == limit {
+						in_flight.next().await;
+						continue;
+					}
+
+					match ctx.recv().await {
+						Ok(FromOverseer::Communication { msg }) => {
+							let (tx, rx) = oneshot::channel();
+							ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(msg), tx).into()).await;
+							in_flight.push(rx);
+						}
+						_ => break,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn throttled_subsystems_pass() {
+		concurrency_test(2);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem had 5 downstream requests outstanding at once, more than 2")]
+	fn exceeded_concurrency_limits_are_reported() {
+		concurrency_test(5);
+	}
+
+	#[test]
+	#[should_panic(expected = "while throttling, 0 downstream requests answered, 0 outstanding")]
+	fn subsystems_never_falling_silent_while_throttling_are_reported() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				handle.assert_max_concurrent(
+					(0..3).map(comm).collect(),
+					|msg| matches!(msg, AllMessages::ChainApi(_)),
+					Err,
+					2,
+				).await;
+			},
+			babble,
+		);
+	}
+
+	/// Requests the validators of the relay-parent of each request if it is active, or if not
+	/// `guarded`. Responds with whether the relay-parent was active.
+	fn unknown_relay_parent_test(guarded: bool, respond: bool) {
+		subsystem_test_harness(
+			move |mut handle: TestSubsystemContextHandle<(Hash, Option<oneshot::Sender<bool>>)>| async move {
+				let unknown = Hash::repeat_byte(2);
+				handle.activate_leaves(vec![(Hash::repeat_byte(1), 1)]).await;
+				if respond {
+					let known = handle.assert_rejects_unknown_relay_parent(
+						|tx| (unknown, Some(tx)),
+						|known| !known,
+					).await;
+					assert!(!known);
+				} else {
+					handle.assert_ignores_unknown_relay_parent((unknown, None)).await;
+				}
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				let mut active = HashSet::new();
+				loop {
+					match ctx.recv().await {
+						Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) =>
+							active.extend(update.activated.into_iter().map(|leaf| leaf.hash)),
+						Ok(FromOverseer::Communication { msg: (relay_parent, responder) }) => {
+							let known = active.contains(&relay_parent);
+							if known || !guarded {
+								ctx.send_message(request_validators(relay_parent)).await;
+							}
+							if let Some(responder) = responder {
+								let _ = responder.send(known);
+							}
+						}
+						_ => break,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn unknown_relay_parents_are_ignored() {
+		unknown_relay_parent_test(true, false);
+	}
+
+	#[test]
+	fn unknown_relay_parents_are_rejected() {
+		unknown_relay_parent_test(true, true);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem sent 1 messages for an unknown relay-parent")]
+	fn work_for_unknown_relay_parents_is_reported() {
+		unknown_relay_parent_test(false, false);
+	}
+
+	/// Forwards the block number query of each request, propagating failures if `propagate`.
+	fn error_propagation_test(propagate: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<oneshot::Sender<Result<u32, String>>>| async move {
+				handle.assert_error_propagated(
+					|tx| tx,
+					|msg| match msg {
+						AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) => {
+							let _ = tx.send(Err("unknown block".into()));
+							Ok(())
+						}
+						msg => Err(msg),
+					},
+					|response| response.as_ref().err().map_or(false, |error| error == "unknown block"),
+				).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg: responder }) = ctx.recv().await {
+					let (tx, rx) = oneshot::channel();
+					ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx).into()).await;
+					match rx.await {
+						Ok(Ok(number)) => { let _ = responder.send(Ok(number.unwrap_or_default())); }
+						Ok(Err(error)) if propagate => { let _ = responder.send(Err(error.to_string())); }
+						_ => {}
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn propagated_errors_pass() {
+		error_propagation_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem dropped the responder instead of propagating the error")]
+	fn dropped_responders_are_reported() {
+		error_propagation_test(false);
+	}
+
+	/// Queries the number of the block requested by each input, caching answers if `cache`.
+	fn cache_test(cache: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				let sent = handle.assert_cache_hit(
+					1,
+					|msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..))),
+					|msg| match msg {
+						AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) => tx.send(Ok(Some(1))).unwrap(),
+						msg => panic!("{:?}", msg),
+					},
+				).await;
+				assert!(sent.is_empty());
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			move |mut ctx| async move {
+				let mut cached = HashSet::new();
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					if cached.contains(&msg) {
+						continue;
+					}
+
+					let (tx, rx) = oneshot::channel();
+					ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(msg), tx).into()).await;
+					if rx.await.is_ok() && cache {
+						cached.insert(msg);
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn cached_requests_pass() {
+		cache_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "The downstream query fired 1 more times instead of being served from cache")]
+	fn repeated_downstream_queries_are_reported() {
+		cache_test(false);
+	}
+
+	/// Requests the session info of `sessions`, one per input.
+	fn session_test(sessions: Vec<SessionIndex>) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<SessionIndex>| async move {
+				for session in sessions {
+					handle.send(comm(session)).await;
+				}
+				let received = handle.assert_consistent_session(2).await;
+				assert_eq!(received.len(), 3);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					let request = RuntimeApiRequest::SessionInfo(msg, oneshot::channel().0);
+					ctx.send_message(RuntimeApiMessage::Request(Hash::repeat_byte(1), request).into()).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn consistent_sessions_pass() {
+		session_test(vec![2, 2, 2]);
+	}
+
+	#[test]
+	#[should_panic(expected = "1 messages refer to another session than 2")]
+	fn stale_sessions_are_reported() {
+		session_test(vec![2, 1, 2]);
+	}
+
+	/// Requests the validators of every finalized block, which must have been activated before.
+	fn reordering_test(permutation: Vec<usize>, expected_requests: usize) {
+		subsystem_test_harness(
+			move |mut handle: TestSubsystemContextHandle<()>| async move {
+				let leaf = ActivatedLeaf {
+					hash: Hash::repeat_byte(1),
+					number: 1,
+					span: Arc::new(jaeger::Span::Disabled),
+				};
+				let signals = vec![
+					OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)),
+					OverseerSignal::BlockFinalized(Hash::repeat_byte(1), 1),
+				];
+				let sent = handle.send_reordered_signals(signals, permutation).await;
+				assert_eq!(sent.len(), expected_requests);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut active = HashSet::new();
+				loop {
+					match ctx.recv().await {
+						Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) =>
+							active.extend(update.activated.into_iter().map(|leaf| leaf.hash)),
+						Ok(FromOverseer::Signal(OverseerSignal::BlockFinalized(hash, _))) => {
+							if active.contains(&hash) {
+								ctx.send_message(request_validators(hash)).await;
+							}
+						}
+						_ => break,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn signals_are_sent_in_the_given_order() {
+		reordering_test(vec![0, 1], 1);
+	}
+
+	#[test]
+	fn reordered_signals_reach_the_subsystem() {
+		reordering_test(vec![1, 0], 0);
+	}
+
+	#[test]
+	#[should_panic(expected = "isn't a permutation of the indices of 2 signals")]
+	fn invalid_permutations_are_rejected() {
+		reordering_test(vec![0, 0], 0);
+	}
+
+	/// Acknowledges every request with a `RuntimeApi` message, then answers it with `response`.
+	fn cycle_test(response: u32) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<oneshot::Sender<u32>>| async move {
+				let (_, response) = handle.request_expect_ack_and_response(
+					|tx| tx,
+					|msg| matches!(msg, AllMessages::RuntimeApi(_)),
+					|response| *response == 42,
+				).await;
+				assert_eq!(response, 42);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg: tx }) = ctx.recv().await {
+					ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+					let _ = tx.send(response);
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn full_cycles_pass() {
+		cycle_test(42);
+	}
+
+	#[test]
+	#[should_panic(expected = "The cycle failed at the response: received unexpected 7")]
+	fn failing_cycle_steps_are_reported() {
+		cycle_test(7);
+	}
+
+	/// Completes `done` and sends a request, in the order given by `done_first`. With the request
+	/// first, `done` is only completed once the virtual clock has advanced by a second.
+	fn completion_test(done_first: bool) {
+		let (done_tx, done_rx) = oneshot::channel::<()>();
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.assert_completes_before_output(done_rx).await.unwrap();
+				assert!(matches!(handle.recv().await, AllMessages::RuntimeApi(_)));
+			},
+			|mut ctx| async move {
+				if done_first {
+					let _ = done_tx.send(());
+					ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+				} else {
+					ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+					ctx.clock().sleep(Duration::from_secs(1)).await;
+					let _ = done_tx.send(());
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn completions_before_outputs_pass() {
+		completion_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "before the future completed")]
+	fn outputs_before_completions_are_reported() {
+		completion_test(false);
+	}
+
+	/// Requests validators once it received `quorum` inputs.
+	fn threshold_test(quorum: usize) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let inputs = (0..4).map(|_| comm(())).collect();
+				let sent = handle.assert_threshold(inputs, 3, |msg| matches!(msg, AllMessages::RuntimeApi(_))).await;
+				assert_eq!(sent.len(), 1);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut received = 0;
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {
+					received += 1;
+					if received == quorum {
+						ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn outputs_at_the_threshold_pass() {
+		threshold_test(3);
+	}
+
+	#[test]
+	#[should_panic(expected = "The output appeared after 2 inputs, before the threshold of 3")]
+	fn outputs_before_the_threshold_are_reported() {
+		threshold_test(2);
+	}
+
+	/// Requests the validators of every activated leaf, and of every deactivated one unless
+	/// `discards_old_forks`.
+	fn reorg_test(discards_old_forks: bool) {
+		let old_leaf = Hash::repeat_byte(1);
+		let new_leaf = ActivatedLeaf {
+			hash: Hash::repeat_byte(2),
+			number: 1,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let sent = handle.simulate_reorg(old_leaf, new_leaf).await;
+				assert_eq!(sent.len(), 1);
+				assert_eq!(projection::relay_parent(&sent[0]), Some(Hash::repeat_byte(2)));
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+					for leaf in update.activated {
+						ctx.send_message(request_validators(leaf.hash)).await;
+					}
+					for hash in update.deactivated.into_iter().filter(|_| !discards_old_forks) {
+						ctx.send_message(request_validators(hash)).await;
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn reorgs_switch_to_the_new_fork() {
+		reorg_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "still produced 1 messages after the reorg")]
+	fn leakage_after_reorgs_is_reported() {
+		reorg_test(false);
+	}
+
+	/// Requests validators on the `trigger`-th input, counting from one.
+	fn trigger_test(trigger: u8) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				let setup = vec![comm(1), comm(2)];
+				let sent = handle.assert_silent_until_input(
+					setup,
+					comm(3),
+					|msg| matches!(msg, AllMessages::RuntimeApi(_)),
+				).await;
+				assert_eq!(sent.len(), 1);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					if msg == trigger {
+						ctx.send_message(request_validators(Hash::repeat_byte(msg))).await;
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn outputs_on_the_trigger_pass() {
+		trigger_test(3);
+	}
+
+	#[test]
+	#[should_panic(expected = "after 2 of 2 setup inputs, before the trigger")]
+	fn early_outputs_are_reported() {
+		trigger_test(2);
+	}
+
+	/// Requests the validators of every leaf activated, and of the zero hash on updates without
+	/// activations unless `skip_empty`. Stops reacting to updates after an empty one if `stall`.
+	fn empty_update_test(skip_empty: bool, stall: bool) {
+		let follow_up = ActivatedLeaf {
+			hash: Hash::repeat_byte(2),
+			number: 2,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let reaction = handle.assert_handles_empty_update(
+					follow_up,
+					|msg| projection::relay_parent(msg) == Some(Hash::repeat_byte(2)),
+				).await;
+				assert_eq!(reaction.len(), 1);
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut stalled = false;
+				while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+					if stalled {
+						continue;
+					}
+					if update.activated.is_empty() {
+						stalled = stall;
+						if !skip_empty {
+							ctx.send_message(request_validators(Hash::zero())).await;
+						}
+					}
+					for leaf in update.activated {
+						ctx.send_message(request_validators(leaf.hash)).await;
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn empty_updates_are_no_ops() {
+		empty_update_test(true, false);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem sent 1 messages in reaction to an empty update")]
+	fn reactions_to_empty_updates_are_reported() {
+		empty_update_test(false, false);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem didn't react to the activation of")]
+	fn subsystems_stalled_by_empty_updates_are_reported() {
+		empty_update_test(true, true);
+	}
+
+	/// Acknowledges each request with a `ChainApi` message and works with a `RuntimeApi` one, in
+	/// the order the request asks for.
+	fn ack_test(ack_first: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<bool>| async move {
+				let (ack, work) = handle.assert_acks_first(
+					ack_first,
+					|msg| matches!(msg, AllMessages::ChainApi(_)),
+					|msg| matches!(msg, AllMessages::RuntimeApi(_)),
+				).await;
+				assert!(matches!(ack, AllMessages::ChainApi(_)));
+				assert!(matches!(work, AllMessages::RuntimeApi(_)));
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg: ack_first }) = ctx.recv().await {
+					let ack = ChainApiMessage::BlockNumber(Hash::repeat_byte(1), oneshot::channel().0).into();
+					let work = request_validators(Hash::repeat_byte(1));
+					let messages = if ack_first { vec![ack, work] } else { vec![work, ack] };
+					ctx.send_messages(messages).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn acknowledgements_precede_work() {
+		ack_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "Expected the acknowledgement first, but the work came before it")]
+	fn work_before_acknowledgement_is_reported() {
+		ack_test(false);
+	}
+
+	fn targeting_test(expected: &'static [ValidatorIndex]) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.assert_next_targets_validators(expected).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				for index in 0..2 {
+					let (tx, _) = oneshot::channel();
+					let msg = AvailabilityStoreMessage::QueryChunk(Default::default(), ValidatorIndex(index), tx);
+					ctx.send_message(msg.into()).await;
+				}
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn targeted_validators_are_checked() {
+		targeting_test(&[ValidatorIndex(0)]);
+	}
+
+	#[test]
+	#[should_panic(expected = "The message targets the wrong validators")]
+	fn wrong_targets_are_reported() {
+		targeting_test(&[ValidatorIndex(1)]);
+	}
+
+	fn coalescing_test(batch: usize, max_downstream: usize) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let inputs = (0..6).map(|_| FromOverseer::Communication { msg: () }).collect();
+				handle.assert_coalesced(inputs, max_downstream).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut pending = 0;
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {
+					pending += 1;
+					if pending == batch {
+						pending = 0;
+						ctx.send_message(request_validators(Hash::repeat_byte(1))).await;
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn batching_subsystem_is_coalesced() {
+		coalescing_test(3, 2);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem sent 6 messages for 6 inputs, expected at most 2")]
+	fn uncoalesced_requests_are_reported() {
+		coalescing_test(1, 2);
+	}
+
+	fn known_leaves_test(leaked: bool) {
+		let leaf = ActivatedLeaf {
+			hash: Hash::repeat_byte(1),
+			number: 1,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)))).await;
+				assert_eq!(handle.assert_outputs_reference_known_leaves(projection::relay_parent).await.len(), 1);
+
+				let deactivate = ActiveLeavesUpdate::stop_work(Hash::repeat_byte(1));
+				handle.send(signal(OverseerSignal::ActiveLeaves(deactivate))).await;
+				handle.send_dummy().await;
+				handle.assert_outputs_reference_known_leaves(projection::relay_parent).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut last_leaf = None;
+				loop {
+					match ctx.recv().await {
+						Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) => {
+							if let Some(leaf) = update.activated.first() {
+								last_leaf = Some(leaf.hash);
+								ctx.send_message(request_validators(leaf.hash)).await;
+							}
+							if !leaked && update.deactivated.iter().any(|hash| Some(*hash) == last_leaf) {
+								last_leaf = None;
+							}
+						}
+						Ok(FromOverseer::Communication { .. }) => if let Some(hash) = last_leaf {
+							ctx.send_message(request_validators(hash)).await;
+						},
+						_ => break,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn outputs_for_active_leaves_are_accepted() {
+		known_leaves_test(false);
+	}
+
+	#[test]
+	#[should_panic(expected = "which is not an active leaf")]
+	fn outputs_for_deactivated_leaves_are_reported() {
+		known_leaves_test(true);
+	}
+
+	fn forwarding_test(expected: fn(&AllMessages) -> bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<Hash>| async move {
+				handle.assert_forwarded(Hash::repeat_byte(1), expected).await;
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					ctx.send_message(request_validators(msg)).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn forwarded_requests_are_accepted() {
+		forwarding_test(|msg| matches!(
+			msg,
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(relay_parent, RuntimeApiRequest::Validators(_)))
+				if *relay_parent == Hash::repeat_byte(1)
+		));
+	}
+
+	#[test]
+	#[should_panic(expected = "it sent RuntimeApi(Request(")]
+	fn mismatching_forwards_are_reported() {
+		forwarding_test(|msg| matches!(msg, AllMessages::ChainApi(_)));
+	}
+
+	fn request_validators(relay_parent: Hash) -> AllMessages {
+		RuntimeApiMessage::Request(relay_parent, RuntimeApiRequest::Validators(oneshot::channel().0)).into()
+	}
+
+	fn eventually_test(sent: u8) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let received = assert_eventually(
+					&mut handle,
+					Duration::from_millis(100),
+					|msgs| msgs.len() == 3,
+				).await;
+				assert_eq!(received.len(), 3);
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				for i in 0..sent {
+					ctx.send_message(request_validators(Hash::repeat_byte(i))).await;
+				}
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	fn addressed_test(expected: &'static [MessageKind]) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.assert_addressed_to(expected).await;
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let (tx, _rx) = oneshot::channel();
+				ctx.send_messages(vec![
+					request_validators(Hash::repeat_byte(1)),
+					ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx).into(),
+				]).await;
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn messages_are_addressed_in_order() {
+		addressed_test(&[MessageKind::RuntimeApi, MessageKind::ChainApi]);
+	}
+
+	#[test]
+	#[should_panic(expected = "Messages were not addressed as expected")]
+	fn misaddressed_messages_are_reported() {
+		addressed_test(&[MessageKind::ChainApi, MessageKind::RuntimeApi]);
+	}
+
+	#[test]
+	fn eventually_holding_predicate_passes() {
+		eventually_test(3);
+	}
+
+	#[test]
+	#[should_panic(expected = "The predicate didn't hold within 100ms, received 2 messages")]
+	fn eventually_reports_received_messages() {
+		eventually_test(2);
+	}
+
+	fn deactivation_test(cancel_jobs: bool) {
+		use std::collections::HashMap;
+		use futures::prelude::*;
+
+		let leaf = ActivatedLeaf {
+			hash: Hash::repeat_byte(7),
+			number: 7,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let stop = ActiveLeavesUpdate::stop_work(leaf.hash);
+				let sent = handle.assert_stops_on_deactivate(
+					leaf,
+					FromOverseer::Communication { msg: () },
+					FromOverseer::Signal(OverseerSignal::ActiveLeaves(stop)),
+				).await;
+				assert!(!sent.is_empty());
+
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut active = Vec::new();
+				let mut jobs = HashMap::new();
+
+				loop {
+					match ctx.recv().await {
+						Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) => {
+							active.extend(update.activated.into_iter().map(|leaf| leaf.hash));
+							for hash in update.deactivated {
+								active.retain(|h| *h != hash);
+								if cancel_jobs {
+									jobs.remove(&hash);
+								}
+							}
+						}
+						Ok(FromOverseer::Communication { .. }) => for hash in active.clone() {
+							let mut sender = ctx.sender().clone();
+							let (job, job_handle) = async move {
+								loop {
+									sender.send_message(request_validators(hash)).await;
+									futures_timer::Delay::new(Duration::from_millis(5)).await;
+								}
+							}.remote_handle();
+							ctx.spawn("job", job.boxed()).await.unwrap();
+							jobs.insert(hash, job_handle);
+						},
+						_ => break,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn cancelled_jobs_stop_on_deactivate() {
+		deactivation_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "still produced")]
+	fn leaking_jobs_are_reported() {
+		deactivation_test(false);
+	}
+
+	fn duplicate_leaf_test(deduplicate: bool) {
+		let leaf = ActivatedLeaf {
+			hash: Hash::repeat_byte(3),
+			number: 3,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				assert_eq!(handle.assert_tolerates_duplicate_leaf(leaf).await.len(), 1);
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let mut active = HashSet::new();
+				while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+					for leaf in update.activated {
+						if active.insert(leaf.hash) || !deduplicate {
+							ctx.send_message(request_validators(leaf.hash)).await;
+						}
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn duplicate_leaves_are_tolerated() {
+		duplicate_leaf_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "Duplicate activation of leaf")]
+	fn duplicate_leaf_output_is_reported() {
+		duplicate_leaf_test(false);
+	}
+
+	fn shutdown_race_test(exit_on_conclude: bool) {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let sent = handle.send_work_then_conclude(FromOverseer::Communication { msg: () }).await;
+				assert_eq!(sent.len(), 1);
+			},
+			|mut ctx| async move {
+				loop {
+					match ctx.recv().await {
+						Ok(FromOverseer::Communication { .. }) =>
+							ctx.send_message(request_validators(Hash::repeat_byte(1))).await,
+						_ if exit_on_conclude => break,
+						_ => futures::future::pending().await,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn racing_conclude_lets_the_subsystem_exit() {
+		shutdown_race_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem didn't exit within")]
+	fn lingering_subsystems_are_reported() {
+		shutdown_race_test(false);
+	}
+
+	#[test]
+	fn deduplicating_subsystem_is_idempotent() {
+		activate_twice(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "Second delivery was not a no-op")]
+	fn repeated_work_is_reported() {
+		activate_twice(false);
+	}
+}
@@ -0,0 +1,122 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A rendezvous point for the tasks of a test.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use parking_lot::Mutex;
+use polkadot_node_subsystem_util::TimeoutExt;
+
+use crate::assertion_timeout;
+
+/// An async barrier releasing all waiting tasks once a given number of them has arrived.
+///
+/// Unlike [`std::sync::Barrier`], waiting does not block the thread, and a task waiting for
+/// longer than the barrier's timeout panics instead of deadlocking the test. The barrier can be
+/// reused: once released, the next `n` arrivals form a new round.
+#[derive(Clone)]
+pub struct TestBarrier {
+	n: usize,
+	timeout: Duration,
+	waiting: Arc<Mutex<Vec<oneshot::Sender<()>>>>,
+}
+
+impl TestBarrier {
+	/// Create a barrier for `n` tasks, timing out after half the harness timeout.
+	pub fn new(n: usize) -> Self {
+		assert!(n > 0, "A barrier needs at least one task");
+
+		TestBarrier {
+			n,
+			timeout: assertion_timeout(),
+			waiting: Default::default(),
+		}
+	}
+
+	/// Set how long tasks wait for the others before panicking.
+	pub fn with_timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Wait until `n` tasks are waiting on the barrier.
+	///
+	/// Panics if the other tasks do not arrive within the timeout.
+	pub async fn wait(&self) {
+		let rx = {
+			let mut waiting = self.waiting.lock();
+			if waiting.len() + 1 == self.n {
+				for tx in waiting.drain(..) {
+					let _ = tx.send(());
+				}
+				return;
+			}
+
+			let (tx, rx) = oneshot::channel();
+			waiting.push(tx);
+			rx
+		};
+
+		if rx.timeout(self.timeout).await.is_none() {
+			panic!(
+				"TestBarrier timed out after {:?}: {} of {} tasks arrived",
+				self.timeout,
+				self.waiting.lock().len(),
+				self.n,
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::executor::block_on;
+	use futures::future::{join, join3};
+
+	#[test]
+	fn tasks_are_released_together() {
+		let barrier = TestBarrier::new(3);
+		let log = Arc::new(Mutex::new(Vec::new()));
+
+		let task = |i| {
+			let barrier = barrier.clone();
+			let log = log.clone();
+			async move {
+				log.lock().push(("arrived", i));
+				barrier.wait().await;
+				log.lock().push(("released", i));
+			}
+		};
+
+		block_on(join3(task(0), task(1), task(2)));
+
+		let log = log.lock();
+		assert_eq!(log.len(), 6);
+		assert!(log[..3].iter().all(|(event, _)| *event == "arrived"));
+		assert!(log[3..].iter().all(|(event, _)| *event == "released"));
+	}
+
+	#[test]
+	#[should_panic(expected = "2 of 3 tasks arrived")]
+	fn missing_tasks_time_out() {
+		let barrier = TestBarrier::new(3).with_timeout(Duration::from_millis(10));
+		block_on(join(barrier.wait(), barrier.wait()));
+	}
+}
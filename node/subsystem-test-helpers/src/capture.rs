@@ -0,0 +1,556 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Capturing the output of a test run, for comparison with other runs.
+
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::prelude::*;
+use parking_lot::Mutex;
+use polkadot_node_subsystem_util::TimeoutExt;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use polkadot_node_subsystem::FromOverseer;
+
+use crate::{
+	TestSubsystemContext, TestSubsystemContextHandle, harness_timeout, make_subsystem_context,
+	executor::LocalPoolExecutor, fixtures::duplicate, structural_eq::MessageProjection,
+};
+
+/// All messages the subsystem sent during a test run, in order.
+///
+/// Messages are captured as [`MessageProjection`]s when they are sent, before the test gets to
+/// consume them, so comparing reports compares messages structurally.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaptureReport {
+	/// The captured messages.
+	pub messages: Vec<MessageProjection>,
+}
+
+impl CaptureReport {
+	/// The index of the first message in which the reports differ, if any.
+	pub fn first_difference(&self, other: &CaptureReport) -> Option<usize> {
+		let len = std::cmp::max(self.messages.len(), other.messages.len());
+		(0..len).find(|i| self.messages.get(*i) != other.messages.get(*i))
+	}
+
+	/// Describe the first difference between the reports, if any.
+	pub fn diff(&self, other: &CaptureReport) -> Option<String> {
+		let render = |msg: Option<&MessageProjection>| msg.map_or_else(
+			|| "<no message>".to_owned(),
+			ToString::to_string,
+		);
+
+		self.first_difference(other).map(|i| format!(
+			"message #{} differs:\n  left: {}\n right: {}",
+			i,
+			render(self.messages.get(i)),
+			render(other.messages.get(i)),
+		))
+	}
+}
+
+/// Run a test like [`subsystem_test_harness`](crate::subsystem_test_harness), but on a
+/// [`LocalPoolExecutor`], capturing all messages sent by the subsystem.
+pub fn run_captured<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) -> CaptureReport
+where
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, LocalPoolExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let timeout = harness_timeout();
+	let executor = LocalPoolExecutor::new();
+	let (context, handle) = make_subsystem_context(executor.clone());
+	let outbound = handle.outbound.clone();
+	outbound.lock().capture = Some(Vec::new());
+
+	let overseer = overseer_factory(handle);
+	let test = test_factory(context);
+
+	executor.run_until(async move {
+		future::join(overseer, test)
+			.timeout(timeout)
+			.await
+			.expect("test timed out instead of completing")
+	});
+
+	let messages = outbound.lock().capture.take().unwrap_or_default();
+	CaptureReport { messages }
+}
+
+/// Like [`run_captured`], additionally measuring how long the run took in real time, e.g. to
+/// flag tests exceeding a budget.
+///
+/// The duration is measured with [`Instant`], independently of the virtual clock of the run.
+pub fn run_timed<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) -> (CaptureReport, Duration)
+where
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, LocalPoolExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let start = Instant::now();
+	let report = run_captured(overseer_factory, test_factory);
+	(report, start.elapsed())
+}
+
+/// Like [`run_captured`], additionally recording the inputs sent to the subsystem along with
+/// their timing, for [`replay_with_timing`].
+///
+/// The delay of each input is the time the [`VirtualClock`](crate::VirtualClock) advanced since
+/// the previous input, or since the start of the run for the first one.
+pub fn run_recorded<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) -> (CaptureReport, Session<M>)
+where
+	M: Clone + Send + 'static,
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, LocalPoolExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let inputs = Arc::new(Mutex::new(Vec::new()));
+	let recorded = inputs.clone();
+
+	let report = run_captured(
+		move |mut handle: TestSubsystemContextHandle<M>| {
+			let clock = handle.clock().clone();
+			let mut previous = clock.now();
+			handle.recorder = Some(Box::new(move |from_overseer: &FromOverseer<M>| {
+				let now = clock.now();
+				recorded.lock().push((now - previous, duplicate(from_overseer)));
+				previous = now;
+			}));
+			overseer_factory(handle)
+		},
+		test_factory,
+	);
+
+	let inputs = std::mem::take(&mut *inputs.lock());
+	(report, Session { inputs })
+}
+
+/// Run the test scenario produced by `factory` `runs` times, asserting all runs capture the
+/// same output.
+///
+/// This hunts for hidden nondeterminism. Note that the runs can only be identical if the
+/// subsystem and the test are deterministic throughout: any randomness must be seeded and timers
+/// must not depend on the wall clock.
+pub fn run_deterministic_check<M, Factory, OverseerFactory, Overseer, TestFactory, Test>(
+	runs: usize,
+	factory: Factory,
+)
+where
+	Factory: Fn() -> (OverseerFactory, TestFactory),
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, LocalPoolExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let (overseer_factory, test_factory) = factory();
+	let reference = run_captured(overseer_factory, test_factory);
+
+	for run in 1..runs {
+		let (overseer_factory, test_factory) = factory();
+		let report = run_captured(overseer_factory, test_factory);
+
+		if let Some(diff) = reference.diff(&report) {
+			panic!("Run #{} diverged from run #0, {}", run, diff);
+		}
+	}
+}
+
+/// Run two implementations of a subsystem against the same `input_script`, asserting they send
+/// structurally equal messages.
+///
+/// Each implementation is started by its factory and receives the script in order. Requests
+/// are captured but not answered, so the comparison ignores responders, and the script should
+/// end with `Conclude` for the runs to complete. Panics with the first differing pair of
+/// messages.
+pub fn assert_equivalent<M, FactoryA, SubsystemA, FactoryB, SubsystemB>(
+	factory_a: FactoryA,
+	factory_b: FactoryB,
+	input_script: Vec<FromOverseer<M>>,
+)
+where
+	M: Clone,
+	FactoryA: FnOnce(TestSubsystemContext<M, LocalPoolExecutor>) -> SubsystemA,
+	SubsystemA: Future<Output = ()>,
+	FactoryB: FnOnce(TestSubsystemContext<M, LocalPoolExecutor>) -> SubsystemB,
+	SubsystemB: Future<Output = ()>,
+{
+	let script = |input_script: Vec<FromOverseer<M>>| {
+		move |mut handle: TestSubsystemContextHandle<M>| async move {
+			for from_overseer in input_script {
+				handle.send(from_overseer).await;
+			}
+		}
+	};
+
+	let replay = input_script.iter().map(duplicate).collect();
+	let a = run_captured(script(input_script), factory_a);
+	let b = run_captured(script(replay), factory_b);
+
+	if let Some(diff) = a.diff(&b) {
+		panic!("The implementations are not equivalent, {}", diff);
+	}
+}
+
+/// Run the subsystem started by `factory` twice against `signals`, asserting it sends
+/// structurally equal messages whether the inputs arrive one by one or in a single batch.
+///
+/// In the first run, each input is sent once the subsystem read the previous one. In the
+/// second run, all inputs are queued at once, so they are pending together when the subsystem
+/// starts processing. Requests are captured but not answered, like in [`assert_equivalent`], and
+/// `signals` must end with `Conclude`, as both runs wait for the subsystem to exit. Panics with
+/// the first difference between the runs.
+pub fn assert_batching_invariant<M, Factory, Subsystem>(factory: Factory, signals: Vec<FromOverseer<M>>)
+where
+	M: Clone,
+	Factory: Fn(TestSubsystemContext<M, LocalPoolExecutor>) -> Subsystem,
+	Subsystem: Future<Output = ()>,
+{
+	let batch = signals.iter().map(duplicate).collect::<Vec<_>>();
+
+	let individual = run_captured(
+		move |mut handle: TestSubsystemContextHandle<M>| async move {
+			for from_overseer in signals {
+				handle.send(from_overseer).await;
+			}
+			handle.await_exit().await;
+		},
+		&factory,
+	);
+	let batched = run_captured(
+		move |mut handle: TestSubsystemContextHandle<M>| async move {
+			for from_overseer in batch {
+				handle.observe(&from_overseer);
+				handle.tx.enqueue(from_overseer);
+			}
+			handle.await_exit().await;
+		},
+		&factory,
+	);
+
+	if let Some(diff) = individual.diff(&batched) {
+		panic!("The batched run diverged from the individual one, {}", diff);
+	}
+}
+
+/// The inputs of a test run along with their timing, to be replayed with [`replay_with_timing`].
+///
+/// Record one with [`run_recorded`].
+pub struct Session<M> {
+	/// The inputs in order, each with the delay since the previous input, or since the start of
+	/// the session for the first one.
+	pub inputs: Vec<(Duration, FromOverseer<M>)>,
+}
+
+/// How [`replay_with_timing`] alters the delays between the inputs of a [`Session`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimingPolicy {
+	/// Keep the recorded delays.
+	Fixed,
+	/// Multiply every delay by the given finite, non-negative factor, see [`Self::scale`].
+	Scale(f64),
+	/// Replace every delay by one drawn uniformly from the non-empty range, using the given seed,
+	/// see [`Self::jitter`].
+	Jitter(Range<Duration>, u64),
+}
+
+impl TimingPolicy {
+	/// Multiply every delay by `factor`. Panics unless `factor` is finite and non-negative.
+	pub fn scale(factor: f64) -> Self {
+		let policy = TimingPolicy::Scale(factor);
+		policy.validate();
+		policy
+	}
+
+	/// Draw every delay uniformly from `range`, seeded by `seed`. Panics if `range` is empty.
+	pub fn jitter(range: Range<Duration>, seed: u64) -> Self {
+		let policy = TimingPolicy::Jitter(range, seed);
+		policy.validate();
+		policy
+	}
+
+	fn validate(&self) {
+		match self {
+			TimingPolicy::Fixed => {}
+			TimingPolicy::Scale(factor) => assert!(
+				factor.is_finite() && *factor >= 0.0,
+				"TimingPolicy::Scale needs a finite, non-negative factor, got {}",
+				factor,
+			),
+			TimingPolicy::Jitter(range, _) => assert!(
+				range.start < range.end,
+				"TimingPolicy::Jitter needs a non-empty range, got {:?}",
+				range,
+			),
+		}
+	}
+
+	fn delays(&self, recorded: impl Iterator<Item = Duration>) -> Vec<Duration> {
+		match self {
+			TimingPolicy::Fixed => recorded.collect(),
+			TimingPolicy::Scale(factor) => recorded.map(|delay| delay.mul_f64(*factor)).collect(),
+			TimingPolicy::Jitter(range, seed) => {
+				let mut rng = StdRng::seed_from_u64(*seed);
+				recorded.map(|_| rng.gen_range(range.clone())).collect()
+			}
+		}
+	}
+}
+
+/// Replay `session` against the subsystem started by `factory` with the delays altered by
+/// `policy`, asserting the subsystem sends the same messages as with the recorded delays.
+///
+/// Delays elapse on the [`VirtualClock`](crate::VirtualClock) of the context, which is advanced
+/// right before each input is sent, so timers of the subsystem fire without spending real time.
+/// Requests are captured but not answered, like in [`assert_equivalent`]. Returns the capture of
+/// the altered run, or panics with the first difference to the original timing. Panics before
+/// running anything if `policy` is invalid, see [`TimingPolicy::scale`] and
+/// [`TimingPolicy::jitter`].
+pub fn replay_with_timing<M, Factory, Subsystem>(
+	session: &Session<M>,
+	policy: TimingPolicy,
+	factory: Factory,
+) -> CaptureReport
+where
+	M: Clone,
+	Factory: Fn(TestSubsystemContext<M, LocalPoolExecutor>) -> Subsystem,
+	Subsystem: Future<Output = ()>,
+{
+	policy.validate();
+
+	let replay = |policy: &TimingPolicy| {
+		let delays = policy.delays(session.inputs.iter().map(|(delay, _)| *delay));
+		let inputs = delays
+			.into_iter()
+			.zip(session.inputs.iter().map(|(_, from_overseer)| duplicate(from_overseer)))
+			.collect::<Vec<_>>();
+
+		run_captured(
+			move |mut handle: TestSubsystemContextHandle<M>| async move {
+				for (delay, from_overseer) in inputs {
+					handle.clock().advance(delay);
+					handle.send(from_overseer).await;
+				}
+			},
+			&factory,
+		)
+	};
+
+	let original = replay(&TimingPolicy::Fixed);
+	let altered = replay(&policy);
+
+	if let Some(diff) = original.diff(&altered) {
+		panic!("The replay with {:?} diverged from the original session, {}", policy, diff);
+	}
+
+	altered
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicU8, Ordering};
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use polkadot_node_subsystem::messages::{AllMessages, ChainApiMessage};
+	use polkadot_primitives::v1::Hash;
+
+	fn scenario(byte: u8) -> (
+		impl FnOnce(TestSubsystemContextHandle<()>) -> future::BoxFuture<'static, ()>,
+		impl FnOnce(TestSubsystemContext<(), LocalPoolExecutor>) -> future::BoxFuture<'static, ()>,
+	) {
+		(
+			|mut handle| async move {
+				assert!(matches!(handle.recv().await, AllMessages::ChainApi(_)));
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			}.boxed(),
+			move |mut ctx| async move {
+				let (tx, _rx) = futures::channel::oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(byte), tx).into()).await;
+				assert!(ctx.recv().await.is_ok());
+			}.boxed(),
+		)
+	}
+
+	async fn request_block_numbers(mut ctx: TestSubsystemContext<u8, LocalPoolExecutor>, offset: u8) {
+		while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+			let (tx, _rx) = futures::channel::oneshot::channel();
+			let hash = Hash::repeat_byte(msg.wrapping_add(offset));
+			ctx.send_message(ChainApiMessage::BlockNumber(hash, tx).into()).await;
+		}
+	}
+
+	fn script() -> Vec<FromOverseer<u8>> {
+		vec![
+			FromOverseer::Communication { msg: 1 },
+			FromOverseer::Communication { msg: 2 },
+			FromOverseer::Signal(OverseerSignal::Conclude),
+		]
+	}
+
+	#[test]
+	fn equivalent_implementations_pass() {
+		assert_equivalent(
+			|ctx| request_block_numbers(ctx, 0),
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					let (tx, _rx) = futures::channel::oneshot::channel();
+					let batch = vec![ChainApiMessage::BlockNumber(Hash::repeat_byte(msg), tx).into()];
+					ctx.send_messages(batch).await;
+				}
+			},
+			script(),
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "The implementations are not equivalent, message #0 differs")]
+	fn diverging_implementations_are_reported() {
+		assert_equivalent(|ctx| request_block_numbers(ctx, 0), |ctx| request_block_numbers(ctx, 1), script());
+	}
+
+	/// Requests the block number of every hash it receives, or of the zero hash if it received
+	/// no input for a second.
+	async fn timing_sensitive(mut ctx: TestSubsystemContext<u8, LocalPoolExecutor>) {
+		let clock = ctx.clock().clone();
+		let mut last_input = clock.now();
+
+		while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+			let idle = clock.now() - last_input >= Duration::from_secs(1);
+			last_input = clock.now();
+
+			let (tx, _rx) = futures::channel::oneshot::channel();
+			let hash = Hash::repeat_byte(if idle { 0 } else { msg });
+			ctx.send_message(ChainApiMessage::BlockNumber(hash, tx).into()).await;
+		}
+	}
+
+	fn session() -> Session<u8> {
+		Session {
+			inputs: script().into_iter().map(|input| (Duration::from_millis(100), input)).collect(),
+		}
+	}
+
+	#[test]
+	fn timing_invariant_subsystems_pass() {
+		let policies = vec![
+			TimingPolicy::Scale(5.0),
+			TimingPolicy::Jitter(Duration::from_secs(0)..Duration::from_secs(10), 7),
+		];
+
+		for policy in policies {
+			let report = replay_with_timing(&session(), policy, |ctx| request_block_numbers(ctx, 0));
+			assert_eq!(report.messages.len(), 2);
+		}
+	}
+
+	#[test]
+	#[should_panic(expected = "The replay with Scale(20.0) diverged from the original session, message #0 differs")]
+	fn timing_dependent_subsystems_are_reported() {
+		replay_with_timing(&session(), TimingPolicy::Scale(20.0), timing_sensitive);
+	}
+
+	#[test]
+	fn recorded_sessions_are_replayed() {
+		let (report, session) = run_recorded(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				for input in script() {
+					handle.clock().advance(Duration::from_millis(100));
+					handle.send(input).await;
+				}
+			},
+			|ctx| request_block_numbers(ctx, 0),
+		);
+
+		let delays = session.inputs.iter().map(|(delay, _)| *delay).collect::<Vec<_>>();
+		assert_eq!(delays, vec![Duration::from_millis(100); 3]);
+		let replay = replay_with_timing(&session, TimingPolicy::scale(5.0), |ctx| request_block_numbers(ctx, 0));
+		assert_eq!(replay, report);
+	}
+
+	#[test]
+	#[should_panic(expected = "TimingPolicy::Scale needs a finite, non-negative factor, got -1")]
+	fn negative_scales_are_rejected() {
+		TimingPolicy::scale(-1.0);
+	}
+
+	#[test]
+	#[should_panic(expected = "TimingPolicy::Jitter needs a non-empty range, got 1s..1s")]
+	fn empty_jitter_ranges_are_rejected() {
+		replay_with_timing(
+			&session(),
+			TimingPolicy::Jitter(Duration::from_secs(1)..Duration::from_secs(1), 0),
+			|ctx| request_block_numbers(ctx, 0),
+		);
+	}
+
+	#[test]
+	fn timed_runs_are_captured() {
+		let (overseer, test) = scenario(1);
+		let (report, duration) = run_timed(overseer, test);
+		assert_eq!(report.messages.len(), 1);
+		assert!(duration < harness_timeout());
+	}
+
+	/// Requests one block number per batch of inputs pending at once.
+	async fn coalescing(mut ctx: TestSubsystemContext<u8, LocalPoolExecutor>) {
+		while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+			let mut sum = msg;
+			while let Ok(Some(msg)) = ctx.try_recv_communication().await {
+				sum += msg;
+			}
+
+			let (tx, _rx) = futures::channel::oneshot::channel();
+			ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(sum), tx).into()).await;
+		}
+	}
+
+	#[test]
+	fn batching_invariant_subsystems_pass() {
+		assert_batching_invariant(|ctx| request_block_numbers(ctx, 0), script());
+	}
+
+	#[test]
+	#[should_panic(expected = "The batched run diverged from the individual one, message #0 differs")]
+	fn batching_dependent_subsystems_are_reported() {
+		assert_batching_invariant(coalescing, script());
+	}
+
+	#[test]
+	fn deterministic_scenarios_pass() {
+		run_deterministic_check(3, || scenario(1));
+	}
+
+	#[test]
+	#[should_panic(expected = "Run #1 diverged from run #0")]
+	fn nondeterministic_scenarios_are_reported() {
+		let runs = AtomicU8::new(0);
+		run_deterministic_check(3, || scenario(runs.fetch_add(1, Ordering::SeqCst)));
+	}
+}
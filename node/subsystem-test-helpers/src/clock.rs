@@ -0,0 +1,170 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A clock under the control of the test.
+
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures::prelude::*;
+use parking_lot::Mutex;
+
+#[derive(Default)]
+struct ClockState {
+	now: Duration,
+	next_id: u64,
+	/// Deadlines and wakers of the pending sleeps, by id.
+	sleepers: BTreeMap<u64, (Duration, Waker)>,
+	/// Whether time stands still, see [`VirtualClock::freeze`].
+	frozen: bool,
+}
+
+/// A clock which only advances when told to.
+///
+/// Time is measured from the creation of the clock. Code sleeping on [`Self::sleep`] wakes up as
+/// soon as the test advances the clock far enough, so tests don't spend real time waiting.
+/// Clones share the same time.
+#[derive(Clone, Default)]
+pub struct VirtualClock(Arc<Mutex<ClockState>>);
+
+impl VirtualClock {
+	/// Create a clock at time zero.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The time elapsed since the creation of the clock.
+	pub fn now(&self) -> Duration {
+		self.0.lock().now
+	}
+
+	/// Freeze the clock: from now on, no time passes and no sleep completes, not even one of
+	/// zero length.
+	///
+	/// This asserts the subsystem makes progress from its inputs alone and that its
+	/// timer-dependent paths never trigger. Code waiting on a timer hangs by design, until the
+	/// harness times out. Advancing a frozen clock panics.
+	pub fn freeze(&self) {
+		self.0.lock().frozen = true;
+	}
+
+	/// Whether the clock has been frozen, see [`Self::freeze`].
+	pub fn is_frozen(&self) -> bool {
+		self.0.lock().frozen
+	}
+
+	/// Advance the clock by `by`, waking all sleeps which are due.
+	///
+	/// Panics if the clock is frozen.
+	pub fn advance(&self, by: Duration) {
+		let mut state = self.0.lock();
+		assert!(!state.frozen, "advance called on a frozen clock");
+		state.now += by;
+
+		let now = state.now;
+		let due = state.sleepers.iter()
+			.filter(|(_, (deadline, _))| *deadline <= now)
+			.map(|(id, _)| *id)
+			.collect::<Vec<_>>();
+
+		for id in due {
+			if let Some((_, waker)) = state.sleepers.remove(&id) {
+				waker.wake();
+			}
+		}
+	}
+
+	/// A future completing once the clock has advanced by `duration`.
+	pub fn sleep(&self, duration: Duration) -> Sleep {
+		let mut state = self.0.lock();
+		let id = state.next_id;
+		state.next_id += 1;
+
+		Sleep {
+			clock: self.clone(),
+			id,
+			deadline: state.now + duration,
+		}
+	}
+}
+
+/// A sleep on a [`VirtualClock`], see [`VirtualClock::sleep`].
+pub struct Sleep {
+	clock: VirtualClock,
+	id: u64,
+	deadline: Duration,
+}
+
+impl Future for Sleep {
+	type Output = ();
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		let mut state = self.clock.0.lock();
+		if !state.frozen && state.now >= self.deadline {
+			state.sleepers.remove(&self.id);
+			return Poll::Ready(());
+		}
+
+		state.sleepers.insert(self.id, (self.deadline, cx.waker().clone()));
+		Poll::Pending
+	}
+}
+
+impl Drop for Sleep {
+	fn drop(&mut self) {
+		self.clock.0.lock().sleepers.remove(&self.id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sleeps_complete_once_the_clock_advanced_enough() {
+		let clock = VirtualClock::new();
+		let mut sleep = clock.sleep(Duration::from_secs(6));
+
+		assert!((&mut sleep).now_or_never().is_none());
+		clock.advance(Duration::from_secs(5));
+		assert!((&mut sleep).now_or_never().is_none());
+		clock.advance(Duration::from_secs(1));
+		assert!(sleep.now_or_never().is_some());
+		assert_eq!(clock.now(), Duration::from_secs(6));
+	}
+
+	#[test]
+	fn frozen_clocks_never_complete_sleeps() {
+		let clock = VirtualClock::new();
+		clock.advance(Duration::from_secs(1));
+		clock.freeze();
+
+		assert!(clock.is_frozen());
+		assert!(clock.sleep(Duration::from_secs(0)).now_or_never().is_none());
+		assert_eq!(clock.now(), Duration::from_secs(1));
+	}
+
+	#[test]
+	#[should_panic(expected = "advance called on a frozen clock")]
+	fn frozen_clocks_cannot_be_advanced() {
+		let clock = VirtualClock::new();
+		clock.freeze();
+		clock.advance(Duration::from_secs(1));
+	}
+}
@@ -0,0 +1,458 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A mock overseer enforcing a request/response contract.
+//!
+//! The [`ContractOverseer`] is configured with an ordered list of expectations. Each expectation
+//! matches exactly one request of the subsystem under test and answers it. Running the contract
+//! panics as soon as the subsystem deviates from it.
+//!
+//! Additionally, standing rules registered with [`ContractOverseer::when`] answer any number of
+//! matching requests, computing the response from fields captured out of the request.
+//!
+//! Responses to expectations can be held back on the test's [`VirtualClock`] with
+//! [`ContractOverseer::delayed_by`], to simulate slow dependencies without spending real time.
+
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use polkadot_node_subsystem::messages::AllMessages;
+use polkadot_node_subsystem_util::TimeoutExt;
+
+use crate::{TestSubsystemContextHandle, VirtualClock, assertion_timeout};
+
+/// How long to wait for an expected request before declaring it missing, half the
+/// [`harness_timeout`](crate::harness_timeout).
+pub fn expectation_timeout() -> Duration {
+	assertion_timeout()
+}
+
+type Respond = Box<dyn FnOnce(AllMessages) + Send>;
+
+struct Expectation {
+	name: &'static str,
+	matcher: Box<dyn Fn(&AllMessages) -> bool + Send>,
+	respond: Respond,
+	delay: Duration,
+}
+
+/// A matched request whose response is held until the virtual clock reaches `due`.
+struct Held {
+	due: Duration,
+	msg: AllMessages,
+	respond: Respond,
+}
+
+enum Event {
+	Received(Option<AllMessages>),
+	TimedOut,
+	Due,
+}
+
+/// A standing rule, handing the message back if it doesn't apply.
+type Rule = Box<dyn FnMut(AllMessages) -> Result<(), AllMessages> + Send>;
+
+/// A mock overseer asserting the subsystem makes each expected request in order.
+///
+/// ```ignore
+/// let contract = ContractOverseer::new()
+/// 	.expect(
+/// 		"block number of the leaf",
+/// 		|msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..))),
+/// 		|msg| if let AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) = msg {
+/// 			let _ = tx.send(Ok(Some(1)));
+/// 		},
+/// 	);
+///
+/// subsystem_test_harness(
+/// 	|mut handle| async move { contract.run(&mut handle).await },
+/// 	|ctx| run(ctx),
+/// );
+/// ```
+#[derive(Default)]
+pub struct ContractOverseer {
+	expectations: Vec<Expectation>,
+	rules: Vec<Rule>,
+}
+
+/// A rule under construction, see [`ContractOverseer::when`].
+pub struct When<E> {
+	contract: ContractOverseer,
+	extract: E,
+}
+
+impl<E> When<E> {
+	/// Answer every request the rule applies to with the response computed from its captures.
+	pub fn reply_with<C, R>(self, reply: impl Fn(C) -> R + Send + 'static) -> ContractOverseer
+	where
+		E: FnMut(AllMessages) -> Result<(C, oneshot::Sender<R>), AllMessages> + Send + 'static,
+	{
+		let When { mut contract, mut extract } = self;
+		contract.rules.push(Box::new(move |msg| {
+			let (captured, tx) = extract(msg)?;
+			let _ = tx.send(reply(captured));
+			Ok(())
+		}));
+		contract
+	}
+}
+
+impl ContractOverseer {
+	/// Create a contract without any expectations.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Append an expectation to the contract.
+	///
+	/// The next request of the subsystem must satisfy `matcher`. It is then passed to `respond`,
+	/// which is in charge of answering through the request's response channel.
+	pub fn expect(
+		mut self,
+		name: &'static str,
+		matcher: impl Fn(&AllMessages) -> bool + Send + 'static,
+		respond: impl FnOnce(AllMessages) + Send + 'static,
+	) -> Self {
+		self.expectations.push(Expectation {
+			name,
+			matcher: Box::new(matcher),
+			respond: Box::new(respond),
+			delay: Duration::from_secs(0),
+		});
+		self
+	}
+
+	/// Hold the response to the most recently appended expectation for `delay`.
+	///
+	/// The delay is measured on the handle's [`VirtualClock`] from the moment the request is
+	/// matched, so the response is only sent once the test advanced the clock far enough.
+	/// Meanwhile, the contract keeps serving further requests. [`Self::run`] doesn't return
+	/// before all held responses have been sent, unless the subsystem hangs up.
+	///
+	/// Panics if the contract has no expectations yet.
+	pub fn delayed_by(mut self, delay: Duration) -> Self {
+		self.expectations
+			.last_mut()
+			.expect("delayed_by must follow an expectation")
+			.delay = delay;
+		self
+	}
+
+	/// Start a standing rule applying to the requests `extract` accepts.
+	///
+	/// `extract` takes the request and either returns the fields it captured together with the
+	/// request's response channel, or hands the request back if the rule doesn't apply. The reply
+	/// computed from the captures by [`When::reply_with`] is then sent on that channel.
+	///
+	/// Rules are not consumed. A request is first checked against the next ordered expectation,
+	/// then against the rules in the order they were registered: the first match wins.
+	pub fn when<E>(self, extract: E) -> When<E> {
+		When { contract: self, extract }
+	}
+
+	/// Drive the overseer side of `handle` until all expectations have been met.
+	///
+	/// A contract made up of rules only serves requests until the subsystem hangs up.
+	///
+	/// Panics naming the violated expectation if an unexpected request arrives or an expected
+	/// one doesn't arrive within [`expectation_timeout`].
+	pub async fn run<M>(mut self, handle: &mut TestSubsystemContextHandle<M>) {
+		let clock = handle.clock().clone();
+		let serve_until_hangup = self.expectations.is_empty();
+		let mut expectations = std::mem::take(&mut self.expectations).into_iter().enumerate();
+		let mut next = expectations.next();
+		let mut held = Vec::<Held>::new();
+
+		while next.is_some() || serve_until_hangup || !held.is_empty() {
+			let pending = next.as_ref().map(|(i, expectation)| (*i, expectation.name));
+			let received = match next_event(handle, pending.is_some(), &clock, &held).await {
+				Event::Received(received) => received,
+				Event::TimedOut => {
+					let (i, name) = pending.expect("only waiting for expectations times out");
+					panic!(
+						"Expectation #{} ({}) missing: nothing received within {:?}",
+						i,
+						name,
+						expectation_timeout(),
+					)
+				}
+				Event::Due => {
+					release_due(&clock, &mut held);
+					continue;
+				}
+			};
+
+			let msg = match (received, pending) {
+				(Some(msg), _) => msg,
+				(None, Some((i, name))) => panic!("Expectation #{} ({}) missing: the subsystem hung up", i, name),
+				(None, None) => return,
+			};
+
+			let msg = match next.take() {
+				Some((_, expectation)) if (expectation.matcher)(&msg) => {
+					held.push(Held {
+						due: clock.now() + expectation.delay,
+						msg,
+						respond: expectation.respond,
+					});
+					release_due(&clock, &mut held);
+					next = expectations.next();
+					continue;
+				}
+				not_matching => {
+					next = not_matching;
+					msg
+				}
+			};
+
+			let msg = match self.apply_rules(msg) {
+				Ok(()) => continue,
+				Err(msg) => msg,
+			};
+
+			match next {
+				Some((i, expectation)) => panic!(
+					"Expectation #{} ({}) failed: received unexpected {:?}",
+					i,
+					expectation.name,
+					msg,
+				),
+				None => panic!("No rule applies to {:?}", msg),
+			}
+		}
+	}
+
+	fn apply_rules(&mut self, mut msg: AllMessages) -> Result<(), AllMessages> {
+		for rule in &mut self.rules {
+			msg = match rule(msg) {
+				Ok(()) => return Ok(()),
+				Err(msg) => msg,
+			};
+		}
+
+		Err(msg)
+	}
+}
+
+/// Wait for the next request, for the timeout of a `pending` expectation or for the earliest
+/// `held` response to become due, whichever comes first.
+async fn next_event<M>(
+	handle: &mut TestSubsystemContextHandle<M>,
+	pending: bool,
+	clock: &VirtualClock,
+	held: &[Held],
+) -> Event {
+	let received = async {
+		if pending {
+			match handle.try_recv().timeout(expectation_timeout()).await {
+				Some(received) => Event::Received(received),
+				None => Event::TimedOut,
+			}
+		} else {
+			Event::Received(handle.try_recv().await)
+		}
+	};
+
+	let due = match held.iter().map(|held| held.due).min() {
+		Some(due) => Either::Left(clock.sleep(due.saturating_sub(clock.now()))),
+		None => Either::Right(future::pending()),
+	};
+
+	futures::pin_mut!(received);
+	match future::select(received, due).await {
+		Either::Left((event, _)) => event,
+		Either::Right(((), _)) => Event::Due,
+	}
+}
+
+/// Send the `held` responses which are due, in the order their requests were matched.
+fn release_due(clock: &VirtualClock, held: &mut Vec<Held>) {
+	let now = clock.now();
+	let (due, pending): (Vec<_>, Vec<_>) = held.drain(..).partition(|held| held.due <= now);
+	*held = pending;
+
+	for Held { msg, respond, .. } in due {
+		respond(msg);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_subsystem::SubsystemContext;
+	use polkadot_node_subsystem::messages::ChainApiMessage;
+	use polkadot_primitives::v1::Hash;
+	use crate::subsystem_test_harness;
+
+	fn block_number_contract(hash: Hash) -> ContractOverseer {
+		ContractOverseer::new().expect(
+			"block number",
+			move |msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(h, _)) if *h == hash),
+			|msg| if let AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) = msg {
+				let _ = tx.send(Ok(Some(5)));
+			},
+		)
+	}
+
+	#[test]
+	fn contract_answers_expected_requests() {
+		let hash = Hash::repeat_byte(1);
+		let contract = block_number_contract(hash);
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				contract.run(&mut handle).await;
+			},
+			|mut ctx| async move {
+				let (tx, rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(hash, tx).into()).await;
+				assert_eq!(rx.await.unwrap().unwrap(), Some(5));
+			},
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "Expectation #0 (block number) failed")]
+	fn contract_rejects_unexpected_requests() {
+		let contract = block_number_contract(Hash::repeat_byte(1));
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				contract.run(&mut handle).await;
+			},
+			|mut ctx| async move {
+				let (tx, _rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(2), tx).into()).await;
+			},
+		);
+	}
+
+	fn ancestors(msg: AllMessages) -> Result<((Hash, usize), oneshot::Sender<ChainApiResult>), AllMessages> {
+		match msg {
+			AllMessages::ChainApi(ChainApiMessage::Ancestors { hash, k, response_channel }) =>
+				Ok(((hash, k), response_channel)),
+			msg => Err(msg),
+		}
+	}
+
+	type ChainApiResult = Result<Vec<Hash>, polkadot_node_subsystem::errors::ChainApiError>;
+
+	async fn request_ancestors(ctx: &mut impl SubsystemContext, hash: Hash, k: usize) -> Vec<Hash> {
+		let (tx, rx) = oneshot::channel();
+		ctx.send_message(ChainApiMessage::Ancestors { hash, k, response_channel: tx }.into()).await;
+		rx.await.unwrap().unwrap()
+	}
+
+	#[test]
+	fn rules_reply_with_captured_fields() {
+		let contract = ContractOverseer::new()
+			.when(ancestors)
+			.reply_with(|(hash, k)| Ok(vec![hash; k]));
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				contract.run(&mut handle).await;
+			},
+			|mut ctx| async move {
+				let hash = Hash::repeat_byte(3);
+				assert_eq!(request_ancestors(&mut ctx, hash, 2).await, vec![hash; 2]);
+				assert_eq!(request_ancestors(&mut ctx, hash, 1).await, vec![hash]);
+			},
+		);
+	}
+
+	#[test]
+	fn first_matching_rule_wins() {
+		let contract = ContractOverseer::new()
+			.when(ancestors)
+			.reply_with(|_| Ok(Vec::new()))
+			.when(ancestors)
+			.reply_with(|(hash, k)| Ok(vec![hash; k]));
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				contract.run(&mut handle).await;
+			},
+			|mut ctx| async move {
+				assert!(request_ancestors(&mut ctx, Hash::repeat_byte(3), 2).await.is_empty());
+			},
+		);
+	}
+
+	#[test]
+	fn rules_apply_while_waiting_for_expectations() {
+		let hash = Hash::repeat_byte(1);
+		let contract = block_number_contract(hash)
+			.when(ancestors)
+			.reply_with(|(hash, k)| Ok(vec![hash; k]));
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				contract.run(&mut handle).await;
+			},
+			|mut ctx| async move {
+				assert_eq!(request_ancestors(&mut ctx, hash, 1).await, vec![hash]);
+
+				let (tx, rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(hash, tx).into()).await;
+				assert_eq!(rx.await.unwrap().unwrap(), Some(5));
+			},
+		);
+	}
+
+	#[test]
+	fn delayed_responses_wait_for_the_clock() {
+		let hash = Hash::repeat_byte(1);
+		let contract = block_number_contract(hash)
+			.delayed_by(Duration::from_secs(6))
+			.when(ancestors)
+			.reply_with(|(hash, k)| Ok(vec![hash; k]));
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				contract.run(&mut handle).await;
+			},
+			|mut ctx| async move {
+				let (tx, mut rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(hash, tx).into()).await;
+
+				// Served after the slow request was matched, while its response is still held.
+				assert_eq!(request_ancestors(&mut ctx, hash, 1).await, vec![hash]);
+				ctx.clock().advance(Duration::from_secs(5));
+				assert!(rx.try_recv().unwrap().is_none());
+
+				ctx.clock().advance(Duration::from_secs(1));
+				assert_eq!(rx.await.unwrap().unwrap(), Some(5));
+			},
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "Expectation #0 (block number) missing")]
+	fn contract_reports_missing_requests() {
+		let contract = block_number_contract(Hash::repeat_byte(1));
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				contract.run(&mut handle).await;
+			},
+			|ctx| async move {
+				drop(ctx);
+			},
+		);
+	}
+}
@@ -0,0 +1,208 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A declarative shorthand for subsystem tests.
+
+/// Test a subsystem against a declarative overseer script.
+///
+/// Expands to [`subsystem_test_harness`](crate::subsystem_test_harness), with the script run as
+/// the overseer and `subsystem` as the test. The script is made up of:
+///
+/// * `send!(from_overseer);` sends a message or signal to the subsystem.
+/// * `expect!(pattern => binding);` receives the next message, which must match `pattern`, and
+///   binds `binding` (an identifier, or a tuple of identifiers) for the rest of the script.
+///   `expect!(pattern);` doesn't bind anything.
+/// * `reply!(binding, value);` sends `value` on the response channel `binding`.
+/// * any other statement, which is inserted as is.
+///
+/// ```ignore
+/// test_subsystem! {
+/// 	subsystem: |ctx| run(ctx),
+/// 	script: {
+/// 		send!(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update)));
+/// 		expect!(AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) => (hash, tx));
+/// 		assert_eq!(hash, leaf);
+/// 		reply!(tx, Ok(Some(1)));
+/// 		send!(FromOverseer::Signal(OverseerSignal::Conclude));
+/// 	}
+/// }
+/// ```
+#[macro_export]
+macro_rules! test_subsystem {
+	(
+		subsystem: $subsystem:expr,
+		script: { $($script:tt)* } $(,)?
+	) => {
+		$crate::subsystem_test_harness(
+			|mut handle| async move {
+				$crate::test_subsystem!(@script handle; $($script)*);
+			},
+			$subsystem,
+		)
+	};
+	(@script $handle:ident;) => {};
+	(@script $handle:ident; send!($msg:expr); $($rest:tt)*) => {
+		$handle.send($msg).await;
+		$crate::test_subsystem!(@script $handle; $($rest)*);
+	};
+	(@script $handle:ident; expect!($pat:pat => ($($bind:ident),+ $(,)?)); $($rest:tt)*) => {
+		let ($($bind),+) = match $handle.recv().await {
+			$pat => ($($bind),+),
+			other => panic!("Expected message matching `{}`, received {:?}", stringify!($pat), other),
+		};
+		$crate::test_subsystem!(@script $handle; $($rest)*);
+	};
+	(@script $handle:ident; expect!($pat:pat => $bind:ident); $($rest:tt)*) => {
+		let $bind = match $handle.recv().await {
+			$pat => $bind,
+			other => panic!("Expected message matching `{}`, received {:?}", stringify!($pat), other),
+		};
+		$crate::test_subsystem!(@script $handle; $($rest)*);
+	};
+	(@script $handle:ident; expect!($pat:pat); $($rest:tt)*) => {
+		match $handle.recv().await {
+			$pat => {},
+			other => panic!("Expected message matching `{}`, received {:?}", stringify!($pat), other),
+		}
+		$crate::test_subsystem!(@script $handle; $($rest)*);
+	};
+	(@script $handle:ident; reply!($responder:expr, $value:expr); $($rest:tt)*) => {
+		let _ = $responder.send($value);
+		$crate::test_subsystem!(@script $handle; $($rest)*);
+	};
+	(@script $handle:ident; $stmt:stmt; $($rest:tt)*) => {
+		$stmt;
+		$crate::test_subsystem!(@script $handle; $($rest)*);
+	};
+}
+
+/// Receive the next message from a [`TestSubsystemContextHandle`] and match it against a
+/// pattern, evaluating to the expression after `=>`, which may use the pattern's bindings.
+///
+/// The pattern may be refutable and carry a guard. Panics naming the pattern and the actual
+/// message if the message doesn't match. Without an expression, evaluates to `()`.
+///
+/// ```ignore
+/// let hash = expect_message!(
+/// 	handle,
+/// 	AllMessages::CandidateBacking(CandidateBackingMessage::Second(hash, ..)) => hash
+/// );
+/// let tx = expect_message!(
+/// 	handle,
+/// 	AllMessages::ChainApi(ChainApiMessage::BlockNumber(h, tx)) if h == hash => tx
+/// );
+/// ```
+///
+/// [`TestSubsystemContextHandle`]: crate::TestSubsystemContextHandle
+#[macro_export]
+macro_rules! expect_message {
+	($handle:expr, $pat:pat => $value:expr $(,)?) => {
+		match $handle.recv().await {
+			$pat => $value,
+			other => panic!("Expected message matching `{}`, received {:?}", stringify!($pat), other),
+		}
+	};
+	($handle:expr, $pat:pat if $guard:expr => $value:expr $(,)?) => {
+		match $handle.recv().await {
+			$pat if $guard => $value,
+			other => panic!(
+				"Expected message matching `{} if {}`, received {:?}",
+				stringify!($pat),
+				stringify!($guard),
+				other,
+			),
+		}
+	};
+	($handle:expr, $pat:pat $(,)?) => {
+		$crate::expect_message!($handle, $pat => ())
+	};
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::channel::oneshot;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use polkadot_node_subsystem::messages::{AllMessages, ChainApiMessage};
+	use polkadot_primitives::v1::Hash;
+	use crate::{TestSubsystemContext, TestSubsystemContextHandle, subsystem_test_harness};
+
+	#[test]
+	fn script_drives_the_subsystem() {
+		let leaf = Hash::repeat_byte(7);
+
+		test_subsystem! {
+			subsystem: |mut ctx: TestSubsystemContext<(), _>| async move {
+				let (tx, rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(leaf, tx).into()).await;
+				assert_eq!(rx.await.unwrap().unwrap(), Some(3));
+
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+			script: {
+				expect!(AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) => (hash, tx));
+				assert_eq!(hash, leaf);
+				reply!(tx, Ok(Some(3)));
+				send!(FromOverseer::Signal(OverseerSignal::Conclude));
+			}
+		}
+	}
+
+	#[test]
+	#[should_panic(expected = "Expected message matching")]
+	fn mismatch_reports_the_pattern() {
+		test_subsystem! {
+			subsystem: |mut ctx: TestSubsystemContext<(), _>| async move {
+				let (tx, _rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(Hash::zero(), tx).into()).await;
+			},
+			script: {
+				expect!(AllMessages::CandidateBacking(_));
+			}
+		}
+	}
+
+	/// Requests the block number of `hash`, expecting 4.
+	async fn request_block_number(mut ctx: TestSubsystemContext<(), sp_core::testing::TaskExecutor>, hash: Hash) {
+		let (tx, rx) = oneshot::channel();
+		ctx.send_message(ChainApiMessage::BlockNumber(hash, tx).into()).await;
+		assert_eq!(rx.await.unwrap().unwrap(), Some(4));
+	}
+
+	#[test]
+	fn expect_message_binds_the_pattern() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let tx = expect_message!(
+					handle,
+					AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) if hash == Hash::repeat_byte(2) => tx,
+				);
+				let _ = tx.send(Ok(Some(4)));
+			},
+			|ctx| request_block_number(ctx, Hash::repeat_byte(2)),
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "Expected message matching `AllMessages::CandidateBacking(_)`, received ChainApi")]
+	fn expect_message_reports_the_actual_message() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				expect_message!(handle, AllMessages::CandidateBacking(_));
+			},
+			|ctx| request_block_number(ctx, Hash::repeat_byte(2)),
+		);
+	}
+}
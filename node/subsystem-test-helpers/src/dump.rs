@@ -0,0 +1,196 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Dumping the messages sent by the subsystem to a file, for offline analysis, and comparing
+//! them against golden files.
+
+use std::{io, path::Path};
+
+use polkadot_node_subsystem::messages::AllMessages;
+use serde_json::{Value, json};
+
+use crate::TestSubsystemContextHandle;
+use crate::structural_eq::project;
+
+/// The environment variable which, when set, makes
+/// [`TestSubsystemContextHandle::assert_matches_golden`] write golden files instead of checking
+/// against them.
+pub const UPDATE_GOLDEN_ENV_VAR: &str = "UPDATE_GOLDEN";
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Write the messages the subsystem has sent but the test hasn't taken yet to `path`.
+	///
+	/// The file holds a JSON array with one object per message, in order, giving its index,
+	/// subsystem, variant and the `Debug` rendering of its fields. Response channels can't be
+	/// serialized and are rendered as [`RESPONDER`](crate::structural_eq::RESPONDER), which makes
+	/// dumps of deterministic runs identical. The messages stay queued for the test to receive.
+	pub fn dump_to(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+		self.buffer_ready();
+		std::fs::write(path, render(self.buffered.iter()))
+	}
+
+	/// Receive the next message and assert it matches the golden file at `path`.
+	///
+	/// The message is compared in the JSON format of [`Self::dump_to`], without the index. If the
+	/// [`UPDATE_GOLDEN_ENV_VAR`] environment variable is set, the file is (re)written from the
+	/// message instead, creating missing directories. Panics with a line diff on mismatch, or if
+	/// the file can't be read. Returns the message, e.g. to answer it.
+	pub async fn assert_matches_golden(&mut self, path: impl AsRef<Path>) -> AllMessages {
+		let path = path.as_ref();
+		let msg = self.recv().await;
+		let actual = pretty(entry(&msg));
+
+		if std::env::var_os(UPDATE_GOLDEN_ENV_VAR).is_some() {
+			if let Some(parent) = path.parent() {
+				std::fs::create_dir_all(parent).expect("failed to create the golden file directory");
+			}
+			std::fs::write(path, actual).expect("failed to write the golden file");
+			return msg;
+		}
+
+		let expected = match std::fs::read_to_string(path) {
+			Ok(expected) => expected,
+			Err(err) => panic!(
+				"Failed to read the golden file {}: {}, set {} to create it",
+				path.display(),
+				err,
+				UPDATE_GOLDEN_ENV_VAR,
+			),
+		};
+
+		if expected != actual {
+			panic!(
+				"The message doesn't match the golden file {} (- golden, + actual):\n{}",
+				path.display(),
+				line_diff(&expected, &actual),
+			);
+		}
+
+		msg
+	}
+}
+
+fn entry(msg: &AllMessages) -> Value {
+	let projection = project(msg);
+	json!({
+		"subsystem": projection.subsystem,
+		"variant": projection.variant,
+		"fields": projection.fields,
+	})
+}
+
+fn render<'a>(msgs: impl Iterator<Item = &'a AllMessages>) -> String {
+	let entries = msgs.enumerate().map(|(index, msg)| {
+		let mut entry = entry(msg);
+		entry["index"] = json!(index);
+		entry
+	}).collect();
+
+	pretty(Value::Array(entries))
+}
+
+fn pretty(value: Value) -> String {
+	let mut rendered = serde_json::to_string_pretty(&value).expect("JSON values always serialize");
+	rendered.push('\n');
+	rendered
+}
+
+/// Compare `expected` and `actual` line by line, marking the lines which differ.
+fn line_diff(expected: &str, actual: &str) -> String {
+	let expected = expected.lines().collect::<Vec<_>>();
+	let actual = actual.lines().collect::<Vec<_>>();
+
+	(0..std::cmp::max(expected.len(), actual.len()))
+		.flat_map(|i| match (expected.get(i), actual.get(i)) {
+			(Some(e), Some(a)) if e == a => vec![format!("  {}", e)],
+			(e, a) => e.map(|e| format!("- {}", e)).into_iter().chain(a.map(|a| format!("+ {}", a))).collect(),
+		})
+		.collect::<Vec<_>>()
+		.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::channel::oneshot;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use polkadot_node_subsystem::messages::ChainApiMessage;
+	use polkadot_primitives::v1::Hash;
+	use crate::subsystem_test_harness;
+
+	#[test]
+	fn dumps_pending_messages_with_placeholders() {
+		let path = std::env::temp_dir().join(format!("subsystem-dump-{}.json", std::process::id()));
+		let dumped = path.clone();
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				// Resolves once the subsystem read it, after sending its messages.
+				handle.send(FromOverseer::Communication { msg: () }).await;
+				handle.dump_to(&dumped).unwrap();
+				assert_eq!(handle.drain().len(), 2);
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				for i in 1..=2 {
+					let (tx, _) = oneshot::channel();
+					ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(i), tx).into()).await;
+				}
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Communication { .. })));
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+
+		let dump: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert_eq!(dump.as_array().unwrap().len(), 2);
+		assert_eq!(dump[1]["index"], 1);
+		assert_eq!(dump[1]["subsystem"], "ChainApi");
+		assert_eq!(dump[1]["variant"], "BlockNumber");
+		assert_eq!(dump[1]["fields"][1], "<responder>");
+	}
+
+	#[test]
+	fn golden_mismatches_show_a_diff() {
+		let expected = "[\n  \"a\",\n  \"b\"\n]\n";
+		let actual = "[\n  \"a\",\n  \"c\"\n]\n";
+
+		assert_eq!(
+			line_diff(expected, actual),
+			"  [\n    \"a\",\n-   \"b\"\n+   \"c\"\n  ]",
+		);
+	}
+
+	#[test]
+	fn messages_are_checked_against_golden_files() {
+		let path = std::env::temp_dir().join(format!("subsystem-golden-{}.json", std::process::id()));
+		let golden = path.clone();
+		let msg = AllMessages::ChainApi(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), oneshot::channel().0));
+		std::fs::write(&path, pretty(entry(&msg))).unwrap();
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.assert_matches_golden(&golden).await;
+			},
+			|mut ctx| async move {
+				ctx.send_message(msg).await;
+			},
+		);
+
+		std::fs::remove_file(&path).unwrap();
+	}
+}
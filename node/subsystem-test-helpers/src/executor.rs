@@ -0,0 +1,548 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Executors for running subsystem tests.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, Thread};
+
+use futures::future::{BoxFuture, Future, FutureExt};
+use futures::task::{ArcWake, waker};
+use parking_lot::Mutex;
+use rand::{SeedableRng, rngs::StdRng, seq::SliceRandom};
+use sp_core::traits::SpawnNamed;
+
+/// Task id of the future passed to [`LocalPoolExecutor::run_until`].
+const MAIN: usize = usize::MAX;
+
+#[derive(Default)]
+struct Queue {
+	/// Spawned futures not yet adopted by the run loop.
+	incoming: Vec<BoxFuture<'static, ()>>,
+	/// Tasks woken since they were last polled, in order of wake-up.
+	ready: VecDeque<usize>,
+}
+
+#[derive(Default)]
+struct Shared {
+	queue: Mutex<Queue>,
+	/// The thread driving the run loop, to be unparked on wake-ups.
+	thread: Mutex<Option<Thread>>,
+	/// Shuffles the ready tasks before polling them, if set, see [`LocalPoolExecutor::with_seed`].
+	rng: Mutex<Option<StdRng>>,
+}
+
+impl Shared {
+	fn unpark(&self) {
+		if let Some(thread) = &*self.thread.lock() {
+			thread.unpark();
+		}
+	}
+}
+
+struct TaskWaker {
+	id: usize,
+	shared: Arc<Shared>,
+}
+
+impl ArcWake for TaskWaker {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+		arc_self.shared.queue.lock().ready.push_back(arc_self.id);
+		arc_self.shared.unpark();
+	}
+}
+
+/// A single-threaded executor polling tasks in a deterministic order.
+///
+/// All spawned tasks, blocking or not, run on the thread calling [`Self::run_until`]. Tasks are
+/// polled in the order they were woken up in, so as long as all wake-ups originate from the
+/// tasks themselves, a test run is reproducible.
+#[derive(Clone, Default)]
+pub struct LocalPoolExecutor {
+	shared: Arc<Shared>,
+}
+
+impl LocalPoolExecutor {
+	/// Create a new executor.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Poll the tasks which are ready at the same time in an order derived from `seed`, rather
+	/// than in the order they were woken up in.
+	///
+	/// Only ready tasks are reordered: a task waiting for another one is still only polled once
+	/// woken, so the interleavings explored are those a multi-threaded executor could produce.
+	/// Runs with the same seed are reproducible, so trying several seeds, e.g. in a property
+	/// test, explores different interleavings of the subsystem's tasks reproducibly. Clones share
+	/// the seeded order.
+	pub fn with_seed(self, seed: u64) -> Self {
+		*self.shared.rng.lock() = Some(StdRng::seed_from_u64(seed));
+		self
+	}
+
+	/// Run `future` to completion on the current thread, along with all spawned tasks.
+	///
+	/// Spawned tasks which are still pending when `future` completes are not polled any further.
+	pub fn run_until<F: Future>(&self, future: F) -> F::Output {
+		*self.shared.thread.lock() = Some(thread::current());
+		futures::pin_mut!(future);
+
+		let main_waker = waker(Arc::new(TaskWaker { id: MAIN, shared: self.shared.clone() }));
+		let mut tasks: Vec<Option<(BoxFuture<'static, ()>, Waker)>> = Vec::new();
+		self.shared.queue.lock().ready.push_back(MAIN);
+
+		loop {
+			let batch = {
+				let mut guard = self.shared.queue.lock();
+				let queue = &mut *guard;
+
+				for task in queue.incoming.drain(..) {
+					let id = tasks.len();
+					let task_waker = waker(Arc::new(TaskWaker { id, shared: self.shared.clone() }));
+					tasks.push(Some((task, task_waker)));
+					queue.ready.push_back(id);
+				}
+
+				let mut seen = HashSet::new();
+				let mut batch = queue.ready.drain(..).filter(|id| seen.insert(*id)).collect::<Vec<_>>();
+				if let Some(rng) = self.shared.rng.lock().as_mut() {
+					batch.shuffle(rng);
+				}
+				batch
+			};
+
+			if batch.is_empty() {
+				thread::park();
+				continue;
+			}
+
+			for id in batch {
+				if id == MAIN {
+					if let Poll::Ready(output) = future.as_mut().poll(&mut Context::from_waker(&main_waker)) {
+						return output;
+					}
+				} else if let Some((task, task_waker)) = tasks[id].as_mut() {
+					if task.as_mut().poll(&mut Context::from_waker(task_waker)).is_ready() {
+						tasks[id] = None;
+					}
+				}
+			}
+		}
+	}
+}
+
+impl SpawnNamed for LocalPoolExecutor {
+	fn spawn_blocking(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		self.spawn(name, future)
+	}
+
+	fn spawn(&self, _name: &'static str, future: BoxFuture<'static, ()>) {
+		self.shared.queue.lock().incoming.push(future);
+		self.shared.unpark();
+	}
+}
+
+#[derive(Default)]
+struct SpawnStats {
+	total: AtomicUsize,
+	non_blocking: AtomicUsize,
+	blocking: AtomicUsize,
+	/// Spawned tasks which have neither completed nor been dropped yet.
+	active: AtomicUsize,
+	names: Mutex<Vec<&'static str>>,
+}
+
+/// Accounts for a spawned task as active as long as it lives.
+struct ActiveGuard(Arc<SpawnStats>);
+
+impl Drop for ActiveGuard {
+	fn drop(&mut self) {
+		self.0.active.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// An executor wrapper keeping count of the tasks spawned through it.
+///
+/// Useful to guard against subsystems spawning more tasks than they should, e.g. one per message
+/// instead of one per leaf. Blocking and non-blocking spawns are counted separately as well, to
+/// check that CPU-heavy work goes through `spawn_blocking` rather than stalling the async
+/// executor. Clones share their counters.
+#[derive(Clone)]
+pub struct TrackingExecutor<S> {
+	inner: S,
+	stats: Arc<SpawnStats>,
+}
+
+impl<S> TrackingExecutor<S> {
+	/// Wrap `inner`, spawning all tasks on it.
+	pub fn new(inner: S) -> Self {
+		TrackingExecutor {
+			inner,
+			stats: Default::default(),
+		}
+	}
+
+	/// The number of tasks spawned so far, blocking ones included.
+	pub fn total_spawned(&self) -> usize {
+		self.stats.total.load(Ordering::Relaxed)
+	}
+
+	/// The number of non-blocking tasks spawned so far.
+	pub fn non_blocking_spawned(&self) -> usize {
+		self.stats.non_blocking.load(Ordering::Relaxed)
+	}
+
+	/// The number of blocking tasks spawned so far.
+	pub fn blocking_spawned(&self) -> usize {
+		self.stats.blocking.load(Ordering::Relaxed)
+	}
+
+	/// The number of spawned tasks which haven't completed yet.
+	///
+	/// Tasks dropped by the executor before completing, e.g. aborted ones, are no longer counted
+	/// either, so this drops to zero once the subsystem tore down all of its tasks.
+	pub fn active_task_count(&self) -> usize {
+		self.stats.active.load(Ordering::SeqCst)
+	}
+
+	/// The names of the tasks spawned so far, in spawn order.
+	pub fn spawned_names(&self) -> Vec<&'static str> {
+		self.stats.names.lock().clone()
+	}
+
+	/// Assert the names of all tasks spawned so far start with `prefix`, e.g. the subsystem's
+	/// name, as production task names are only attributable with a consistent naming scheme.
+	///
+	/// Panics naming the offending tasks.
+	pub fn assert_all_named_with_prefix(&self, prefix: &str) {
+		let offending = self.stats.names.lock()
+			.iter()
+			.filter(|name| !name.starts_with(prefix))
+			.copied()
+			.collect::<Vec<_>>();
+
+		assert!(
+			offending.is_empty(),
+			"{} spawned tasks are not prefixed with {:?}: {:?}",
+			offending.len(),
+			prefix,
+			offending,
+		);
+	}
+
+	fn record(&self, name: &'static str, future: BoxFuture<'static, ()>) -> BoxFuture<'static, ()> {
+		self.stats.total.fetch_add(1, Ordering::Relaxed);
+		self.stats.names.lock().push(name);
+
+		self.stats.active.fetch_add(1, Ordering::SeqCst);
+		let guard = ActiveGuard(self.stats.clone());
+		async move {
+			future.await;
+			drop(guard);
+		}.boxed()
+	}
+}
+
+impl<S: SpawnNamed> SpawnNamed for TrackingExecutor<S> {
+	fn spawn_blocking(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		let future = self.record(name, future);
+		self.stats.blocking.fetch_add(1, Ordering::Relaxed);
+		self.inner.spawn_blocking(name, future)
+	}
+
+	fn spawn(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		let future = self.record(name, future);
+		self.stats.non_blocking.fetch_add(1, Ordering::Relaxed);
+		self.inner.spawn(name, future)
+	}
+}
+
+#[derive(Default)]
+struct Faults {
+	/// The faults yet to be injected, in order. `None` targets the next task, whatever its name.
+	pending: Vec<Option<&'static str>>,
+	/// The names of the tasks faults have been injected into, in order.
+	injected: Vec<&'static str>,
+}
+
+impl Faults {
+	/// Whether the task `name` being spawned is to be faulted.
+	fn take(&mut self, name: &'static str) -> bool {
+		match self.pending.iter().position(|target| target.map_or(true, |target| target == name)) {
+			Some(index) => {
+				self.pending.remove(index);
+				self.injected.push(name);
+				true
+			}
+			None => false,
+		}
+	}
+}
+
+/// An executor wrapper making selected tasks fail, to test how subsystems supervise their tasks.
+///
+/// A faulted task is dropped instead of being spawned, as if it had failed before doing any
+/// work. Anything it owns is dropped with it, so the subsystem observes the failure through
+/// whatever it holds on to, e.g. a closed channel or a cancelled oneshot. Clones share their
+/// faults.
+#[derive(Clone)]
+pub struct FaultInjectingExecutor<S> {
+	inner: S,
+	faults: Arc<Mutex<Faults>>,
+}
+
+impl<S> FaultInjectingExecutor<S> {
+	/// Wrap `inner`, spawning all tasks but the faulted ones on it.
+	pub fn new(inner: S) -> Self {
+		FaultInjectingExecutor {
+			inner,
+			faults: Default::default(),
+		}
+	}
+
+	/// Make the next task spawned with `task_name` fail.
+	pub fn inject_fault(&self, task_name: &'static str) {
+		self.faults.lock().pending.push(Some(task_name));
+	}
+
+	/// Make the next task spawned fail, whatever its name.
+	pub fn inject_fault_into_next(&self) {
+		self.faults.lock().pending.push(None);
+	}
+
+	/// The names of the tasks which have been made to fail so far, in order.
+	pub fn faulted(&self) -> Vec<&'static str> {
+		self.faults.lock().injected.clone()
+	}
+}
+
+impl<S: SpawnNamed> SpawnNamed for FaultInjectingExecutor<S> {
+	fn spawn_blocking(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		if !self.faults.lock().take(name) {
+			self.inner.spawn_blocking(name, future)
+		}
+	}
+
+	fn spawn(&self, name: &'static str, future: BoxFuture<'static, ()>) {
+		if !self.faults.lock().take(name) {
+			self.inner.spawn(name, future)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::channel::oneshot;
+	use futures::FutureExt;
+
+	#[test]
+	fn spawned_tasks_run_in_spawn_order() {
+		let executor = LocalPoolExecutor::new();
+		let order = Arc::new(Mutex::new(Vec::new()));
+
+		let receivers = (0..3).map(|i| {
+			let (tx, rx) = oneshot::channel();
+			let order = order.clone();
+			executor.spawn("task", async move {
+				order.lock().push(i);
+				let _ = tx.send(());
+			}.boxed());
+			rx
+		}).collect::<Vec<_>>();
+
+		executor.run_until(futures::future::join_all(receivers));
+		assert_eq!(*order.lock(), vec![0, 1, 2]);
+	}
+
+	fn execution_order(seed: u64) -> Vec<usize> {
+		let executor = LocalPoolExecutor::new().with_seed(seed);
+		let order = Arc::new(Mutex::new(Vec::new()));
+
+		let receivers = (0..8).map(|i| {
+			let (tx, rx) = oneshot::channel();
+			let order = order.clone();
+			executor.spawn("task", async move {
+				order.lock().push(i);
+				let _ = tx.send(());
+			}.boxed());
+			rx
+		}).collect::<Vec<_>>();
+
+		executor.run_until(futures::future::join_all(receivers));
+		let order = order.lock().clone();
+		order
+	}
+
+	#[test]
+	fn seeded_executors_shuffle_ready_tasks_reproducibly() {
+		let orders = (0..10).map(execution_order).collect::<Vec<_>>();
+		assert!(orders.iter().any(|order| *order != (0..8).collect::<Vec<_>>()));
+
+		for (seed, order) in orders.into_iter().enumerate() {
+			let mut sorted = order.clone();
+			sorted.sort();
+			assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+			assert_eq!(execution_order(seed as u64), order);
+		}
+	}
+
+	#[test]
+	fn supervisors_observe_faulted_tasks() {
+		let pool = LocalPoolExecutor::new();
+		let executor = FaultInjectingExecutor::new(pool.clone());
+		executor.inject_fault("worker");
+
+		let supervisor = async {
+			for attempt in 1.. {
+				let (tx, rx) = oneshot::channel();
+				executor.spawn("worker", async move {
+					let _ = tx.send(42);
+				}.boxed());
+
+				if let Ok(result) = rx.await {
+					return (attempt, result);
+				}
+			}
+			unreachable!()
+		};
+
+		assert_eq!(pool.run_until(supervisor), (2, 42));
+		assert_eq!(executor.faulted(), vec!["worker"]);
+	}
+
+	#[test]
+	fn tracking_executor_counts_spawns_per_leaf() {
+		use polkadot_node_subsystem::{
+			ActivatedLeaf, ActiveLeavesUpdate, FromOverseer, OverseerSignal, SubsystemContext, jaeger,
+		};
+		use polkadot_primitives::v1::Hash;
+
+		let pool = LocalPoolExecutor::new();
+		let executor = TrackingExecutor::new(pool.clone());
+		let (mut ctx, mut handle) = crate::make_subsystem_context::<(), _>(executor.clone());
+		let leaves = 3;
+
+		let overseer = async move {
+			for i in 0..leaves {
+				let leaf = ActivatedLeaf {
+					hash: Hash::repeat_byte(i),
+					number: i as _,
+					span: Arc::new(jaeger::Span::Disabled),
+				};
+				handle.send(FromOverseer::Signal(
+					OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)),
+				)).await;
+			}
+			handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+		};
+
+		let subsystem = async move {
+			loop {
+				match ctx.recv().await {
+					Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) => {
+						for _ in update.activated {
+							ctx.spawn("leaf-job", async {}.boxed()).await.unwrap();
+						}
+					}
+					_ => break,
+				}
+			}
+			ctx.spawn_blocking("cleanup", async {}.boxed()).await.unwrap();
+		};
+
+		pool.run_until(futures::future::join(overseer, subsystem));
+		assert_eq!(executor.total_spawned(), leaves as usize + 1);
+		assert_eq!(executor.blocking_spawned(), 1);
+		assert_eq!(executor.spawned_names().last(), Some(&"cleanup"));
+	}
+
+	#[test]
+	fn active_tasks_are_counted_until_completion() {
+		let pool = LocalPoolExecutor::new();
+		let executor = TrackingExecutor::new(pool.clone());
+		let (tx, rx) = oneshot::channel();
+		let (done_tx, done_rx) = oneshot::channel();
+
+		executor.spawn("waiting", async move {
+			let _ = rx.await;
+			let _ = done_tx.send(());
+		}.boxed());
+		executor.spawn("instant", async {}.boxed());
+		assert_eq!(executor.active_task_count(), 2);
+
+		pool.run_until(async {
+			let _ = tx.send(());
+			assert_eq!(executor.active_task_count(), 2);
+			let _ = done_rx.await;
+		});
+		assert_eq!(executor.active_task_count(), 0);
+	}
+
+	#[test]
+	fn prefixed_task_names_pass() {
+		let executor = TrackingExecutor::new(LocalPoolExecutor::new());
+		executor.spawn("availability-store-prune", async {}.boxed());
+		executor.spawn_blocking("availability-store-write", async {}.boxed());
+		executor.assert_all_named_with_prefix("availability-store");
+	}
+
+	#[test]
+	#[should_panic(expected = "1 spawned tasks are not prefixed with \"availability-store\": [\"prune\"]")]
+	fn unprefixed_task_names_are_reported() {
+		let executor = TrackingExecutor::new(LocalPoolExecutor::new());
+		executor.spawn("availability-store-write", async {}.boxed());
+		executor.spawn("prune", async {}.boxed());
+		executor.assert_all_named_with_prefix("availability-store");
+	}
+
+	#[test]
+	fn heavy_work_is_spawned_blocking() {
+		use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+
+		let pool = LocalPoolExecutor::new();
+		let executor = TrackingExecutor::new(pool.clone());
+		let (mut ctx, mut handle) = crate::make_subsystem_context::<u64, _>(executor.clone());
+
+		let overseer = async move {
+			handle.send(FromOverseer::Communication { msg: 1_000 }).await;
+			handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+		};
+
+		// Sums up to the number it receives off the async path, reporting the result from an
+		// async task.
+		let subsystem = async move {
+			while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+				let (tx, rx) = oneshot::channel();
+				ctx.spawn_blocking("sum", async move {
+					let _ = tx.send((1..=msg).sum::<u64>());
+				}.boxed()).await.unwrap();
+				ctx.spawn("report", async move {
+					assert_eq!(rx.await, Ok(500_500));
+				}.boxed()).await.unwrap();
+			}
+		};
+
+		pool.run_until(futures::future::join(overseer, subsystem));
+		assert_eq!(executor.blocking_spawned(), 1);
+		assert_eq!(executor.non_blocking_spawned(), 1);
+		assert_eq!(executor.spawned_names(), vec!["sum", "report"]);
+	}
+}
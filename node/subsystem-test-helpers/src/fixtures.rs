@@ -0,0 +1,212 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Ready-made messages, signals and primitives for subsystem tests.
+
+use polkadot_node_primitives::{BlockData, MAX_POV_SIZE, PoV};
+use std::sync::Arc;
+
+use polkadot_node_subsystem::{ActivatedLeaf, ActiveLeavesUpdate, FromOverseer, OverseerSignal, jaeger};
+use polkadot_primitives::v1::{BlockNumber, CandidateCommitments, CandidateReceipt, Hash, ValidatorId};
+use sp_core::sr25519;
+
+use crate::TestSubsystemContextHandle;
+
+/// Wrap `msg` for delivery to the subsystem, e.g. `handle.send(comm(msg))`.
+pub fn comm<M>(msg: M) -> FromOverseer<M> {
+	FromOverseer::Communication { msg }
+}
+
+/// Wrap `signal` for delivery to the subsystem, e.g. `handle.send(signal(OverseerSignal::Conclude))`.
+pub fn signal<M>(signal: OverseerSignal) -> FromOverseer<M> {
+	FromOverseer::Signal(signal)
+}
+
+/// A copy of `from_overseer`, for delivering the same input more than once.
+pub(crate) fn duplicate<M: Clone>(from_overseer: &FromOverseer<M>) -> FromOverseer<M> {
+	match from_overseer {
+		FromOverseer::Signal(signal) => FromOverseer::Signal(signal.clone()),
+		FromOverseer::Communication { msg } => FromOverseer::Communication { msg: msg.clone() },
+	}
+}
+
+/// The `n`-th test hash. Distinct seeds give distinct hashes.
+pub const fn hash(n: u8) -> Hash {
+	Hash::repeat_byte(n)
+}
+
+/// The `n`-th test validator. Distinct seeds give distinct validators.
+///
+/// The keys are not valid points, so they can't be used to check signatures.
+pub fn validator(n: u8) -> ValidatorId {
+	ValidatorId::from(sr25519::Public::from_raw([n; 32]))
+}
+
+/// The signal activating `leaves`, given by hash and height, in a single update.
+pub fn activated<M>(leaves: &[(Hash, BlockNumber)]) -> FromOverseer<M> {
+	let activated = leaves.iter()
+		.map(|&(hash, number)| ActivatedLeaf {
+			hash,
+			number,
+			span: Arc::new(jaeger::Span::Disabled),
+		})
+		.collect();
+
+	signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate { activated, ..Default::default() }))
+}
+
+/// The signal finalizing the block `hash` at height `number`.
+pub fn finalized<M>(hash: Hash, number: BlockNumber) -> FromOverseer<M> {
+	signal(OverseerSignal::BlockFinalized(hash, number))
+}
+
+/// The signals finalizing `blocks`, in order.
+pub fn finalization_sequence<M>(blocks: &[(Hash, BlockNumber)]) -> Vec<FromOverseer<M>> {
+	blocks.iter().map(|&(hash, number)| finalized(hash, number)).collect()
+}
+
+/// A candidate along with the data it commits to.
+#[derive(Debug, Clone)]
+pub struct CandidateFixture {
+	/// The receipt of the candidate.
+	pub receipt: CandidateReceipt,
+	/// The commitments of the candidate, matching the receipt unless made invalid.
+	pub commitments: CandidateCommitments,
+	/// The proof of validity of the candidate, matching the receipt unless made invalid.
+	pub pov: PoV,
+}
+
+/// The ways a [`CandidateFixture`] can be made invalid, see [`invalid_candidate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invalidity {
+	/// The receipt's PoV hash is not the hash of the PoV.
+	PovHashMismatch,
+	/// The receipt's commitments hash is not the hash of the commitments.
+	CommitmentsHashMismatch,
+	/// The PoV exceeds [`MAX_POV_SIZE`]. The receipt's PoV hash matches it nonetheless.
+	OversizedPov,
+}
+
+/// A candidate whose receipt is consistent with its commitments and PoV.
+pub fn valid_candidate() -> CandidateFixture {
+	candidate_with_pov(PoV { block_data: BlockData(vec![1, 2, 3]) })
+}
+
+/// A candidate which is valid but for the given `invalidity`.
+pub fn invalid_candidate(invalidity: Invalidity) -> CandidateFixture {
+	match invalidity {
+		Invalidity::PovHashMismatch => {
+			let mut candidate = valid_candidate();
+			candidate.receipt.descriptor.pov_hash = mismatching(candidate.pov.hash());
+			candidate
+		}
+		Invalidity::CommitmentsHashMismatch => {
+			let mut candidate = valid_candidate();
+			candidate.receipt.commitments_hash = mismatching(candidate.commitments.hash());
+			candidate
+		}
+		Invalidity::OversizedPov => candidate_with_pov(PoV {
+			block_data: BlockData(vec![0; MAX_POV_SIZE as usize + 1]),
+		}),
+	}
+}
+
+fn candidate_with_pov(pov: PoV) -> CandidateFixture {
+	let commitments = CandidateCommitments::default();
+
+	let mut receipt = CandidateReceipt::default();
+	receipt.descriptor.pov_hash = pov.hash();
+	receipt.commitments_hash = commitments.hash();
+
+	CandidateFixture { receipt, commitments, pov }
+}
+
+/// A hash guaranteed to differ from `hash`.
+fn mismatching(hash: Hash) -> Hash {
+	let mut bytes = hash.to_fixed_bytes();
+	bytes[0] ^= 0xff;
+	Hash::from(bytes)
+}
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Signal the subsystem that the block `hash` at height `number` has been finalized.
+	pub async fn finalize(&mut self, hash: Hash, number: BlockNumber) {
+		self.send(finalized(hash, number)).await
+	}
+
+	/// Send the subsystem a message about a candidate made invalid by `invalidity`.
+	///
+	/// `make_msg` wraps the candidate into the message the subsystem is expected to reject.
+	pub async fn send_invalid(
+		&mut self,
+		invalidity: Invalidity,
+		make_msg: impl FnOnce(CandidateFixture) -> M,
+	) {
+		self.send(comm(make_msg(invalid_candidate(invalidity)))).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn finalization_sequence_keeps_order_and_numbers() {
+		let blocks = [(Hash::repeat_byte(1), 10), (Hash::repeat_byte(2), 11)];
+		let signals = finalization_sequence::<()>(&blocks);
+
+		assert_eq!(signals.len(), blocks.len());
+		for (signal, (hash, number)) in signals.into_iter().zip(blocks.iter()) {
+			match signal {
+				FromOverseer::Signal(OverseerSignal::BlockFinalized(h, n)) => {
+					assert_eq!((h, n), (*hash, *number));
+				}
+				other => panic!("Unexpected {:?}", other),
+			}
+		}
+	}
+
+	#[test]
+	fn constructors_build_the_expected_variants() {
+		assert!(matches!(comm(5u32), FromOverseer::Communication { msg: 5 }));
+		assert!(matches!(signal::<()>(OverseerSignal::Conclude), FromOverseer::Signal(OverseerSignal::Conclude)));
+	}
+
+	#[test]
+	fn seeded_fixtures_are_distinct() {
+		use std::collections::HashSet;
+
+		assert_eq!((0..=u8::MAX).map(hash).collect::<HashSet<_>>().len(), 256);
+		assert_eq!((0..=u8::MAX).map(validator).collect::<HashSet<_>>().len(), 256);
+		assert_eq!(hash(1), hash(1));
+	}
+
+	fn is_consistent(candidate: &CandidateFixture) -> (bool, bool, bool) {
+		(
+			candidate.receipt.descriptor.pov_hash == candidate.pov.hash(),
+			candidate.receipt.commitments_hash == candidate.commitments.hash(),
+			candidate.pov.block_data.0.len() <= MAX_POV_SIZE as usize,
+		)
+	}
+
+	#[test]
+	fn each_invalidity_breaks_exactly_one_property() {
+		assert_eq!(is_consistent(&valid_candidate()), (true, true, true));
+		assert_eq!(is_consistent(&invalid_candidate(Invalidity::PovHashMismatch)), (false, true, true));
+		assert_eq!(is_consistent(&invalid_candidate(Invalidity::CommitmentsHashMismatch)), (true, false, true));
+		assert_eq!(is_consistent(&invalid_candidate(Invalidity::OversizedPov)), (true, true, false));
+	}
+}
@@ -0,0 +1,148 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Delivering a message in fragments, for subsystems reassembling streamed data.
+
+use std::time::Duration;
+
+#[cfg(any(test, feature = "structural-eq"))]
+use futures::prelude::*;
+
+use crate::TestSubsystemContextHandle;
+use crate::fixtures::comm;
+#[cfg(any(test, feature = "structural-eq"))]
+use crate::{TestSubsystemContext, capture::run_captured, executor::LocalPoolExecutor, fixtures::signal};
+#[cfg(any(test, feature = "structural-eq"))]
+use polkadot_node_subsystem::OverseerSignal;
+
+/// A message which can be split into fragments, for the subsystem to reassemble.
+pub trait Fragment: Sized {
+	/// Split the message into `count` fragments, in delivery order.
+	fn fragment(self, count: usize) -> Vec<Self>;
+}
+
+impl<M: Fragment> TestSubsystemContextHandle<M> {
+	/// Send `full_msg` split into `fragments` fragments, advancing the virtual clock by
+	/// `per_fragment_delay` between two fragments.
+	///
+	/// Each fragment is sent once the subsystem read the previous one.
+	pub async fn send_fragmented(&mut self, full_msg: M, fragments: usize, per_fragment_delay: Duration) {
+		for (i, fragment) in full_msg.fragment(fragments).into_iter().enumerate() {
+			if i > 0 {
+				self.clock.advance(per_fragment_delay);
+			}
+			self.send(comm(fragment)).await;
+		}
+	}
+}
+
+/// Run the subsystem started by `factory` twice, asserting it sends structurally equal
+/// messages whether `full_msg` is delivered at once or in fragments, see
+/// [`TestSubsystemContextHandle::send_fragmented`].
+///
+/// Both runs conclude after the delivery and wait for the subsystem to exit. Requests are
+/// captured but not answered, like in [`assert_equivalent`](crate::assert_equivalent). Panics
+/// with the first difference between the runs.
+#[cfg(any(test, feature = "structural-eq"))]
+pub fn assert_fragmentation_invariant<M, Factory, Subsystem>(
+	factory: Factory,
+	full_msg: M,
+	fragments: usize,
+	per_fragment_delay: Duration,
+)
+where
+	M: Fragment + Clone,
+	Factory: Fn(TestSubsystemContext<M, LocalPoolExecutor>) -> Subsystem,
+	Subsystem: Future<Output = ()>,
+{
+	let atomic_msg = full_msg.clone();
+	let atomic = run_captured(
+		move |mut handle: TestSubsystemContextHandle<M>| async move {
+			handle.send(comm(atomic_msg)).await;
+			handle.send(signal(OverseerSignal::Conclude)).await;
+			handle.await_exit().await;
+		},
+		&factory,
+	);
+	let fragmented = run_captured(
+		move |mut handle: TestSubsystemContextHandle<M>| async move {
+			handle.send_fragmented(full_msg, fragments, per_fragment_delay).await;
+			handle.send(signal(OverseerSignal::Conclude)).await;
+			handle.await_exit().await;
+		},
+		&factory,
+	);
+
+	if let Some(diff) = atomic.diff(&fragmented) {
+		panic!("The fragmented delivery diverged from the atomic one, {}", diff);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_subsystem::{FromOverseer, SubsystemContext};
+	use polkadot_node_subsystem::messages::ChainApiMessage;
+	use polkadot_primitives::v1::Hash;
+
+	/// A part of a byte stream, the last one ending it.
+	#[derive(Clone)]
+	struct Chunk {
+		data: Vec<u8>,
+		last: bool,
+	}
+
+	impl Fragment for Chunk {
+		fn fragment(self, count: usize) -> Vec<Self> {
+			let size = (self.data.len() + count - 1) / count;
+			let chunks = self.data.chunks(size).map(<[u8]>::to_vec).collect::<Vec<_>>();
+			let len = chunks.len();
+			chunks.into_iter()
+				.enumerate()
+				.map(|(i, data)| Chunk { data, last: self.last && i + 1 == len })
+				.collect()
+		}
+	}
+
+	/// Queries the block number of the hash summing up each stream, or each chunk if not
+	/// `reassemble`.
+	async fn summing(mut ctx: TestSubsystemContext<Chunk, LocalPoolExecutor>, reassemble: bool) {
+		let mut sum = 0u8;
+		while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+			sum = msg.data.iter().fold(sum, |sum, byte| sum.wrapping_add(*byte));
+			if msg.last || !reassemble {
+				let request = ChainApiMessage::BlockNumber(Hash::repeat_byte(sum), futures::channel::oneshot::channel().0);
+				ctx.send_message(request.into()).await;
+				sum = 0;
+			}
+		}
+	}
+
+	fn stream() -> Chunk {
+		Chunk { data: vec![1, 2, 3, 4, 5], last: true }
+	}
+
+	#[test]
+	fn reassembling_subsystems_pass() {
+		assert_fragmentation_invariant(|ctx| summing(ctx, true), stream(), 3, Duration::from_millis(100));
+	}
+
+	#[test]
+	#[should_panic(expected = "The fragmented delivery diverged from the atomic one, message #0 differs")]
+	fn fragment_dependent_subsystems_are_reported() {
+		assert_fragmentation_invariant(|ctx| summing(ctx, false), stream(), 3, Duration::from_millis(100));
+	}
+}
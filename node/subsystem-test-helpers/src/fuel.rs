@@ -0,0 +1,152 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Bounding the polls of a subsystem, a deterministic complement to the harness timeout.
+
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::prelude::*;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+
+use crate::{Operation, OutboundState, TestSubsystemContext, TestSubsystemContextHandle};
+
+/// The error a [`Fueled`] future resolves to once it exhausted its poll budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuelExhausted {
+	/// The number of polls the future was permitted.
+	pub budget: usize,
+	/// The last task spawned or message sent by the subsystem, if any.
+	pub last_activity: Option<Operation>,
+}
+
+impl fmt::Display for FuelExhausted {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "The subsystem exhausted its budget of {} polls, last activity: ", self.budget)?;
+		match &self.last_activity {
+			Some(operation) => write!(f, "{:?}", operation),
+			None => write!(f, "none"),
+		}
+	}
+}
+
+/// A future permitted a limited number of polls, set with
+/// [`TestSubsystemContextHandle::set_poll_budget`], after which it resolves to
+/// [`FuelExhausted`].
+///
+/// Created with [`TestSubsystemContext::fueled`]. Unlike the harness timeout, the budget doesn't
+/// depend on how fast the machine running the test is, so on a deterministic executor like the
+/// [`LocalPoolExecutor`](crate::LocalPoolExecutor) a subsystem reaching quiescence within the
+/// budget does so on every run. Without a budget set, polls are unlimited.
+#[pin_project]
+pub struct Fueled<F> {
+	#[pin]
+	inner: F,
+	outbound: Arc<Mutex<OutboundState>>,
+}
+
+impl<F: Future> Future for Fueled<F> {
+	type Output = Result<F::Output, FuelExhausted>;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		let this = self.project();
+
+		{
+			let mut outbound = this.outbound.lock();
+			if let Some(budget) = outbound.poll_budget {
+				if outbound.polls >= budget {
+					return Poll::Ready(Err(FuelExhausted {
+						budget,
+						last_activity: outbound.operations.last().cloned(),
+					}));
+				}
+				outbound.polls += 1;
+			}
+		}
+
+		this.inner.poll(cx).map(Ok)
+	}
+}
+
+impl<M, S> TestSubsystemContext<M, S> {
+	/// Run the subsystem started by `subsystem` on this context, limiting its polls with
+	/// [`TestSubsystemContextHandle::set_poll_budget`].
+	pub fn fueled<F: Future>(self, subsystem: impl FnOnce(Self) -> F) -> Fueled<F> {
+		let outbound = self.tx.outbound.clone();
+		Fueled {
+			inner: subsystem(self),
+			outbound,
+		}
+	}
+}
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Permit the [`Fueled`] subsystem future `budget` more polls, after which it resolves to
+	/// [`FuelExhausted`]. `None`, the default, lifts the limit.
+	pub fn set_poll_budget(&mut self, budget: Option<usize>) {
+		let mut outbound = self.outbound.lock();
+		outbound.poll_budget = budget;
+		outbound.polls = 0;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use crate::{fixtures::signal, subsystem_test_harness};
+
+	#[test]
+	fn quiescent_subsystems_stay_within_budget() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.set_poll_budget(Some(20));
+				handle.send_dummy().await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|ctx| async move {
+				let subsystem = ctx.fueled(|mut ctx| async move {
+					while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {}
+				});
+				assert_eq!(subsystem.await, Ok(()));
+			},
+		);
+	}
+
+	#[test]
+	fn busy_looping_subsystems_exhaust_their_budget() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.set_poll_budget(Some(10));
+			},
+			|ctx| async move {
+				let subsystem = ctx.fueled(|_ctx| future::poll_fn(|cx| {
+					cx.waker().wake_by_ref();
+					Poll::<()>::Pending
+				}));
+				let exhausted = subsystem.await.unwrap_err();
+				assert_eq!(exhausted, FuelExhausted { budget: 10, last_activity: None });
+				assert_eq!(
+					exhausted.to_string(),
+					"The subsystem exhausted its budget of 10 polls, last activity: none",
+				);
+			},
+		);
+	}
+}
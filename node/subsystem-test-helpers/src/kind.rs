@@ -0,0 +1,140 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! The destination subsystems of messages.
+
+use polkadot_node_subsystem::messages::AllMessages;
+
+/// The subsystem an [`AllMessages`] is addressed to, one per variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(missing_docs)]
+pub enum MessageKind {
+	CandidateValidation,
+	CandidateBacking,
+	CandidateSelection,
+	ChainApi,
+	CollatorProtocol,
+	StatementDistribution,
+	AvailabilityDistribution,
+	AvailabilityRecovery,
+	BitfieldDistribution,
+	BitfieldSigning,
+	Provisioner,
+	RuntimeApi,
+	AvailabilityStore,
+	NetworkBridge,
+	CollationGeneration,
+	ApprovalVoting,
+	ApprovalDistribution,
+	GossipSupport,
+}
+
+impl MessageKind {
+	/// All kinds, in the order of the [`AllMessages`] variants.
+	pub const ALL: [MessageKind; 18] = [
+		MessageKind::CandidateValidation,
+		MessageKind::CandidateBacking,
+		MessageKind::CandidateSelection,
+		MessageKind::ChainApi,
+		MessageKind::CollatorProtocol,
+		MessageKind::StatementDistribution,
+		MessageKind::AvailabilityDistribution,
+		MessageKind::AvailabilityRecovery,
+		MessageKind::BitfieldDistribution,
+		MessageKind::BitfieldSigning,
+		MessageKind::Provisioner,
+		MessageKind::RuntimeApi,
+		MessageKind::AvailabilityStore,
+		MessageKind::NetworkBridge,
+		MessageKind::CollationGeneration,
+		MessageKind::ApprovalVoting,
+		MessageKind::ApprovalDistribution,
+		MessageKind::GossipSupport,
+	];
+}
+
+/// Extension trait telling the destination of a message.
+pub trait MessageKindExt {
+	/// The subsystem the message is addressed to.
+	fn kind(&self) -> MessageKind;
+}
+
+impl MessageKindExt for AllMessages {
+	fn kind(&self) -> MessageKind {
+		match self {
+			AllMessages::CandidateValidation(_) => MessageKind::CandidateValidation,
+			AllMessages::CandidateBacking(_) => MessageKind::CandidateBacking,
+			AllMessages::CandidateSelection(_) => MessageKind::CandidateSelection,
+			AllMessages::ChainApi(_) => MessageKind::ChainApi,
+			AllMessages::CollatorProtocol(_) => MessageKind::CollatorProtocol,
+			AllMessages::StatementDistribution(_) => MessageKind::StatementDistribution,
+			AllMessages::AvailabilityDistribution(_) => MessageKind::AvailabilityDistribution,
+			AllMessages::AvailabilityRecovery(_) => MessageKind::AvailabilityRecovery,
+			AllMessages::BitfieldDistribution(_) => MessageKind::BitfieldDistribution,
+			AllMessages::BitfieldSigning(_) => MessageKind::BitfieldSigning,
+			AllMessages::Provisioner(_) => MessageKind::Provisioner,
+			AllMessages::RuntimeApi(_) => MessageKind::RuntimeApi,
+			AllMessages::AvailabilityStore(_) => MessageKind::AvailabilityStore,
+			AllMessages::NetworkBridge(_) => MessageKind::NetworkBridge,
+			AllMessages::CollationGeneration(_) => MessageKind::CollationGeneration,
+			AllMessages::ApprovalVoting(_) => MessageKind::ApprovalVoting,
+			AllMessages::ApprovalDistribution(_) => MessageKind::ApprovalDistribution,
+			AllMessages::GossipSupport(_) => MessageKind::GossipSupport,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// The kind after `kind`, in the order of the [`AllMessages`] variants.
+	///
+	/// Being exhaustive, this fails to compile once a kind is added, so it can't be left out of
+	/// the walk in `all_kinds_are_listed`.
+	fn next(kind: MessageKind) -> Option<MessageKind> {
+		match kind {
+			MessageKind::CandidateValidation => Some(MessageKind::CandidateBacking),
+			MessageKind::CandidateBacking => Some(MessageKind::CandidateSelection),
+			MessageKind::CandidateSelection => Some(MessageKind::ChainApi),
+			MessageKind::ChainApi => Some(MessageKind::CollatorProtocol),
+			MessageKind::CollatorProtocol => Some(MessageKind::StatementDistribution),
+			MessageKind::StatementDistribution => Some(MessageKind::AvailabilityDistribution),
+			MessageKind::AvailabilityDistribution => Some(MessageKind::AvailabilityRecovery),
+			MessageKind::AvailabilityRecovery => Some(MessageKind::BitfieldDistribution),
+			MessageKind::BitfieldDistribution => Some(MessageKind::BitfieldSigning),
+			MessageKind::BitfieldSigning => Some(MessageKind::Provisioner),
+			MessageKind::Provisioner => Some(MessageKind::RuntimeApi),
+			MessageKind::RuntimeApi => Some(MessageKind::AvailabilityStore),
+			MessageKind::AvailabilityStore => Some(MessageKind::NetworkBridge),
+			MessageKind::NetworkBridge => Some(MessageKind::CollationGeneration),
+			MessageKind::CollationGeneration => Some(MessageKind::ApprovalVoting),
+			MessageKind::ApprovalVoting => Some(MessageKind::ApprovalDistribution),
+			MessageKind::ApprovalDistribution => Some(MessageKind::GossipSupport),
+			MessageKind::GossipSupport => None,
+		}
+	}
+
+	#[test]
+	fn all_kinds_are_listed() {
+		let mut kinds = vec![MessageKind::CandidateValidation];
+		while let Some(kind) = next(*kinds.last().unwrap()) {
+			kinds.push(kind);
+		}
+
+		assert_eq!(MessageKind::ALL.to_vec(), kinds);
+	}
+}
@@ -0,0 +1,177 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Composable middleware for what a test sends to the subsystem.
+//!
+//! Layers are stacked with [`TestSubsystemContextHandle::layer`] and [`LayeredHandle::layer`].
+//! Each layer wraps the ones added before it, so a send passes through the most recently added
+//! layer first and through the first added layer last, right before reaching the subsystem. In
+//! `handle.layer(recording).layer(Delay(d)).layer(Lossy::new(p, seed))` a message is first subject to loss,
+//! then delayed, and only recorded if it is actually delivered.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use polkadot_node_subsystem::FromOverseer;
+
+use crate::TestSubsystemContextHandle;
+
+/// A wrapper around the sends of a [`TestSubsystemContextHandle`].
+#[async_trait]
+pub trait HandleLayer<M>: Send {
+	/// Process `from_overseer` on its way to the subsystem.
+	///
+	/// Returning `None` drops it, so neither the layers below nor the subsystem see it.
+	async fn process(&mut self, from_overseer: FromOverseer<M>) -> Option<FromOverseer<M>>;
+}
+
+/// A handle whose sends pass through a stack of [`HandleLayer`]s.
+pub struct LayeredHandle<M> {
+	inner: TestSubsystemContextHandle<M>,
+	/// Ordered from the innermost to the outermost layer.
+	layers: Vec<Box<dyn HandleLayer<M>>>,
+}
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Wrap the handle into `layer`, see the [module docs](crate::layer) for the ordering.
+	pub fn layer(self, layer: impl HandleLayer<M> + 'static) -> LayeredHandle<M> {
+		LayeredHandle { inner: self, layers: Vec::new() }.layer(layer)
+	}
+}
+
+impl<M> LayeredHandle<M> {
+	/// Wrap the stack into another `layer`, which sees every send before the existing ones.
+	pub fn layer(mut self, layer: impl HandleLayer<M> + 'static) -> Self {
+		self.layers.push(Box::new(layer));
+		self
+	}
+
+	/// Pass a message or signal through all layers and send it to the subsystem.
+	///
+	/// Returns whether it was delivered.
+	pub async fn send(&mut self, from_overseer: FromOverseer<M>) -> bool {
+		let mut from_overseer = from_overseer;
+		for layer in self.layers.iter_mut().rev() {
+			from_overseer = match layer.process(from_overseer).await {
+				Some(from_overseer) => from_overseer,
+				None => return false,
+			};
+		}
+
+		self.inner.send(from_overseer).await;
+		true
+	}
+
+	/// Access the wrapped handle, e.g. to receive messages from the subsystem.
+	pub fn inner(&mut self) -> &mut TestSubsystemContextHandle<M> {
+		&mut self.inner
+	}
+
+	/// Unwrap the handle, discarding the layers.
+	pub fn into_inner(self) -> TestSubsystemContextHandle<M> {
+		self.inner
+	}
+}
+
+/// A layer recording the `Debug` representation of every send passing through it.
+///
+/// Clones share the record, so keep one to inspect it after moving the other into the stack.
+#[derive(Clone, Default)]
+pub struct Recording(Arc<Mutex<Vec<String>>>);
+
+impl Recording {
+	/// The sends recorded so far, in order.
+	pub fn recorded(&self) -> Vec<String> {
+		self.0.lock().clone()
+	}
+}
+
+#[async_trait]
+impl<M: fmt::Debug + Send + 'static> HandleLayer<M> for Recording {
+	async fn process(&mut self, from_overseer: FromOverseer<M>) -> Option<FromOverseer<M>> {
+		self.0.lock().push(format!("{:?}", from_overseer));
+		Some(from_overseer)
+	}
+}
+
+/// A layer delaying every send by the given wall-clock duration.
+#[derive(Clone, Copy, Debug)]
+pub struct Delay(pub Duration);
+
+#[async_trait]
+impl<M: Send + 'static> HandleLayer<M> for Delay {
+	async fn process(&mut self, from_overseer: FromOverseer<M>) -> Option<FromOverseer<M>> {
+		futures_timer::Delay::new(self.0).await;
+		Some(from_overseer)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::{executor::block_on, future};
+	use polkadot_node_subsystem::{OverseerSignal, SubsystemContext};
+	use sp_core::testing::TaskExecutor;
+	use std::time::Instant;
+	use crate::{lossy::Lossy, make_subsystem_context};
+
+	/// Sends `0..3` through a handle built by `stack`, returning how many arrived.
+	fn layered_run(stack: impl FnOnce(TestSubsystemContextHandle<u32>) -> LayeredHandle<u32>) -> usize {
+		let (mut ctx, handle) = make_subsystem_context::<u32, _>(TaskExecutor::new());
+		let mut handle = stack(handle);
+
+		let overseer = async move {
+			for i in 0..3 {
+				handle.send(FromOverseer::Communication { msg: i }).await;
+			}
+			assert!(handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await);
+		};
+
+		let subsystem = async move {
+			let mut received = 0;
+			while let FromOverseer::Communication { .. } = ctx.recv().await.unwrap() {
+				received += 1;
+			}
+			received
+		};
+
+		block_on(future::join(overseer, subsystem)).1
+	}
+
+	#[test]
+	fn outer_layers_see_sends_first() {
+		let inner_record = Recording::default();
+		let received = layered_run(|handle| handle.layer(inner_record.clone()).layer(Lossy::new(1.0, 0)));
+		assert_eq!(received, 0);
+		let conclude = FromOverseer::<u32>::Signal(OverseerSignal::Conclude);
+		assert_eq!(inner_record.recorded(), vec![format!("{:?}", conclude)]);
+
+		let outer_record = Recording::default();
+		let received = layered_run(|handle| handle.layer(Lossy::new(1.0, 0)).layer(outer_record.clone()));
+		assert_eq!(received, 0);
+		assert_eq!(outer_record.recorded().len(), 4);
+	}
+
+	#[test]
+	fn delay_holds_back_each_send() {
+		let delay = Duration::from_millis(10);
+		let start = Instant::now();
+
+		assert_eq!(layered_run(|handle| handle.layer(Delay(delay)).layer(Recording::default())), 3);
+		assert!(start.elapsed() >= 4 * delay);
+	}
+}
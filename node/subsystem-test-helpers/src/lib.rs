@@ -24,20 +24,132 @@ use polkadot_node_subsystem::{
 	SpawnedSubsystem, OverseerSignal, SubsystemSender,
 };
 use polkadot_node_subsystem_util::TimeoutExt;
+use polkadot_primitives::v1::{BlockNumber, Hash};
 
-use futures::channel::mpsc;
+use futures::channel::{mpsc, oneshot};
+use futures::future::RemoteHandle;
 use futures::poll;
 use futures::prelude::*;
 use parking_lot::Mutex;
 use sp_core::{testing::TaskExecutor, traits::SpawnNamed};
 
+use tracing::instrument::WithSubscriber;
+
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::Infallible;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll, Waker};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+mod assertions;
+pub mod barrier;
+#[cfg(any(test, feature = "structural-eq"))]
+pub mod capture;
+pub mod clock;
+pub mod contract;
+mod dsl;
+#[cfg(feature = "json-dump")]
+mod dump;
+pub mod executor;
+pub mod fixtures;
+pub mod fragment;
+pub mod fuel;
+pub mod kind;
+pub mod layer;
+pub mod local;
+pub mod lossy;
+pub mod memdb;
+pub mod metrics;
+pub mod poll_counting;
+pub mod projection;
+#[cfg(feature = "property-testing")]
+pub mod property;
+pub mod script;
+mod stress;
+pub mod tracing_capture;
+pub mod wakeup;
+#[cfg(any(test, feature = "structural-eq"))]
+pub mod structural_eq;
+
+#[cfg(any(test, feature = "structural-eq"))]
+pub use capture::{
+	CaptureReport, Session, TimingPolicy, assert_batching_invariant, assert_equivalent,
+	replay_with_timing, run_captured, run_deterministic_check, run_recorded, run_timed,
+};
+pub use assertions::{DrainPolicy, assert_eventually, exit_timeout};
+pub use barrier::TestBarrier;
+pub use clock::VirtualClock;
+pub use contract::ContractOverseer;
+#[cfg(feature = "json-dump")]
+pub use dump::UPDATE_GOLDEN_ENV_VAR;
+pub use executor::{FaultInjectingExecutor, LocalPoolExecutor, TrackingExecutor};
+pub use fixtures::{comm, signal};
+#[cfg(any(test, feature = "structural-eq"))]
+pub use fragment::assert_fragmentation_invariant;
+pub use fragment::Fragment;
+pub use fuel::{FuelExhausted, Fueled};
+pub use kind::{MessageKind, MessageKindExt};
+pub use local::{LocalTestSubsystemContext, make_local_subsystem_context};
+pub use layer::{Delay, HandleLayer, LayeredHandle, Recording};
+pub use lossy::{Lossy, LossyHandle};
+pub use memdb::MemDb;
+pub use metrics::TestMetricsRegistry;
+pub use poll_counting::{PollCounter, PollCounting};
+pub use projection::Projection;
+#[cfg(feature = "property-testing")]
+pub use property::subsystem_property;
+pub use script::{Dialogue, OverseerStep, run_with_two_scripts, with_script};
+pub use stress::LEAF_INTERVAL;
+pub use tracing_capture::TracingCapture;
+pub use wakeup::{WakeupCounter, WakeupCounting};
+
+/// How long a test harness may run before it is considered hung, see [`harness_timeout`].
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The environment variable overriding the harness timeout, in whole seconds.
+///
+/// It only affects the timeout of the harness entry points as a whole. Explicit timeouts, like
+/// `.timeout()` calls within a test or the timeouts of individual helpers, take precedence
+/// within their scope and keep their durations.
+pub const TIMEOUT_ENV_VAR: &str = "POLKADOT_TEST_TIMEOUT_SECS";
+
+/// The largest timeout accepted from [`TIMEOUT_ENV_VAR`], one hour.
+pub const MAX_TIMEOUT_SECS: u64 = 3600;
+
+/// How long a test harness may run before it is considered hung.
+///
+/// Two seconds, unless overridden by [`TIMEOUT_ENV_VAR`], e.g. on slow CI machines. The
+/// variable is read whenever a harness starts. Panics if it is set to anything but a number of
+/// seconds within `1..=MAX_TIMEOUT_SECS`, so a typo doesn't silently fall back to the default.
+pub fn harness_timeout() -> Duration {
+	match std::env::var(TIMEOUT_ENV_VAR) {
+		Ok(value) => parse_timeout(&value),
+		Err(std::env::VarError::NotPresent) => DEFAULT_TIMEOUT,
+		Err(err) => panic!("{} is invalid: {}", TIMEOUT_ENV_VAR, err),
+	}
+}
+
+/// How long an assertion waits for the subsystem before panicking, half the
+/// [`harness_timeout`], so its report isn't preempted by the harness timing out.
+pub(crate) fn assertion_timeout() -> Duration {
+	harness_timeout() / 2
+}
+
+fn parse_timeout(value: &str) -> Duration {
+	match value.trim().parse::<u64>() {
+		Ok(secs) if (1..=MAX_TIMEOUT_SECS).contains(&secs) => Duration::from_secs(secs),
+		_ => panic!(
+			"{} must be a number of seconds within 1..={}, got {:?}",
+			TIMEOUT_ENV_VAR,
+			MAX_TIMEOUT_SECS,
+			value,
+		),
+	}
+}
 
-enum SinkState<T> {
+enum Slot<T> {
 	Empty {
 		read_waker: Option<Waker>,
 	},
@@ -48,6 +160,74 @@ enum SinkState<T> {
 	},
 }
 
+struct SinkState<T> {
+	slot: Slot<T>,
+	/// Items queued behind the slot without waiting, see [`SingleItemSink::enqueue`].
+	backlog: VecDeque<T>,
+	/// The sender waiting for the backlog to be read.
+	backlog_waker: Option<Waker>,
+	/// Whether the sink refuses new items, see [`TestSubsystemContextHandle::pause_input`].
+	paused: bool,
+	/// The sender waiting for the sink to be resumed.
+	resume_waker: Option<Waker>,
+	/// Whether the stream ends once the slot is empty, see [`SingleItemSink::finish`].
+	finished: bool,
+	/// Counts the wakeups of the reader, see [`TestSubsystemContextHandle::wakeup_count`].
+	wakeups: WakeupCounter,
+	#[cfg(feature = "debug-internals")]
+	transitions: VecDeque<SinkTransition>,
+}
+
+/// An operation on a single-item sink or its stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkOperation {
+	/// [`Sink::poll_ready`].
+	PollReady,
+	/// [`Sink::start_send`].
+	StartSend,
+	/// [`Sink::poll_flush`].
+	PollFlush,
+	/// [`Stream::poll_next`].
+	PollNext,
+}
+
+/// The maximum number of transitions kept by a single-item sink.
+#[cfg(feature = "debug-internals")]
+pub const TRANSITION_LOG_CAPACITY: usize = 256;
+
+/// An operation performed on a single-item sink, see [`SingleItemSink::transition_log`].
+#[cfg(feature = "debug-internals")]
+#[derive(Debug, Clone)]
+pub struct SinkTransition {
+	/// When the operation was performed.
+	pub at: std::time::Instant,
+	/// The operation performed.
+	pub operation: SinkOperation,
+	/// Whether the operation completed, as opposed to returning `Poll::Pending`.
+	pub ready: bool,
+	/// Whether the sink held an item after the operation.
+	pub holds_item: bool,
+}
+
+impl<T> SinkState<T> {
+	fn record(&mut self, _operation: SinkOperation, _ready: bool) {
+		#[cfg(feature = "debug-internals")]
+		{
+			if self.transitions.len() == TRANSITION_LOG_CAPACITY {
+				self.transitions.pop_front();
+			}
+
+			let holds_item = matches!(self.slot, Slot::Item { .. });
+			self.transitions.push_back(SinkTransition {
+				at: std::time::Instant::now(),
+				operation: _operation,
+				ready: _ready,
+				holds_item,
+			});
+		}
+	}
+}
+
 /// The sink half of a single-item sink that does not resolve until the item has been read.
 pub struct SingleItemSink<T>(Arc<Mutex<SinkState<T>>>);
 
@@ -58,6 +238,91 @@ impl<T> Clone for SingleItemSink<T> {
 	}
 }
 
+impl<T> SingleItemSink<T> {
+	/// Signal that no more items are coming.
+	///
+	/// The stream yields the items still in the sink, if any, and `None` afterwards. Dropping the
+	/// sink instead does not end the stream, which then pends forever, but dropping leaves
+	/// nothing to query: a finished sink still answers [`Self::len`] and [`Self::is_empty`].
+	///
+	/// Sending on a finished sink panics.
+	pub fn finish(&mut self) {
+		let mut state = self.0.lock();
+		state.finished = true;
+
+		if let Slot::Empty { ref mut read_waker } = state.slot {
+			if let Some(waker) = read_waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	/// Queue `item` behind the unread ones, without waiting for it to be read.
+	///
+	/// Sends through the [`Sink`] pend until the queued items have been read, which keeps the
+	/// items in order. Enqueueing on a finished sink panics.
+	pub fn enqueue(&mut self, item: T) {
+		let mut state = self.0.lock();
+		assert!(!state.finished, "enqueue called on a finished sink");
+
+		state.backlog.push_back(item);
+		if let Slot::Empty { ref mut read_waker } = state.slot {
+			if let Some(waker) = read_waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	/// Queue all of `items` behind the unread ones at once, so the reader sees either none or all
+	/// of them. See [`Self::enqueue`].
+	pub fn enqueue_all(&mut self, items: impl IntoIterator<Item = T>) {
+		let mut state = self.0.lock();
+		assert!(!state.finished, "enqueue_all called on a finished sink");
+
+		state.backlog.extend(items);
+		if let Slot::Empty { ref mut read_waker } = state.slot {
+			if let Some(waker) = read_waker.take() {
+				waker.wake();
+			}
+		}
+	}
+
+	/// The number of items sent or queued but not read yet.
+	pub fn len(&self) -> usize {
+		let state = self.0.lock();
+		let slot = match state.slot {
+			Slot::Empty { .. } => 0,
+			Slot::Item { .. } => 1,
+		};
+
+		slot + state.backlog.len()
+	}
+
+	/// Whether all items sent have been read.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// The most recent operations performed on the sink and its stream, oldest first.
+	///
+	/// At most [`TRANSITION_LOG_CAPACITY`] transitions are kept.
+	#[cfg(feature = "debug-internals")]
+	pub fn transition_log(&self) -> Vec<SinkTransition> {
+		self.0.lock().transitions.iter().cloned().collect()
+	}
+
+	fn set_paused(&self, paused: bool) {
+		let mut state = self.0.lock();
+		state.paused = paused;
+
+		if !paused {
+			if let Some(waker) = state.resume_waker.take() {
+				waker.wake();
+			}
+		}
+	}
+}
+
 /// The stream half of a single-item sink.
 pub struct SingleItemStream<T>(Arc<Mutex<SinkState<T>>>);
 
@@ -66,23 +331,37 @@ impl<T> Sink<T> for SingleItemSink<T> {
 
 	fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Infallible>> {
 		let mut state = self.0.lock();
-		match *state {
-			SinkState::Empty { .. } => Poll::Ready(Ok(())),
-			SinkState::Item {
+		if state.paused {
+			state.resume_waker = Some(cx.waker().clone());
+			state.record(SinkOperation::PollReady, false);
+			return Poll::Pending;
+		}
+
+		let poll = match state.slot {
+			Slot::Empty { .. } if !state.backlog.is_empty() => {
+				state.backlog_waker = Some(cx.waker().clone());
+				Poll::Pending
+			}
+			Slot::Empty { .. } => Poll::Ready(Ok(())),
+			Slot::Item {
 				ref mut ready_waker,
 				..
 			} => {
 				*ready_waker = Some(cx.waker().clone());
 				Poll::Pending
 			}
-		}
+		};
+
+		state.record(SinkOperation::PollReady, poll.is_ready());
+		poll
 	}
 
 	fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Infallible> {
 		let mut state = self.0.lock();
+		assert!(!state.finished, "start_send called on a finished sink");
 
-		match *state {
-			SinkState::Empty { ref mut read_waker } => {
+		match state.slot {
+			Slot::Empty { ref mut read_waker } => {
 				if let Some(waker) = read_waker.take() {
 					waker.wake();
 				}
@@ -90,27 +369,31 @@ impl<T> Sink<T> for SingleItemSink<T> {
 			_ => panic!("start_send called outside of empty sink state ensured by poll_ready"),
 		}
 
-		*state = SinkState::Item {
+		state.slot = Slot::Item {
 			item,
 			ready_waker: None,
 			flush_waker: None,
 		};
+		state.record(SinkOperation::StartSend, true);
 
 		Ok(())
 	}
 
 	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Infallible>> {
 		let mut state = self.0.lock();
-		match *state {
-			SinkState::Empty { .. } => Poll::Ready(Ok(())),
-			SinkState::Item {
+		let poll = match state.slot {
+			Slot::Empty { .. } => Poll::Ready(Ok(())),
+			Slot::Item {
 				ref mut flush_waker,
 				..
 			} => {
 				*flush_waker = Some(cx.waker().clone());
 				Poll::Pending
 			}
-		}
+		};
+
+		state.record(SinkOperation::PollFlush, poll.is_ready());
+		poll
 	}
 
 	fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Infallible>> {
@@ -124,11 +407,23 @@ impl<T> Stream for SingleItemStream<T> {
 	fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
 		let mut state = self.0.lock();
 
-		let read_waker = Some(cx.waker().clone());
+		let read_waker = Some(state.wakeups.wrap(cx.waker().clone()));
+
+		let poll = match std::mem::replace(&mut state.slot, Slot::Empty { read_waker }) {
+			Slot::Empty { .. } => match state.backlog.pop_front() {
+				Some(item) => {
+					if state.backlog.is_empty() {
+						if let Some(waker) = state.backlog_waker.take() {
+							waker.wake();
+						}
+					}
 
-		match std::mem::replace(&mut *state, SinkState::Empty { read_waker }) {
-			SinkState::Empty { .. } => Poll::Pending,
-			SinkState::Item {
+					Poll::Ready(Some(item))
+				}
+				None if state.finished => Poll::Ready(None),
+				None => Poll::Pending,
+			},
+			Slot::Item {
 				item,
 				ready_waker,
 				flush_waker,
@@ -143,7 +438,10 @@ impl<T> Stream for SingleItemStream<T> {
 
 				Poll::Ready(Some(item))
 			}
-		}
+		};
+
+		state.record(SinkOperation::PollNext, poll.is_ready());
+		poll
 	}
 }
 
@@ -152,21 +450,129 @@ impl<T> Stream for SingleItemStream<T> {
 /// The sink's send methods resolve at the point which the stream reads the item,
 /// not when the item is buffered.
 pub fn single_item_sink<T>() -> (SingleItemSink<T>, SingleItemStream<T>) {
-	let inner = Arc::new(Mutex::new(SinkState::Empty { read_waker: None }));
+	let inner = Arc::new(Mutex::new(SinkState {
+		slot: Slot::Empty { read_waker: None },
+		backlog: VecDeque::new(),
+		backlog_waker: None,
+		paused: false,
+		resume_waker: None,
+		finished: false,
+		wakeups: WakeupCounter::default(),
+		#[cfg(feature = "debug-internals")]
+		transitions: VecDeque::with_capacity(TRANSITION_LOG_CAPACITY),
+	}));
 	(SingleItemSink(inner.clone()), SingleItemStream(inner))
 }
 
+/// An operation performed by the subsystem through its context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+	/// A task was spawned, blocking or not, under the given name.
+	Spawn(&'static str),
+	/// A message was sent, summarized as a string.
+	Send(String),
+}
+
+/// Bookkeeping of the messages sent by the subsystem, shared by all clones of its sender and
+/// the handle.
+#[derive(Default)]
+struct OutboundState {
+	/// Projections of the messages sent since capturing was enabled.
+	#[cfg(any(test, feature = "structural-eq"))]
+	capture: Option<Vec<structural_eq::MessageProjection>>,
+	/// All spawns and sends, in the order they happened.
+	operations: Vec<Operation>,
+	/// The number of messages sent but not taken by the handle yet.
+	depth: usize,
+	/// The highest `depth` seen so far.
+	peak_depth: usize,
+	/// Per-kind queues messages are routed to instead of the handle's `rx`, if demultiplexing.
+	demux: Option<HashMap<MessageKind, mpsc::UnboundedSender<AllMessages>>>,
+	/// The `depth` at which bounded sends block, see
+	/// [`TestSubsystemContextHandle::set_outbound_capacity`].
+	capacity: Option<usize>,
+	/// The senders waiting for the handle to take messages.
+	blocked_senders: Vec<Waker>,
+	/// Whether the handle takes no messages, see [`TestSubsystemContextHandle::stop_reading`].
+	reading_stopped: bool,
+	/// The handle waiting for reading to be resumed.
+	reader_waker: Option<Waker>,
+	/// The virtual clock of the test, to timestamp sends.
+	clock: VirtualClock,
+	/// The virtual time of the last send, see [`TestSubsystemContextHandle::idle_duration`].
+	last_send: Duration,
+	/// The polls permitted to a [`fuel::Fueled`] subsystem, see
+	/// [`TestSubsystemContextHandle::set_poll_budget`].
+	poll_budget: Option<usize>,
+	/// The polls of the subsystem since the budget was set.
+	polls: usize,
+}
+
+impl OutboundState {
+	/// Account for `msg` being sent, returning it unless it has been routed to a per-kind queue.
+	fn on_send(&mut self, msg: AllMessages) -> Option<AllMessages> {
+		#[cfg(any(test, feature = "structural-eq"))]
+		let summary = structural_eq::project(&msg);
+		#[cfg(not(any(test, feature = "structural-eq")))]
+		let summary = format!("{:?}", msg);
+
+		self.operations.push(Operation::Send(summary.to_string()));
+		self.last_send = self.clock.now();
+
+		#[cfg(any(test, feature = "structural-eq"))]
+		if let Some(capture) = self.capture.as_mut() {
+			capture.push(summary);
+		}
+
+		if let Some(queues) = &self.demux {
+			let _ = queues[&msg.kind()].unbounded_send(msg);
+			return None;
+		}
+
+		self.depth += 1;
+		self.peak_depth = std::cmp::max(self.peak_depth, self.depth);
+		Some(msg)
+	}
+
+	fn on_spawn(&mut self, name: &'static str) {
+		self.operations.push(Operation::Spawn(name));
+	}
+
+	fn on_receive(&mut self) {
+		self.depth = self.depth.saturating_sub(1);
+		self.wake_blocked_senders();
+	}
+
+	/// Account for `msg` being sent through the bounded path, waiting for room if the outbound
+	/// capacity is exhausted.
+	fn poll_send(&mut self, cx: &mut Context, msg: &mut Option<AllMessages>) -> Poll<Option<AllMessages>> {
+		if self.capacity.map_or(false, |capacity| self.depth >= capacity) {
+			self.blocked_senders.push(cx.waker().clone());
+			return Poll::Pending;
+		}
+
+		Poll::Ready(self.on_send(msg.take().expect("polled after completion")))
+	}
+
+	fn wake_blocked_senders(&mut self) {
+		for waker in self.blocked_senders.drain(..) {
+			waker.wake();
+		}
+	}
+}
+
 /// A test subsystem sender.
 #[derive(Clone)]
 pub struct TestSubsystemSender {
 	tx: mpsc::UnboundedSender<AllMessages>,
+	outbound: Arc<Mutex<OutboundState>>,
 }
 
 /// Construct a sender/receiver pair.
 pub fn sender_receiver() -> (TestSubsystemSender, mpsc::UnboundedReceiver<AllMessages>) {
 	let (tx, rx) = mpsc::unbounded();
 	(
-		TestSubsystemSender { tx },
+		TestSubsystemSender { tx, outbound: Default::default() },
 		rx,
 	)
 }
@@ -174,10 +580,15 @@ pub fn sender_receiver() -> (TestSubsystemSender, mpsc::UnboundedReceiver<AllMes
 #[async_trait::async_trait]
 impl SubsystemSender for TestSubsystemSender {
 	async fn send_message(&mut self, msg: AllMessages) {
-		self.tx
-			.send(msg)
-			.await
-			.expect("test overseer no longer live");
+		let outbound = &self.outbound;
+		let mut msg = Some(msg);
+		let msg = future::poll_fn(|cx| outbound.lock().poll_send(cx, &mut msg)).await;
+		if let Some(msg) = msg {
+			self.tx
+				.send(msg)
+				.await
+				.expect("test overseer no longer live");
+		}
 	}
 
 	async fn send_messages<T>(&mut self, msgs: T)
@@ -185,15 +596,76 @@ impl SubsystemSender for TestSubsystemSender {
 		T: IntoIterator<Item = AllMessages> + Send,
 		T::IntoIter: Send,
 	{
-		let mut iter = stream::iter(msgs.into_iter().map(Ok));
-		self.tx
-			.send_all(&mut iter)
-			.await
-			.expect("test overseer no longer live");
+		for msg in msgs {
+			self.send_message(msg).await;
+		}
 	}
 
 	fn send_unbounded_message(&mut self, msg: AllMessages) {
-		self.tx.unbounded_send(msg).expect("test overseer no longer live");
+		let msg = self.outbound.lock().on_send(msg);
+		if let Some(msg) = msg {
+			self.tx.unbounded_send(msg).expect("test overseer no longer live");
+		}
+	}
+}
+
+/// A task spawned by the subsystem under test.
+///
+/// Dropping the remote handle cancels the task.
+struct SpawnedTask {
+	name: &'static str,
+	handle: RemoteHandle<()>,
+	/// Set once the task has run to completion.
+	finished: Arc<AtomicBool>,
+}
+
+/// The tasks spawned through a [`TestSubsystemContext`], shared with its handle.
+#[derive(Clone, Default)]
+struct SpawnedTasks(Arc<Mutex<Vec<SpawnedTask>>>);
+
+impl SpawnedTasks {
+	/// Wrap `s` so that it can be cancelled later on and register it under `name`.
+	fn track(
+		&self,
+		name: &'static str,
+		s: Pin<Box<dyn Future<Output = ()> + Send>>,
+	) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+		Box::pin(self.register(name, s))
+	}
+
+	/// Like [`Self::track`], for futures confined to the current thread.
+	fn track_local(
+		&self,
+		name: &'static str,
+		s: Pin<Box<dyn Future<Output = ()>>>,
+	) -> Pin<Box<dyn Future<Output = ()>>> {
+		Box::pin(self.register(name, s))
+	}
+
+	fn register<F: Future<Output = ()>>(&self, name: &'static str, s: F) -> impl Future<Output = ()> {
+		let finished = Arc::new(AtomicBool::new(false));
+		let flag = finished.clone();
+		let (remote, handle) = async move {
+			s.await;
+			flag.store(true, Ordering::SeqCst);
+		}.remote_handle();
+
+		self.0.lock().push(SpawnedTask { name, handle, finished });
+		remote
+	}
+
+	/// Cancel all tracked tasks, returning their names.
+	fn abort_all(&self) -> Vec<&'static str> {
+		self.0.lock().drain(..).map(|task| task.name).collect()
+	}
+
+	/// The names of the tracked tasks which haven't completed yet.
+	fn running(&self) -> Vec<&'static str> {
+		self.0.lock()
+			.iter()
+			.filter(|task| !task.finished.load(Ordering::SeqCst))
+			.map(|task| task.name)
+			.collect()
 	}
 }
 
@@ -202,6 +674,80 @@ pub struct TestSubsystemContext<M, S> {
 	tx: TestSubsystemSender,
 	rx: SingleItemStream<FromOverseer<M>>,
 	spawn: S,
+	spawned: SpawnedTasks,
+	/// The dispatcher spawned tasks report their `tracing` events to, if not the global one.
+	dispatch: Option<tracing::Dispatch>,
+	clock: VirtualClock,
+	/// An input taken from `rx` by a filtered receive which didn't return it.
+	peeked: Option<FromOverseer<M>>,
+}
+
+impl<M, S> TestSubsystemContext<M, S> {
+	/// The virtual clock of the test, shared with the handle.
+	pub fn clock(&self) -> &VirtualClock {
+		&self.clock
+	}
+
+	/// The tasks spawned and messages sent by the subsystem so far, in order.
+	pub fn operation_log(&self) -> Vec<Operation> {
+		self.tx.outbound.lock().operations.clone()
+	}
+
+	/// Like [`SubsystemContext::try_recv`], but only taking the next pending input if it is a
+	/// signal.
+	///
+	/// Returns `Ok(None)` if nothing is pending or if the next input is a message. That message
+	/// is not consumed: it stays pending for the next receive. Note that the handle considers it
+	/// read nonetheless, so a send of the handle waiting for it resolves.
+	pub async fn try_recv_signal(&mut self) -> Result<Option<OverseerSignal>, ()> {
+		match self.peek().await? {
+			Some(FromOverseer::Signal(signal)) => Ok(Some(signal)),
+			other => {
+				self.peeked = other;
+				Ok(None)
+			}
+		}
+	}
+
+	/// Like [`Self::try_recv_signal`], but only taking the next pending input if it is a message.
+	///
+	/// A pending signal is not consumed, it stays pending for the next receive.
+	pub async fn try_recv_communication(&mut self) -> Result<Option<M>, ()> {
+		match self.peek().await? {
+			Some(FromOverseer::Communication { msg }) => Ok(Some(msg)),
+			other => {
+				self.peeked = other;
+				Ok(None)
+			}
+		}
+	}
+
+	/// Take the next pending input without waiting, whether peeked before or not.
+	async fn peek(&mut self) -> Result<Option<FromOverseer<M>>, ()> {
+		if let Some(peeked) = self.peeked.take() {
+			return Ok(Some(peeked));
+		}
+
+		match poll!(self.rx.next()) {
+			Poll::Ready(Some(msg)) => Ok(Some(msg)),
+			Poll::Ready(None) => Err(()),
+			Poll::Pending => Ok(None),
+		}
+	}
+
+	fn prepare_task(
+		&self,
+		name: &'static str,
+		s: Pin<Box<dyn Future<Output = ()> + Send>>,
+	) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+		self.tx.outbound.lock().on_spawn(name);
+
+		let s = self.spawned.track(name, s);
+		match &self.dispatch {
+			Some(dispatch) => Box::pin(s.with_subscriber(dispatch.clone())),
+			None => s,
+		}
+	}
 }
 
 #[async_trait::async_trait]
@@ -212,14 +758,14 @@ impl<M: Send + 'static, S: SpawnNamed + Send + 'static> SubsystemContext
 	type Sender = TestSubsystemSender;
 
 	async fn try_recv(&mut self) -> Result<Option<FromOverseer<M>>, ()> {
-		match poll!(self.rx.next()) {
-			Poll::Ready(Some(msg)) => Ok(Some(msg)),
-			Poll::Ready(None) => Err(()),
-			Poll::Pending => Ok(None),
-		}
+		self.peek().await
 	}
 
 	async fn recv(&mut self) -> SubsystemResult<FromOverseer<M>> {
+		if let Some(peeked) = self.peeked.take() {
+			return Ok(peeked);
+		}
+
 		self.rx.next().await
 			.ok_or_else(|| SubsystemError::Context("Receiving end closed".to_owned()))
 	}
@@ -229,14 +775,14 @@ impl<M: Send + 'static, S: SpawnNamed + Send + 'static> SubsystemContext
 		name: &'static str,
 		s: Pin<Box<dyn Future<Output = ()> + Send>>,
 	) -> SubsystemResult<()> {
-		self.spawn.spawn(name, s);
+		self.spawn.spawn(name, self.prepare_task(name, s));
 		Ok(())
 	}
 
 	async fn spawn_blocking(&mut self, name: &'static str, s: Pin<Box<dyn Future<Output = ()> + Send>>)
 		-> SubsystemResult<()>
 	{
-		self.spawn.spawn_blocking(name, s);
+		self.spawn.spawn_blocking(name, self.prepare_task(name, s));
 		Ok(())
 	}
 
@@ -255,18 +801,47 @@ pub struct TestSubsystemContextHandle<M> {
 
 	/// Direct access to the receiver.
 	pub rx: mpsc::UnboundedReceiver<AllMessages>,
+
+	/// Messages taken from `rx` but not handed out yet.
+	buffered: VecDeque<AllMessages>,
+	/// Per-kind queues, see [`Self::demultiplex`].
+	queues: HashMap<MessageKind, mpsc::UnboundedReceiver<AllMessages>>,
+	outbound: Arc<Mutex<OutboundState>>,
+	spawned: SpawnedTasks,
+	clock: VirtualClock,
+	/// The leaves activated and not deactivated by the signals sent so far.
+	active_leaves: HashSet<Hash>,
+	/// Called with every input sent to the subsystem, to record a session.
+	recorder: Option<Box<dyn FnMut(&FromOverseer<M>) + Send + Sync>>,
 }
 
 impl<M> TestSubsystemContextHandle<M> {
 	/// Send a message or signal to the subsystem. This resolves at the point in time where the
 	/// subsystem has _read_ the message.
 	pub async fn send(&mut self, from_overseer: FromOverseer<M>) {
+		self.observe(&from_overseer);
 		self.tx
 			.send(from_overseer)
 			.await
 			.expect("Test subsystem no longer live");
 	}
 
+	/// Keep track of the state the signals sent to the subsystem establish.
+	fn observe(&mut self, from_overseer: &FromOverseer<M>) {
+		if let FromOverseer::Signal(OverseerSignal::ActiveLeaves(update)) = from_overseer {
+			for leaf in &update.activated {
+				self.active_leaves.insert(leaf.hash);
+			}
+			for hash in &update.deactivated {
+				self.active_leaves.remove(hash);
+			}
+		}
+
+		if let Some(record) = self.recorder.as_mut() {
+			record(from_overseer);
+		}
+	}
+
 	/// Receive the next message from the subsystem.
 	pub async fn recv(&mut self) -> AllMessages {
 		self.try_recv().await.expect("Test subsystem no longer live")
@@ -274,95 +849,696 @@ impl<M> TestSubsystemContextHandle<M> {
 
 	/// Receive the next message from the subsystem, or `None` if the channel has been closed.
 	pub async fn try_recv(&mut self) -> Option<AllMessages> {
-		self.rx.next().await
+		self.messages().next().await
 	}
-}
-
-/// Make a test subsystem context.
-pub fn make_subsystem_context<M, S>(
-	spawn: S,
-) -> (TestSubsystemContext<M, S>, TestSubsystemContextHandle<M>) {
-	let (overseer_tx, overseer_rx) = single_item_sink();
-	let (all_messages_tx, all_messages_rx) = mpsc::unbounded();
 
-	(
-		TestSubsystemContext {
-			tx: TestSubsystemSender { tx: all_messages_tx },
-			rx: overseer_rx,
-			spawn,
-		},
-		TestSubsystemContextHandle {
-			tx: overseer_tx,
-			rx: all_messages_rx,
-		},
-	)
-}
+	/// The messages from the subsystem as a stream, ending once the subsystem dropped all of its
+	/// senders.
+	///
+	/// ```ignore
+	/// let first = handle.messages().take(3).collect::<Vec<_>>().await;
+	/// ```
+	pub fn messages(&mut self) -> Messages<'_, M> {
+		Messages(self)
+	}
 
-/// Test a subsystem, mocking the overseer
-///
-/// Pass in two async closures: one mocks the overseer, the other runs the test from the perspective of a subsystem.
-///
-/// Times out in two seconds.
-pub fn subsystem_test_harness<M, OverseerFactory, Overseer, TestFactory, Test>(
-	overseer_factory: OverseerFactory,
-	test_factory: TestFactory,
-) where
-	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
-	Overseer: Future<Output = ()>,
-	TestFactory: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> Test,
-	Test: Future<Output = ()>,
-{
-	let pool = TaskExecutor::new();
-	let (context, handle) = make_subsystem_context(pool);
-	let overseer = overseer_factory(handle);
-	let test = test_factory(context);
+	/// Receive the next message and take its response channel, to reply at a later point.
+	///
+	/// `extract` splits the message into its responder and the rest of the message, e.g. the
+	/// message with its responder replaced by a [`placeholder_responder`]:
+	///
+	/// ```ignore
+	/// let (tx, msg) = handle.take_responder(|msg| match msg {
+	/// 	AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) =>
+	/// 		(tx, ChainApiMessage::BlockNumber(hash, placeholder_responder()).into()),
+	/// 	msg => panic!("{:?}", msg),
+	/// });
+	/// ```
+	///
+	/// If `extract` panics, e.g. because the message is of another variant, the panic names the
+	/// received message.
+	pub async fn take_responder<T>(
+		&mut self,
+		extract: impl FnOnce(AllMessages) -> (oneshot::Sender<T>, AllMessages),
+	) -> (oneshot::Sender<T>, AllMessages) {
+		let msg = self.recv().await;
+		let description = format!("{:?}", msg);
 
-	futures::pin_mut!(overseer, test);
+		match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| extract(msg))) {
+			Ok(taken) => taken,
+			Err(_) => panic!("Failed to take the responder of {}", description),
+		}
+	}
 
-	futures::executor::block_on(async move {
-		future::join(overseer, test)
-			.timeout(Duration::from_secs(2))
-			.await
-			.expect("test timed out instead of completing")
-	});
-}
+	/// Take all messages the subsystem has sent so far, without waiting for more.
+	pub fn drain(&mut self) -> Vec<AllMessages> {
+		self.buffer_ready();
+		self.buffered.drain(..).collect()
+	}
 
-/// A forward subsystem that implements [`Subsystem`].
-///
-/// It forwards all communication from the overseer to the internal message
-/// channel.
-///
-/// This subsystem is useful for testing functionality that interacts with the overseer.
-pub struct ForwardSubsystem<Msg>(pub mpsc::Sender<Msg>);
+	/// Wait until the subsystem has sent at least `n` messages, then take all messages it sent.
+	///
+	/// Messages arriving in the same burst as the `n`-th one are taken as well, so more than `n`
+	/// may be returned. Useful to wait for a subsystem to reach a steady state. Panics with the
+	/// number of messages received if fewer than `n` arrive within `timeout`.
+	pub async fn wait_for_count(&mut self, n: usize, timeout: Duration) -> Vec<AllMessages> {
+		let mut received = self.drain();
+		let mut hung_up = false;
 
-impl<C: SubsystemContext<Message = Msg>, Msg: Send + 'static> Subsystem<C> for ForwardSubsystem<Msg> {
-	fn start(mut self, mut ctx: C) -> SpawnedSubsystem {
-		let future = Box::pin(async move {
-			loop {
-				match ctx.recv().await {
-					Ok(FromOverseer::Signal(OverseerSignal::Conclude)) => return Ok(()),
-					Ok(FromOverseer::Communication { msg }) => {
-						let _ = self.0.send(msg).await;
-					},
-					Err(_) => return Ok(()),
-					_ => (),
+		let wait = async {
+			while received.len() < n {
+				match self.try_recv().await {
+					Some(msg) => {
+						received.push(msg);
+						received.extend(self.drain());
+					}
+					None => {
+						hung_up = true;
+						break;
+					}
 				}
 			}
-		});
+		};
+		let _ = wait.timeout(timeout).await;
 
-		SpawnedSubsystem {
-			name: "forward-subsystem",
-			future,
-		}
+		assert!(
+			received.len() >= n,
+			"Received {} of {} messages {}",
+			received.len(),
+			n,
+			if hung_up { "before the subsystem hung up".to_owned() } else { format!("within {:?}", timeout) },
+		);
+		received
 	}
-}
+
+	/// Take the messages the subsystem has sent so far which are bound to `relay_parent`.
+	///
+	/// All other messages are kept in order, to be handed out by subsequent receives.
+	pub fn messages_for_relay_parent(&mut self, relay_parent: Hash) -> Vec<AllMessages> {
+		self.messages_for_relay_parent_with(relay_parent, projection::relay_parent)
+	}
+
+	/// Like [`Self::messages_for_relay_parent`], locating the relay-parent with `projection`.
+	pub fn messages_for_relay_parent_with(
+		&mut self,
+		relay_parent: Hash,
+		projection: impl Projection<Hash>,
+	) -> Vec<AllMessages> {
+		self.buffer_ready();
+
+		let (matching, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut self.buffered)
+			.into_iter()
+			.partition(|msg| projection.project(msg) == Some(relay_parent));
+
+		self.buffered = rest.into();
+		matching
+	}
+
+	/// Activate `leaves`, given by hash and height, in a single update and wait for the
+	/// subsystem to settle, returning the messages it sent meanwhile.
+	///
+	/// This implies the subsystem is running already, as the update is only sent once it reads
+	/// it. To have the leaves activated on startup instead, use
+	/// [`make_subsystem_context_with_leaves`].
+	pub async fn activate_leaves(&mut self, leaves: Vec<(Hash, BlockNumber)>) -> Vec<AllMessages> {
+		self.send(fixtures::activated(&leaves)).await;
+		self.drain_settled("after activating the leaves").await
+	}
+
+	/// Wait until the subsystem hasn't sent anything for `quiet`, then take all messages it sent.
+	///
+	/// Gives up waiting after [`assertion_timeout`], handing back what was sent until then as
+	/// [`NotQuiet`](assertions::NotQuiet), so a subsystem that never falls silent is reported
+	/// instead of timing out the harness.
+	pub(crate) async fn drain_when_quiet(
+		&mut self,
+		quiet: Duration,
+	) -> Result<Vec<AllMessages>, assertions::NotQuiet> {
+		self.drain_when_quiet_by(quiet, Instant::now() + assertion_timeout()).await
+	}
+
+	/// Wait for the subsystem to fall silent for [`assertions::QUIET_PERIOD`], then take all
+	/// messages it sent. Panics describing what it didn't settle `after` if it never does.
+	pub(crate) async fn drain_settled(&mut self, after: &str) -> Vec<AllMessages> {
+		self.drain_when_quiet(assertions::QUIET_PERIOD)
+			.await
+			.unwrap_or_else(|not_quiet| not_quiet.report(after))
+	}
+
+	/// Like [`Self::drain_when_quiet`], giving up at `deadline`, to bound several waits at once.
+	pub(crate) async fn drain_when_quiet_by(
+		&mut self,
+		quiet: Duration,
+		deadline: Instant,
+	) -> Result<Vec<AllMessages>, assertions::NotQuiet> {
+		if self.outbound.lock().reading_stopped {
+			futures_timer::Delay::new(quiet).await;
+			return Ok(self.drain());
+		}
+
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			if remaining == Duration::from_secs(0) {
+				return Err(assertions::NotQuiet { received: self.drain() });
+			}
+
+			match self.rx.next().timeout(quiet.min(remaining)).await {
+				Some(Some(msg)) => {
+					self.outbound.lock().on_receive();
+					self.buffered.push_back(msg);
+				}
+				None if remaining < quiet => return Err(assertions::NotQuiet { received: self.drain() }),
+				_ => return Ok(self.drain()),
+			}
+		}
+	}
+
+	fn buffer_ready(&mut self) {
+		if self.outbound.lock().reading_stopped {
+			return;
+		}
+
+		while let Ok(Some(msg)) = self.rx.try_next() {
+			self.outbound.lock().on_receive();
+			self.buffered.push_back(msg);
+		}
+	}
+
+	/// Stop delivering messages to the subsystem, as if the overseer had stalled.
+	///
+	/// Sends to the subsystem pend until [`Self::resume_input`] is called. A message the
+	/// subsystem hasn't read yet is still delivered.
+	pub fn pause_input(&mut self) {
+		self.tx.set_paused(true);
+	}
+
+	/// Resume delivering messages to the subsystem after [`Self::pause_input`].
+	pub fn resume_input(&mut self) {
+		self.tx.set_paused(false);
+	}
+
+	/// The tasks spawned and messages sent by the subsystem so far, in order.
+	///
+	/// Useful to assert e.g. that a task is spawned before a request it serves is sent.
+	pub fn operation_log(&self) -> Vec<Operation> {
+		self.outbound.lock().operations.clone()
+	}
+
+	/// The virtual clock of the test, shared with the context.
+	pub fn clock(&self) -> &VirtualClock {
+		&self.clock
+	}
+
+	/// The virtual time elapsed since the subsystem last sent a message, or since the start of
+	/// the test if it sent none.
+	///
+	/// Only the [`VirtualClock`] is taken into account: without the test advancing it, this
+	/// stays at zero however long the subsystem is idle in real time.
+	pub fn idle_duration(&self) -> Duration {
+		let last_send = self.outbound.lock().last_send;
+		self.clock.now().saturating_sub(last_send)
+	}
+
+	/// Route the messages the subsystem sends from now on to one queue per destination.
+	///
+	/// Demultiplexed messages bypass [`Self::rx`], they are received from [`Self::queue`] instead.
+	/// Call this before the subsystem starts sending to have all of its messages routed.
+	pub fn demultiplex(&mut self) {
+		let (senders, receivers) = MessageKind::ALL.iter().map(|kind| {
+			let (tx, rx) = mpsc::unbounded();
+			((*kind, tx), (*kind, rx))
+		}).unzip();
+
+		self.outbound.lock().demux = Some(senders);
+		self.queues = receivers;
+	}
+
+	/// The queue of messages addressed to `kind`.
+	///
+	/// Panics unless [`Self::demultiplex`] has been called.
+	pub fn queue(&mut self, kind: MessageKind) -> &mut mpsc::UnboundedReceiver<AllMessages> {
+		self.queues.get_mut(&kind).expect("demultiplex must be called before accessing queues")
+	}
+
+	/// The highest number of messages the subsystem had sent without the handle taking them yet.
+	///
+	/// Messages taken from [`Self::rx`] directly are not accounted for, so they keep counting as
+	/// queued.
+	pub fn max_outbound_depth(&self) -> usize {
+		self.outbound.lock().peak_depth
+	}
+
+	/// Limit the number of messages the subsystem may send without the handle taking them.
+	///
+	/// Once `capacity` messages are queued, [`SubsystemSender::send_message`] and
+	/// [`SubsystemSender::send_messages`] block until the handle takes some, like sends to a
+	/// congested overseer. [`SubsystemSender::send_unbounded_message`] never blocks. `None`, the
+	/// default, lifts the limit.
+	pub fn set_outbound_capacity(&mut self, capacity: Option<usize>) {
+		let mut outbound = self.outbound.lock();
+		outbound.capacity = capacity;
+		outbound.wake_blocked_senders();
+	}
+
+	/// Stop taking messages from the subsystem, as if the overseer had become unresponsive.
+	///
+	/// Messages the handle took before are still handed out, further receives pend until
+	/// [`Self::resume_reading`] is called. Combined with [`Self::set_outbound_capacity`], the
+	/// subsystem's sends back up.
+	pub fn stop_reading(&mut self) {
+		self.outbound.lock().reading_stopped = true;
+	}
+
+	/// Resume taking messages from the subsystem after [`Self::stop_reading`].
+	pub fn resume_reading(&mut self) {
+		let mut outbound = self.outbound.lock();
+		outbound.reading_stopped = false;
+		if let Some(waker) = outbound.reader_waker.take() {
+			waker.wake();
+		}
+	}
+
+	/// How often the sink has woken the subsystem waiting to receive its next input.
+	///
+	/// Exact counts depend on the executor, see the [`wakeup`] module, so assert upper bounds,
+	/// e.g. the number of inputs plus a small constant.
+	pub fn wakeup_count(&self) -> usize {
+		self.tx.0.lock().wakeups.get()
+	}
+
+	/// Cancel all tasks the subsystem has spawned through its context so far.
+	///
+	/// Useful for subsystems spawning long-running loops, which would otherwise outlive the test.
+	/// Returns the names of the cancelled tasks.
+	pub fn abort_spawned_tasks(&mut self) -> Vec<&'static str> {
+		self.spawned.abort_all()
+	}
+
+	/// Assert that every task the subsystem spawned has either completed or been aborted with
+	/// [`Self::abort_spawned_tasks`].
+	///
+	/// Call this at the end of a test: a task still running after the subsystem concluded
+	/// usually means it is not wired up to shutdown. Panics naming the leaked tasks.
+	pub fn assert_no_leaked_tasks(&self) {
+		let leaked = self.spawned.running();
+		assert!(leaked.is_empty(), "Leaked tasks still running: {:?}", leaked);
+	}
+}
+
+/// A response channel nobody listens on, to stand in for a responder taken out of a message.
+pub fn placeholder_responder<T>() -> oneshot::Sender<T> {
+	oneshot::channel().0
+}
+
+/// A subsystem message type with constructors for placeholder messages.
+///
+/// Implemented for `()` and the message types with a `Default` value. Other message types
+/// implement [`Self::dummy`] themselves, or use [`impl_mockable_via_default`] if their `Default`
+/// value serves as the dummy message.
+pub trait MockableSubsystemMessage: Send + Sized + 'static {
+	/// A message for tests which don't care about its content.
+	fn dummy() -> Self;
+
+	/// The [`Self::dummy`] message, ready to be sent to the subsystem.
+	fn dummy_communication() -> FromOverseer<Self> {
+		fixtures::comm(Self::dummy())
+	}
+}
+
+/// Implement [`MockableSubsystemMessage`] for each of the given message types, taking their
+/// `Default` value as the dummy message.
+///
+/// ```ignore
+/// impl_mockable_via_default!(MyMessage, MyOtherMessage);
+/// ```
+#[macro_export]
+macro_rules! impl_mockable_via_default {
+	($($message:ty),+ $(,)?) => {
+		$(
+			impl $crate::MockableSubsystemMessage for $message {
+				fn dummy() -> Self {
+					<$message as ::std::default::Default>::default()
+				}
+			}
+		)+
+	};
+}
+
+impl_mockable_via_default!((), polkadot_node_subsystem::messages::CandidateSelectionMessage);
+
+impl<M: MockableSubsystemMessage> TestSubsystemContextHandle<M> {
+	/// Send the dummy message to the subsystem.
+	pub async fn send_dummy(&mut self) {
+		self.send(M::dummy_communication()).await
+	}
+}
+
+/// Make a test subsystem context for the message type `M`, spawning on a fresh [`TaskExecutor`].
+pub fn make_default_subsystem_context<M: MockableSubsystemMessage>()
+	-> (TestSubsystemContext<M, TaskExecutor>, TestSubsystemContextHandle<M>)
+{
+	make_subsystem_context(TaskExecutor::new())
+}
+
+/// Make a test subsystem context.
+pub fn make_subsystem_context<M, S>(
+	spawn: S,
+) -> (TestSubsystemContext<M, S>, TestSubsystemContextHandle<M>) {
+	let (overseer_tx, overseer_rx) = single_item_sink();
+	let (all_messages_tx, all_messages_rx) = mpsc::unbounded();
+	let clock = VirtualClock::new();
+	let outbound = Arc::new(Mutex::new(OutboundState { clock: clock.clone(), ..Default::default() }));
+	let spawned = SpawnedTasks::default();
+
+	(
+		TestSubsystemContext {
+			tx: TestSubsystemSender { tx: all_messages_tx, outbound: outbound.clone() },
+			rx: overseer_rx,
+			spawn,
+			spawned: spawned.clone(),
+			dispatch: None,
+			clock: clock.clone(),
+			peeked: None,
+		},
+		TestSubsystemContextHandle {
+			tx: overseer_tx,
+			rx: all_messages_rx,
+			buffered: VecDeque::new(),
+			queues: HashMap::new(),
+			outbound,
+			spawned,
+			clock,
+			active_leaves: HashSet::new(),
+			recorder: None,
+		},
+	)
+}
+
+/// Make a test subsystem context with `leaves`, given by hash and height, activated from the
+/// start.
+///
+/// A single update activating the leaves is queued as the first input, for the subsystem to
+/// read once it starts, so tests can skip activating an initial leaf themselves. To activate
+/// leaves of a subsystem which is running already, use
+/// [`TestSubsystemContextHandle::activate_leaves`].
+pub fn make_subsystem_context_with_leaves<M, S>(
+	spawn: S,
+	leaves: Vec<(Hash, BlockNumber)>,
+) -> (TestSubsystemContext<M, S>, TestSubsystemContextHandle<M>) {
+	let (context, mut handle) = make_subsystem_context(spawn);
+	let activation = fixtures::activated(&leaves);
+	handle.observe(&activation);
+	handle.tx.enqueue(activation);
+	(context, handle)
+}
+
+/// The messages from the subsystem, see [`TestSubsystemContextHandle::messages`].
+pub struct Messages<'a, M>(&'a mut TestSubsystemContextHandle<M>);
+
+impl<M> Stream for Messages<'_, M> {
+	type Item = AllMessages;
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<AllMessages>> {
+		let handle = &mut *self.get_mut().0;
+		if let Some(msg) = handle.buffered.pop_front() {
+			return Poll::Ready(Some(msg));
+		}
+
+		{
+			let mut outbound = handle.outbound.lock();
+			if outbound.reading_stopped {
+				outbound.reader_waker = Some(cx.waker().clone());
+				return Poll::Pending;
+			}
+		}
+
+		let poll = handle.rx.poll_next_unpin(cx);
+		if let Poll::Ready(Some(_)) = poll {
+			handle.outbound.lock().on_receive();
+		}
+		poll
+	}
+}
+
+/// Test a subsystem, mocking the overseer
+///
+/// Pass in two async closures: one mocks the overseer, the other runs the test from the perspective of a subsystem.
+///
+/// Both run to completion, see [`run_join`]. Times out after [`harness_timeout`].
+pub fn subsystem_test_harness<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) where
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	run_join(overseer_factory, test_factory)
+}
+
+/// Run the overseer script and the test body concurrently, returning once both have finished.
+///
+/// Neither side is cancelled when the other completes, so an overseer with a finite script may
+/// finish early while the test body carries on, and vice versa. This deadlocks if either side
+/// waits for something the other never provides, e.g. the test body awaiting a message the
+/// finished overseer didn't send. The shared [`harness_timeout`] turns such a deadlock into a
+/// panic instead of a hanging test.
+pub fn run_join<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) where
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let timeout = harness_timeout();
+	let pool = TaskExecutor::new();
+	let (context, handle) = make_subsystem_context(pool);
+	let overseer = overseer_factory(handle);
+	let test = test_factory(context);
+
+	futures::pin_mut!(overseer, test);
+
+	futures::executor::block_on(async move {
+		future::join(overseer, test)
+			.timeout(timeout)
+			.await
+			.expect("test timed out instead of completing")
+	});
+}
+
+/// Like [`run_join`], with the [`VirtualClock`] of the test frozen from the start, see
+/// [`VirtualClock::freeze`].
+///
+/// Tests relying on timers hang by design, so this asserts the subsystem makes progress from
+/// its inputs alone and never takes a timer-dependent path.
+pub fn run_frozen<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) where
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	run_join(
+		|handle| {
+			handle.clock().freeze();
+			overseer_factory(handle)
+		},
+		test_factory,
+	)
+}
+
+/// Like [`run_join`], for tests already running on a `tokio` runtime, e.g. `#[tokio::test]`.
+///
+/// `run_join` blocks the thread, which panics inside a runtime. This awaits both sides on the
+/// caller's runtime instead and enforces the timeout with `tokio`'s timer.
+#[cfg(feature = "tokio")]
+pub async fn run_on_tokio<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) where
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let timeout = harness_timeout();
+	let pool = TaskExecutor::new();
+	let (context, handle) = make_subsystem_context(pool);
+	let overseer = overseer_factory(handle);
+	let test = test_factory(context);
+
+	tokio::time::timeout(timeout, future::join(overseer, test))
+		.await
+		.expect("test timed out instead of completing");
+}
+
+/// Start `subsystem` on a test context and run it against the overseer script to completion.
+///
+/// Returns the result the subsystem exited with. Times out like [`subsystem_test_harness`].
+pub fn run_subsystem<M, S, OverseerFactory, Overseer>(
+	subsystem: S,
+	overseer_factory: OverseerFactory,
+) -> SubsystemResult<()>
+where
+	M: Send + 'static,
+	S: Subsystem<TestSubsystemContext<M, TaskExecutor>>,
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+{
+	run_subsystem_with(|ctx| subsystem.start(ctx).future, overseer_factory)
+}
+
+/// Like [`run_subsystem`], for subsystems exposing a `run(ctx)` function instead.
+pub fn run_subsystem_with<M, E, Run, RunFuture, OverseerFactory, Overseer>(
+	run: Run,
+	overseer_factory: OverseerFactory,
+) -> Result<(), E>
+where
+	Run: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> RunFuture,
+	RunFuture: Future<Output = Result<(), E>>,
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+{
+	let mut result = None;
+
+	run_join(overseer_factory, |ctx| {
+		let subsystem = run(ctx);
+		let result = &mut result;
+		async move { *result = Some(subsystem.await) }
+	});
+
+	result.expect("run_join only returns once the subsystem has exited")
+}
+
+/// Run the test scenario produced by `factory`, asserting the test body panics with a message
+/// containing `substring`.
+///
+/// Once the test body panicked, the overseer script is abandoned. Only panics of the test body
+/// itself are caught, not those of tasks it spawned. The panic hook is left untouched, so the
+/// expected panic is still printed.
+pub fn run_expecting_panic<M, Factory, OverseerFactory, Overseer, TestFactory, Test>(
+	factory: Factory,
+	substring: &str,
+)
+where
+	Factory: FnOnce() -> (OverseerFactory, TestFactory),
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let timeout = harness_timeout();
+	let (overseer_factory, test_factory) = factory();
+	let (context, handle) = make_subsystem_context(TaskExecutor::new());
+
+	let overseer = overseer_factory(handle);
+	let overseer = async move {
+		overseer.await;
+		future::pending::<()>().await
+	};
+	let test = std::panic::AssertUnwindSafe(test_factory(context)).catch_unwind();
+
+	futures::pin_mut!(overseer, test);
+
+	let outcome = futures::executor::block_on(async move {
+		match future::select(overseer, test).timeout(timeout).await {
+			Some(future::Either::Right((outcome, _))) => outcome,
+			Some(future::Either::Left(_)) => unreachable!("the overseer future never completes"),
+			None => panic!("test timed out instead of completing"),
+		}
+	});
+
+	let payload = match outcome {
+		Ok(()) => panic!("The subsystem didn't panic, expected a panic containing {:?}", substring),
+		Err(payload) => payload,
+	};
+
+	let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+		.or_else(|| payload.downcast_ref::<String>().cloned())
+		.unwrap_or_else(|| "<non-string panic payload>".to_owned());
+
+	assert!(
+		message.contains(substring),
+		"The subsystem panicked with {:?}, expected a panic containing {:?}",
+		message,
+		substring,
+	);
+}
+
+/// Like [`subsystem_test_harness`], capturing the `tracing` events emitted during the test.
+///
+/// The capture is installed for the duration of the harness only, on the test's thread and in
+/// all tasks spawned through the context.
+pub fn traced_subsystem_test_harness<M, OverseerFactory, Overseer, TestFactory, Test>(
+	overseer_factory: OverseerFactory,
+	test_factory: TestFactory,
+) -> TracingCapture
+where
+	OverseerFactory: FnOnce(TestSubsystemContextHandle<M>) -> Overseer,
+	Overseer: Future<Output = ()>,
+	TestFactory: FnOnce(TestSubsystemContext<M, TaskExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let capture = TracingCapture::new();
+	let dispatch = capture.dispatch();
+
+	tracing::dispatcher::with_default(&dispatch, || subsystem_test_harness(
+		overseer_factory,
+		|mut context| {
+			context.dispatch = Some(dispatch.clone());
+			test_factory(context)
+		},
+	));
+
+	capture
+}
+
+/// A forward subsystem that implements [`Subsystem`].
+///
+/// It forwards all communication from the overseer to the internal message
+/// channel.
+///
+/// This subsystem is useful for testing functionality that interacts with the overseer.
+pub struct ForwardSubsystem<Msg>(pub mpsc::Sender<Msg>);
+
+impl<C: SubsystemContext<Message = Msg>, Msg: Send + 'static> Subsystem<C> for ForwardSubsystem<Msg> {
+	fn start(mut self, mut ctx: C) -> SpawnedSubsystem {
+		let future = Box::pin(async move {
+			loop {
+				match ctx.recv().await {
+					Ok(FromOverseer::Signal(OverseerSignal::Conclude)) => return Ok(()),
+					Ok(FromOverseer::Communication { msg }) => {
+						let _ = self.0.send(msg).await;
+					},
+					Err(_) => return Ok(()),
+					_ => (),
+				}
+			}
+		});
+
+		SpawnedSubsystem {
+			name: "forward-subsystem",
+			future,
+		}
+	}
+}
 
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use polkadot_overseer::{Overseer, AllSubsystems};
 	use futures::executor::block_on;
-	use polkadot_node_subsystem::messages::CandidateSelectionMessage;
+	use polkadot_node_subsystem::errors::ChainApiError;
+	use polkadot_node_subsystem::messages::{
+		CandidateSelectionMessage, ChainApiMessage, RuntimeApiMessage, RuntimeApiRequest,
+	};
+
+	type ChainApiResult<T> = Result<T, ChainApiError>;
 
 	#[test]
 	fn forward_subsystem_works() {
@@ -381,4 +1557,620 @@ mod tests {
 		block_on(handler.send_msg(CandidateSelectionMessage::Invalid(Default::default(), Default::default())));
 		assert!(matches!(block_on(rx.into_future()).0.unwrap(), CandidateSelectionMessage::Invalid(_, _)));
 	}
+
+	#[test]
+	fn unresponsive_overseer_backs_up_bounded_sends() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.set_outbound_capacity(Some(2));
+				handle.stop_reading();
+
+				futures_timer::Delay::new(Duration::from_millis(20)).await;
+				assert!(handle.drain().is_empty());
+				assert_eq!(handle.max_outbound_depth(), 2);
+
+				handle.resume_reading();
+				assert_eq!(handle.messages().take(5).count().await, 5);
+				assert_eq!(handle.max_outbound_depth(), 2);
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				for i in 0..5 {
+					let (tx, _) = oneshot::channel();
+					ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(i), tx).into()).await;
+				}
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn timeouts_are_parsed_in_seconds() {
+		assert_eq!(parse_timeout("30"), Duration::from_secs(30));
+		assert_eq!(parse_timeout(" 3600\n"), Duration::from_secs(MAX_TIMEOUT_SECS));
+	}
+
+	#[test]
+	#[should_panic(expected = "POLKADOT_TEST_TIMEOUT_SECS must be a number of seconds within 1..=3600, got \"0\"")]
+	fn zero_timeouts_are_rejected() {
+		parse_timeout("0");
+	}
+
+	#[test]
+	#[should_panic(expected = "got \"2s\"")]
+	fn timeouts_with_units_are_rejected() {
+		parse_timeout("2s");
+	}
+
+	#[test]
+	fn messages_stream_ends_with_the_subsystem() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let first = handle.messages().take(3).collect::<Vec<_>>().await;
+				assert_eq!(first.len(), 3);
+
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+				assert_eq!(handle.messages().collect::<Vec<_>>().await.len(), 1);
+			},
+			|mut ctx| async move {
+				for _ in 0..4 {
+					let (tx, _) = oneshot::channel();
+					ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx).into()).await;
+				}
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn run_join_completes_both_sides() {
+		let (overseer_done_tx, overseer_done_rx) = oneshot::channel();
+
+		run_join(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+				let _ = overseer_done_tx.send(());
+			},
+			|mut ctx| async move {
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+				// The overseer's script is over, but the test body still gets to finish.
+				overseer_done_rx.await.unwrap();
+			},
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "test timed out instead of completing")]
+	fn run_join_times_out_on_deadlock() {
+		run_join(
+			|_handle: TestSubsystemContextHandle<()>| async {},
+			|mut ctx| async move {
+				let _ = ctx.recv().await;
+			},
+		);
+	}
+
+	#[cfg(feature = "tokio")]
+	#[tokio::test]
+	async fn run_on_tokio_inside_a_runtime() {
+		run_on_tokio(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		).await;
+	}
+
+	#[cfg(feature = "tokio")]
+	#[tokio::test]
+	#[should_panic(expected = "test timed out instead of completing")]
+	async fn run_on_tokio_times_out_on_deadlock() {
+		run_on_tokio(
+			|_handle: TestSubsystemContextHandle<()>| async {},
+			|mut ctx| async move {
+				let _ = ctx.recv().await;
+			},
+		).await;
+	}
+
+	#[test]
+	fn run_subsystem_drives_subsystems_to_completion() {
+		let (tx, mut rx) = mpsc::channel(1);
+
+		let result = run_subsystem(
+			ForwardSubsystem(tx),
+			|mut handle: TestSubsystemContextHandle<CandidateSelectionMessage>| async move {
+				handle.send_dummy().await;
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+		);
+
+		assert!(result.is_ok());
+		assert!(matches!(rx.try_next(), Ok(Some(CandidateSelectionMessage::Invalid(..)))));
+	}
+
+	#[test]
+	fn run_subsystem_with_surfaces_errors() {
+		let result = run_subsystem_with(
+			|mut ctx: TestSubsystemContext<(), _>| async move {
+				ctx.recv().await?;
+				Err::<(), _>(SubsystemError::Context("refusing to work".to_owned()))
+			},
+			|mut handle| async move {
+				handle.send_dummy().await;
+			},
+		);
+
+		assert!(matches!(result, Err(SubsystemError::Context(reason)) if reason == "refusing to work"));
+	}
+
+	fn take_block_number_responder(
+		msg: AllMessages,
+	) -> (oneshot::Sender<ChainApiResult<Option<u32>>>, AllMessages) {
+		match msg {
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, tx)) =>
+				(tx, ChainApiMessage::BlockNumber(hash, placeholder_responder()).into()),
+			msg => panic!("Not a block number request: {:?}", msg),
+		}
+	}
+
+	#[test]
+	fn taken_responders_can_reply_later() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let (tx, msg) = handle.take_responder(take_block_number_responder).await;
+				assert!(matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..))));
+
+				handle.send_dummy().await;
+				let _ = tx.send(Ok(Some(7)));
+			},
+			|mut ctx| async move {
+				let (tx, rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx).into()).await;
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Communication { .. })));
+				assert_eq!(rx.await.unwrap().unwrap(), Some(7));
+			},
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "Failed to take the responder of RuntimeApi")]
+	fn taking_a_responder_of_another_variant_panics() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.take_responder(take_block_number_responder).await;
+			},
+			|mut ctx| async move {
+				let request = RuntimeApiRequest::Validators(oneshot::channel().0);
+				ctx.send_message(RuntimeApiMessage::Request(Hash::repeat_byte(1), request).into()).await;
+			},
+		);
+	}
+
+	fn strict_subsystem() -> (
+		impl FnOnce(TestSubsystemContextHandle<u32>) -> future::BoxFuture<'static, ()>,
+		impl FnOnce(TestSubsystemContext<u32, TaskExecutor>) -> future::BoxFuture<'static, ()>,
+	) {
+		(
+			|mut handle| async move {
+				handle.send(comm(1)).await;
+				handle.send(comm(0)).await;
+			}.boxed(),
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					assert!(msg > 0, "invariant violated: zero input");
+				}
+			}.boxed(),
+		)
+	}
+
+	#[test]
+	fn expected_panics_are_caught() {
+		run_expecting_panic(strict_subsystem, "invariant violated");
+	}
+
+	#[test]
+	#[should_panic(expected = "The subsystem panicked with \"invariant violated: zero input\"")]
+	fn unexpected_panic_messages_are_reported() {
+		run_expecting_panic(strict_subsystem, "out of range");
+	}
+
+	#[test]
+	fn abort_spawned_tasks_cancels_never_ending_task() {
+		let (alive_tx, alive_rx) = oneshot::channel::<()>();
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+
+				assert_eq!(handle.abort_spawned_tasks(), vec!["never-ending"]);
+				// The task owned the sender, so it resolves once the task has been dropped.
+				assert!(alive_rx.await.is_err());
+			},
+			|mut ctx| async move {
+				ctx.spawn("never-ending", Box::pin(async move {
+					let _alive_tx = alive_tx;
+					future::pending::<()>().await;
+				})).await.unwrap();
+
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	fn run_with_leftover_task(leak: bool) {
+		let pool = LocalPoolExecutor::new();
+		let (mut ctx, mut handle) = make_subsystem_context::<(), _>(pool.clone());
+
+		pool.run_until(future::join(
+			async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+				handle.assert_no_leaked_tasks();
+			},
+			async move {
+				let (done_tx, done_rx) = oneshot::channel();
+				ctx.spawn("short-lived", Box::pin(async move {
+					let _ = done_tx.send(());
+				})).await.unwrap();
+				done_rx.await.unwrap();
+
+				if leak {
+					ctx.spawn("background-loop", Box::pin(future::pending())).await.unwrap();
+				}
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		));
+	}
+
+	#[test]
+	fn completed_tasks_are_not_leaked() {
+		run_with_leftover_task(false);
+	}
+
+	#[test]
+	#[should_panic(expected = "Leaked tasks still running: [\"background-loop\"]")]
+	fn running_tasks_are_reported_as_leaked() {
+		run_with_leftover_task(true);
+	}
+
+	#[test]
+	fn operation_log_interleaves_spawns_and_sends() {
+		let hash = Hash::repeat_byte(1);
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+
+				let log = handle.operation_log();
+				assert_eq!(log.len(), 3);
+				assert_eq!(log[0], Operation::Spawn("responder"));
+				assert!(matches!(log[1], Operation::Send(ref summary) if summary.contains("ChainApi")));
+				assert_eq!(log[2], Operation::Spawn("cleanup"));
+			},
+			|mut ctx| async move {
+				ctx.spawn("responder", Box::pin(async {})).await.unwrap();
+				let (tx, _rx) = oneshot::channel();
+				ctx.send_message(ChainApiMessage::BlockNumber(hash, tx).into()).await;
+				ctx.spawn_blocking("cleanup", Box::pin(async {})).await.unwrap();
+
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn max_outbound_depth_tracks_the_peak() {
+		let block_number = || AllMessages::ChainApi(
+			ChainApiMessage::BlockNumber(Hash::repeat_byte(1), oneshot::channel().0),
+		);
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send_dummy().await;
+				handle.recv().await;
+				handle.send_dummy().await;
+				assert_eq!(handle.drain().len(), 3);
+
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+				assert_eq!(handle.max_outbound_depth(), 3);
+			},
+			|mut ctx| async move {
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Communication { .. })));
+				ctx.send_message(block_number()).await;
+				// The overseer takes the first message before waking us up again.
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Communication { .. })));
+				ctx.send_messages(vec![block_number(), block_number(), block_number()]).await;
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn demultiplexed_messages_are_queued_per_kind() {
+		let hash = Hash::repeat_byte(1);
+		let validators = move || AllMessages::RuntimeApi(
+			RuntimeApiMessage::Request(hash, RuntimeApiRequest::Validators(oneshot::channel().0)),
+		);
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.demultiplex();
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+
+				assert!(matches!(handle.queue(MessageKind::ChainApi).next().await, Some(AllMessages::ChainApi(_))));
+				for _ in 0..2 {
+					let msg = handle.queue(MessageKind::RuntimeApi).next().await;
+					assert!(matches!(msg, Some(AllMessages::RuntimeApi(_))));
+				}
+				assert!(handle.drain().is_empty());
+			},
+			|mut ctx| async move {
+				ctx.send_message(validators()).await;
+				ctx.send_message(ChainApiMessage::BlockNumber(hash, oneshot::channel().0).into()).await;
+				ctx.send_messages(vec![validators()]).await;
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn messages_for_relay_parent_buffers_the_rest() {
+		let a = Hash::repeat_byte(1);
+		let b = Hash::repeat_byte(2);
+		let request = |hash| AllMessages::RuntimeApi(
+			RuntimeApiMessage::Request(hash, RuntimeApiRequest::Validators(oneshot::channel().0)),
+		);
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+
+				let for_a = handle.messages_for_relay_parent(a);
+				assert_eq!(for_a.len(), 2);
+				assert!(for_a.iter().all(|msg| projection::relay_parent(msg) == Some(a)));
+
+				assert_eq!(projection::relay_parent(&handle.recv().await), Some(b));
+				assert!(handle.drain().is_empty());
+			},
+			|mut ctx| async move {
+				ctx.send_messages(vec![request(a), request(b), request(a)]).await;
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	fn filtered_receives_leave_the_other_kind_pending() {
+		let (mut ctx, mut handle) = make_subsystem_context::<u32, _>(TaskExecutor::new());
+		handle.tx.enqueue(FromOverseer::Communication { msg: 1 });
+		handle.tx.enqueue(FromOverseer::Signal(OverseerSignal::Conclude));
+
+		block_on(async {
+			assert_eq!(ctx.try_recv_signal().await, Ok(None));
+			assert_eq!(ctx.try_recv_communication().await, Ok(Some(1)));
+			assert_eq!(ctx.try_recv_communication().await, Ok(None));
+			assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			assert!(matches!(ctx.try_recv().await, Ok(None)));
+		});
+	}
+
+	/// Requests the validators of each activated leaf.
+	async fn request_validators_per_leaf(mut ctx: TestSubsystemContext<(), TaskExecutor>) {
+		while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+			for leaf in update.activated {
+				let request = RuntimeApiRequest::Validators(oneshot::channel().0);
+				ctx.send_message(RuntimeApiMessage::Request(leaf.hash, request).into()).await;
+			}
+		}
+	}
+
+	#[test]
+	fn initial_leaves_are_read_on_startup() {
+		let leaves = vec![(Hash::repeat_byte(1), 1), (Hash::repeat_byte(2), 2)];
+		let (ctx, mut handle) = make_subsystem_context_with_leaves(TaskExecutor::new(), leaves);
+		assert_eq!(handle.active_leaves.len(), 2);
+
+		block_on(future::join(
+			async move {
+				assert_eq!(projection::relay_parent(&handle.recv().await), Some(Hash::repeat_byte(1)));
+				assert_eq!(projection::relay_parent(&handle.recv().await), Some(Hash::repeat_byte(2)));
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			request_validators_per_leaf(ctx),
+		));
+	}
+
+	#[test]
+	fn activated_leaves_settle() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let sent = handle.activate_leaves(vec![(Hash::repeat_byte(1), 1)]).await;
+				assert_eq!(sent.len(), 1);
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			request_validators_per_leaf,
+		);
+	}
+
+	#[test]
+	fn frozen_subsystems_progress_from_inputs_alone() {
+		run_frozen(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send_dummy().await;
+				assert!(matches!(handle.recv().await, AllMessages::ChainApi(_)));
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let timeout = ctx.clock().sleep(Duration::from_secs(0));
+				futures::pin_mut!(timeout);
+
+				loop {
+					let input = match future::select(ctx.recv(), timeout.as_mut()).await {
+						future::Either::Left((input, _)) => input,
+						future::Either::Right(_) => panic!("the timer fired under a frozen clock"),
+					};
+
+					match input {
+						Ok(FromOverseer::Communication { .. }) => {
+							let request = ChainApiMessage::BlockNumber(Hash::repeat_byte(1), oneshot::channel().0);
+							ctx.send_message(request.into()).await;
+						}
+						_ => break,
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn idle_duration_is_reset_by_sends() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.clock().advance(Duration::from_secs(5));
+				assert_eq!(handle.idle_duration(), Duration::from_secs(5));
+
+				handle.send_dummy().await;
+				handle.recv().await;
+				assert_eq!(handle.idle_duration(), Duration::from_secs(0));
+
+				handle.clock().advance(Duration::from_secs(3));
+				assert_eq!(handle.idle_duration(), Duration::from_secs(3));
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {
+					let request = ChainApiMessage::BlockNumber(Hash::repeat_byte(1), oneshot::channel().0);
+					ctx.send_message(request.into()).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn wait_for_count_takes_the_whole_burst() {
+		let request = |hash| AllMessages::RuntimeApi(
+			RuntimeApiMessage::Request(hash, RuntimeApiRequest::Validators(oneshot::channel().0)),
+		);
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+				assert_eq!(handle.wait_for_count(2, Duration::from_secs(1)).await.len(), 3);
+			},
+			|mut ctx| async move {
+				let hash = Hash::repeat_byte(1);
+				ctx.send_messages(vec![request(hash), request(hash), request(hash)]).await;
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "Received 1 of 2 messages")]
+	fn wait_for_count_reports_shortfalls() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.wait_for_count(2, Duration::from_millis(50)).await;
+			},
+			|mut ctx| async move {
+				let request = ChainApiMessage::BlockNumber(Hash::repeat_byte(1), oneshot::channel().0);
+				ctx.send_message(request.into()).await;
+			},
+		);
+	}
+
+	#[test]
+	fn paused_input_starves_the_subsystem() {
+		let block_number = |hash| AllMessages::ChainApi(
+			ChainApiMessage::BlockNumber(hash, oneshot::channel().0),
+		);
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.pause_input();
+				assert!(handle.send_dummy().timeout(Duration::from_millis(50)).await.is_none());
+				assert!(handle.drain().is_empty());
+
+				handle.resume_input();
+				handle.send_dummy().await;
+				assert!(matches!(handle.recv().await, AllMessages::ChainApi(_)));
+
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {
+					ctx.send_message(block_number(Hash::repeat_byte(1))).await;
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn finished_sink_ends_the_stream_after_the_last_item() {
+		let (mut sink, mut stream) = single_item_sink::<u32>();
+
+		block_on(async {
+			assert!(sink.send(1).now_or_never().is_none());
+			assert_eq!(sink.len(), 1);
+
+			sink.finish();
+			assert_eq!(stream.next().await, Some(1));
+			assert_eq!(stream.next().await, None);
+			assert!(sink.is_empty());
+		});
+	}
+
+	#[cfg(feature = "debug-internals")]
+	#[test]
+	fn transition_log_records_the_rendezvous() {
+		let (mut sink, mut stream) = single_item_sink::<u32>();
+
+		block_on(future::join(sink.send(1), stream.next()));
+
+		let log = sink.transition_log();
+		let operations = log.iter().map(|t| (t.operation, t.ready)).collect::<Vec<_>>();
+		assert_eq!(&operations[..3], &[
+			(SinkOperation::PollReady, true),
+			(SinkOperation::StartSend, true),
+			(SinkOperation::PollFlush, false),
+		]);
+		assert!(log.iter().any(|t| t.operation == SinkOperation::PollNext && t.ready));
+		assert!(!log.last().unwrap().holds_item);
+	}
+
+	#[cfg(feature = "debug-internals")]
+	#[test]
+	fn transition_log_is_bounded() {
+		let (sink, mut stream) = single_item_sink::<u32>();
+		for _ in 0..TRANSITION_LOG_CAPACITY + 1 {
+			assert!(stream.next().now_or_never().is_none());
+		}
+
+		assert_eq!(sink.transition_log().len(), TRANSITION_LOG_CAPACITY);
+	}
+
+	#[test]
+	fn dropped_sink_leaves_the_stream_pending() {
+		let (sink, mut stream) = single_item_sink::<u32>();
+		drop(sink);
+
+		assert!(stream.next().now_or_never().is_none());
+	}
+
+	#[test]
+	fn default_context_delivers_dummy_messages() {
+		let (mut ctx, mut handle) = make_default_subsystem_context::<CandidateSelectionMessage>();
+
+		block_on(future::join(
+			handle.send_dummy(),
+			async move {
+				assert!(matches!(
+					ctx.recv().await,
+					Ok(FromOverseer::Communication { msg: CandidateSelectionMessage::Invalid(..) })
+				));
+			},
+		));
+	}
 }
@@ -0,0 +1,132 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A test subsystem context for message types which are not `Send`.
+//!
+//! [`SubsystemContext`](polkadot_node_subsystem::SubsystemContext) requires its messages and
+//! tasks to be `Send`. Tests using mock messages which aren't can use a
+//! [`LocalTestSubsystemContext`] instead, which offers the same operations as inherent methods.
+//! Such a context and its tasks are confined to the thread driving the [`LocalPool`] they were
+//! created for.
+//!
+//! [`LocalPool`]: futures::executor::LocalPool
+
+use std::pin::Pin;
+
+use futures::executor::LocalSpawner;
+use futures::poll;
+use futures::prelude::*;
+use futures::task::{LocalSpawnExt, Poll};
+use polkadot_node_subsystem::messages::AllMessages;
+use polkadot_node_subsystem::{FromOverseer, SubsystemError, SubsystemResult, SubsystemSender};
+
+use crate::{
+	SingleItemStream, SpawnedTasks, TestSubsystemContext, TestSubsystemContextHandle,
+	TestSubsystemSender, make_subsystem_context,
+};
+
+/// A test subsystem context spawning on a [`LocalPool`](futures::executor::LocalPool).
+///
+/// Mirrors [`TestSubsystemContext`], without requiring `M` or spawned tasks to be `Send`. It can
+/// therefore not be handed to code generic over `SubsystemContext`.
+pub struct LocalTestSubsystemContext<M> {
+	tx: TestSubsystemSender,
+	rx: SingleItemStream<FromOverseer<M>>,
+	spawn: LocalSpawner,
+	spawned: SpawnedTasks,
+}
+
+impl<M> LocalTestSubsystemContext<M> {
+	/// Try to receive a message without waiting for one.
+	pub async fn try_recv(&mut self) -> Result<Option<FromOverseer<M>>, ()> {
+		match poll!(self.rx.next()) {
+			Poll::Ready(Some(msg)) => Ok(Some(msg)),
+			Poll::Ready(None) => Err(()),
+			Poll::Pending => Ok(None),
+		}
+	}
+
+	/// Receive a message.
+	pub async fn recv(&mut self) -> SubsystemResult<FromOverseer<M>> {
+		self.rx.next().await
+			.ok_or_else(|| SubsystemError::Context("Receiving end closed".to_owned()))
+	}
+
+	/// Spawn a task on the local pool.
+	pub async fn spawn(
+		&mut self,
+		name: &'static str,
+		s: Pin<Box<dyn Future<Output = ()>>>,
+	) -> SubsystemResult<()> {
+		self.tx.outbound.lock().on_spawn(name);
+		self.spawn.spawn_local(self.spawned.track_local(name, s))?;
+		Ok(())
+	}
+
+	/// Get a mutable reference to the sender.
+	pub fn sender(&mut self) -> &mut TestSubsystemSender {
+		&mut self.tx
+	}
+
+	/// Send a message to another subsystem.
+	pub async fn send_message(&mut self, msg: AllMessages) {
+		self.tx.send_message(msg).await
+	}
+}
+
+/// Make a test subsystem context for the local pool `spawn` belongs to.
+///
+/// The context must only be driven by that pool.
+pub fn make_local_subsystem_context<M>(
+	spawn: LocalSpawner,
+) -> (LocalTestSubsystemContext<M>, TestSubsystemContextHandle<M>) {
+	let (context, handle) = make_subsystem_context(spawn);
+	let TestSubsystemContext { tx, rx, spawn, spawned, .. } = context;
+
+	(LocalTestSubsystemContext { tx, rx, spawn, spawned }, handle)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::rc::Rc;
+	use futures::channel::oneshot;
+	use futures::executor::LocalPool;
+
+	#[test]
+	fn non_send_messages_are_delivered() {
+		let mut pool = LocalPool::new();
+		let (mut ctx, mut handle) = make_local_subsystem_context::<Rc<u32>>(pool.spawner());
+
+		pool.run_until(future::join(
+			async move {
+				handle.send(FromOverseer::Communication { msg: Rc::new(5) }).await;
+			},
+			async move {
+				let msg = match ctx.recv().await {
+					Ok(FromOverseer::Communication { msg }) => msg,
+					other => panic!("Unexpected {:?}", other),
+				};
+
+				let (tx, rx) = oneshot::channel();
+				ctx.spawn("rc-task", Box::pin(async move {
+					let _ = tx.send(*msg + 1);
+				})).await.unwrap();
+				assert_eq!(rx.await, Ok(6));
+			},
+		));
+	}
+}
@@ -0,0 +1,182 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Simulation of intermittent loss of inbound messages.
+
+use async_trait::async_trait;
+use polkadot_node_subsystem::FromOverseer;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{TestSubsystemContextHandle, layer::HandleLayer};
+
+const LOG_TARGET: &str = "subsystem-test-helpers::lossy";
+
+/// A [`HandleLayer`] discarding some of the communication sent to the subsystem.
+///
+/// Drops are decided by an RNG seeded explicitly, so a given seed and probability always drop
+/// the same sends. Signals are always delivered, as the overseer guarantees their delivery.
+pub struct Lossy {
+	drop_probability: f64,
+	rng: StdRng,
+	sent: usize,
+	dropped: Vec<usize>,
+}
+
+impl Lossy {
+	/// Drop each communication with the given probability.
+	///
+	/// Panics if `drop_probability` is not within `0.0..=1.0`.
+	pub fn new(drop_probability: f64, seed: u64) -> Self {
+		assert!(
+			(0.0..=1.0).contains(&drop_probability),
+			"drop probability must be within 0..=1, got {}",
+			drop_probability,
+		);
+
+		Lossy {
+			drop_probability,
+			rng: StdRng::seed_from_u64(seed),
+			sent: 0,
+			dropped: Vec::new(),
+		}
+	}
+
+	/// The indices of the sends which were dropped, counting from 0.
+	pub fn dropped(&self) -> &[usize] {
+		&self.dropped
+	}
+
+	fn should_drop<M>(&mut self, from_overseer: &FromOverseer<M>) -> bool {
+		let index = self.sent;
+		self.sent += 1;
+
+		if let FromOverseer::Communication { .. } = from_overseer {
+			if self.rng.gen_bool(self.drop_probability) {
+				tracing::debug!(target: LOG_TARGET, index, "Dropping message sent to the subsystem");
+				self.dropped.push(index);
+				return true;
+			}
+		}
+
+		false
+	}
+}
+
+#[async_trait]
+impl<M: Send + 'static> HandleLayer<M> for Lossy {
+	async fn process(&mut self, from_overseer: FromOverseer<M>) -> Option<FromOverseer<M>> {
+		if self.should_drop(&from_overseer) {
+			None
+		} else {
+			Some(from_overseer)
+		}
+	}
+}
+
+/// A handle wrapper applying a single [`Lossy`] layer, see there for details.
+pub struct LossyHandle<M> {
+	inner: TestSubsystemContextHandle<M>,
+	lossy: Lossy,
+}
+
+impl<M> LossyHandle<M> {
+	/// Wrap `inner`, dropping each communication with the given probability.
+	///
+	/// Panics if `drop_probability` is not within `0.0..=1.0`.
+	pub fn new(inner: TestSubsystemContextHandle<M>, drop_probability: f64, seed: u64) -> Self {
+		LossyHandle { inner, lossy: Lossy::new(drop_probability, seed) }
+	}
+
+	/// Send a message or signal to the subsystem, unless it gets lost.
+	///
+	/// Returns whether the message was delivered.
+	pub async fn send(&mut self, from_overseer: FromOverseer<M>) -> bool {
+		if self.lossy.should_drop(&from_overseer) {
+			return false;
+		}
+
+		self.inner.send(from_overseer).await;
+		true
+	}
+
+	/// The indices of the sends which were dropped, counting from 0.
+	pub fn dropped(&self) -> &[usize] {
+		self.lossy.dropped()
+	}
+
+	/// Access the wrapped handle, e.g. to receive messages from the subsystem.
+	pub fn inner(&mut self) -> &mut TestSubsystemContextHandle<M> {
+		&mut self.inner
+	}
+
+	/// Unwrap the handle.
+	pub fn into_inner(self) -> TestSubsystemContextHandle<M> {
+		self.inner
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::{executor::block_on, future};
+	use polkadot_node_subsystem::{OverseerSignal, SubsystemContext};
+	use sp_core::testing::TaskExecutor;
+	use crate::make_subsystem_context;
+
+	const SENDS: u32 = 20;
+
+	/// Returns the dropped indices and the number of messages received by the subsystem.
+	fn lossy_run(drop_probability: f64, seed: u64) -> (Vec<usize>, usize) {
+		let (mut ctx, handle) = make_subsystem_context::<u32, _>(TaskExecutor::new());
+		let mut lossy = LossyHandle::new(handle, drop_probability, seed);
+
+		let overseer = async move {
+			for i in 0..SENDS {
+				lossy.send(FromOverseer::Communication { msg: i }).await;
+			}
+			assert!(lossy.send(FromOverseer::Signal(OverseerSignal::Conclude)).await);
+			lossy.dropped().to_vec()
+		};
+
+		let subsystem = async move {
+			let mut received = 0;
+			loop {
+				match ctx.recv().await.unwrap() {
+					FromOverseer::Communication { .. } => received += 1,
+					FromOverseer::Signal(OverseerSignal::Conclude) => return received,
+					FromOverseer::Signal(_) => {}
+				}
+			}
+		};
+
+		block_on(future::join(overseer, subsystem))
+	}
+
+	#[test]
+	fn drops_are_seeded() {
+		let (dropped, received) = lossy_run(0.5, 42);
+
+		assert_eq!(dropped.len() + received, SENDS as usize);
+		assert!(!dropped.is_empty() && received > 0);
+		assert_eq!(lossy_run(0.5, 42).0, dropped);
+	}
+
+	#[test]
+	fn extreme_probabilities() {
+		assert_eq!(lossy_run(0.0, 1), (Vec::new(), SENDS as usize));
+		assert_eq!(lossy_run(1.0, 1), ((0..SENDS as usize).collect(), 0));
+	}
+}
@@ -0,0 +1,181 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! An in-memory database for subsystems persisting data, e.g. the availability store.
+
+use std::sync::Arc;
+
+use kvdb::{DBTransaction, KeyValueDB};
+use kvdb_memorydb::InMemory;
+use polkadot_node_subsystem::{ActivatedLeaf, ActiveLeavesUpdate, OverseerSignal};
+use polkadot_node_subsystem::messages::AllMessages;
+
+use crate::TestSubsystemContextHandle;
+use crate::fixtures::signal;
+
+/// An in-memory key-value database, to be handed to the subsystem under test with [`Self::db`].
+///
+/// Clones share the same data, so the test can keep one to inspect what the subsystem persisted.
+#[derive(Clone)]
+pub struct MemDb {
+	inner: Arc<InMemory>,
+	columns: u32,
+}
+
+impl MemDb {
+	/// Create an empty database with `columns` columns.
+	pub fn new(columns: u32) -> Self {
+		MemDb {
+			inner: Arc::new(kvdb_memorydb::create(columns)),
+			columns,
+		}
+	}
+
+	/// The database, for the subsystem to read from and write to.
+	pub fn db(&self) -> Arc<dyn KeyValueDB> {
+		self.inner.clone()
+	}
+
+	/// The value stored under `key` in `column`, if any.
+	pub fn get(&self, column: u32, key: &[u8]) -> Option<Vec<u8>> {
+		self.inner.get(column, key).expect("the in-memory database doesn't fail")
+	}
+
+	/// Store `value` under `key` in `column`, e.g. to seed the database before the test.
+	pub fn put(&self, column: u32, key: &[u8], value: &[u8]) {
+		let mut transaction = DBTransaction::new();
+		transaction.put(column, key, value);
+		self.inner.write(transaction).expect("the in-memory database doesn't fail");
+	}
+
+	/// All keys stored, along with their columns, ordered by column and key.
+	pub fn all_keys(&self) -> Vec<(u32, Vec<u8>)> {
+		(0..self.columns)
+			.flat_map(|column| {
+				let mut keys = self.inner.iter(column).map(|(key, _)| key.into_vec()).collect::<Vec<_>>();
+				keys.sort();
+				keys.into_iter().map(move |key| (column, key))
+			})
+			.collect()
+	}
+}
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Activate `leaf`, then deactivate it, asserting the subsystem removes from `db` the keys it
+	/// wrote for the leaf in between.
+	///
+	/// The subsystem must be wired to `db`. Keys written after the activation which contain the
+	/// leaf hash are considered scoped to the leaf. Panics if there are none, or naming the ones
+	/// remaining once the subsystem fell silent after the deactivation. Returns all messages the
+	/// subsystem sent meanwhile.
+	pub async fn assert_cleanup(&mut self, db: &MemDb, leaf: ActivatedLeaf) -> Vec<AllMessages> {
+		let hash = leaf.hash;
+		let before = db.all_keys();
+
+		self.send(signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::start_work(leaf)))).await;
+		let mut sent = self.drain_settled("after activating the leaf").await;
+
+		let scoped = db.all_keys()
+			.into_iter()
+			.filter(|key| !before.contains(key))
+			.filter(|(_, key)| key.windows(hash.as_bytes().len()).any(|window| window == hash.as_bytes()))
+			.collect::<Vec<_>>();
+		assert!(!scoped.is_empty(), "The subsystem didn't write any keys for leaf {}", hash);
+
+		self.send(signal(OverseerSignal::ActiveLeaves(ActiveLeavesUpdate::stop_work(hash)))).await;
+		sent.extend(self.drain_settled("after deactivating the leaf").await);
+
+		let after = db.all_keys();
+		let remaining = scoped.into_iter().filter(|key| after.contains(key)).collect::<Vec<_>>();
+		assert!(
+			remaining.is_empty(),
+			"Keys of leaf {} remain after deactivation: {:?}",
+			hash,
+			remaining,
+		);
+
+		sent
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_subsystem::{FromOverseer, SubsystemContext, jaeger};
+	use polkadot_primitives::v1::Hash;
+	use crate::subsystem_test_harness;
+
+	#[test]
+	fn writes_through_the_database_are_visible() {
+		let mem_db = MemDb::new(2);
+		mem_db.put(0, b"b", b"1");
+
+		let mut transaction = DBTransaction::new();
+		transaction.put(1, b"a", b"2");
+		transaction.put(0, b"a", b"3");
+		mem_db.db().write(transaction).unwrap();
+
+		assert_eq!(mem_db.get(1, b"a"), Some(b"2".to_vec()));
+		assert_eq!(mem_db.get(1, b"b"), None);
+		assert_eq!(mem_db.clone().all_keys(), vec![
+			(0, b"a".to_vec()),
+			(0, b"b".to_vec()),
+			(1, b"a".to_vec()),
+		]);
+	}
+
+	/// Writes a key per activated leaf, removing it on deactivation if `clean`.
+	fn cleanup_test(clean: bool) {
+		let mem_db = MemDb::new(1);
+		let db = mem_db.db();
+		let leaf = ActivatedLeaf {
+			hash: Hash::repeat_byte(1),
+			number: 1,
+			span: Arc::new(jaeger::Span::Disabled),
+		};
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				mem_db.put(0, b"unrelated", b"");
+				handle.assert_cleanup(&mem_db, leaf).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+					let mut transaction = DBTransaction::new();
+					for leaf in update.activated {
+						transaction.put(0, &[leaf.hash.as_bytes(), &b"data"[..]].concat(), b"");
+					}
+					for hash in update.deactivated.into_iter().filter(|_| clean) {
+						transaction.delete(0, &[hash.as_bytes(), &b"data"[..]].concat());
+					}
+					db.write(transaction).unwrap();
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn cleaned_up_leaves_pass() {
+		cleanup_test(true);
+	}
+
+	#[test]
+	#[should_panic(expected = "remain after deactivation")]
+	fn remaining_keys_are_reported() {
+		cleanup_test(false);
+	}
+}
@@ -0,0 +1,139 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A metrics registry for inspecting the metrics of the subsystem under test.
+
+use polkadot_node_subsystem::messages::AllMessages;
+use polkadot_node_subsystem_util::metrics::prometheus::Registry;
+
+use crate::TestSubsystemContextHandle;
+
+/// A Prometheus registry for the subsystem under test to register its metrics with, e.g.
+/// through `Metrics::register(Some(metrics.registry()))`.
+///
+/// Clones share the same metrics, so the test can keep one to read what the subsystem recorded.
+#[derive(Clone, Default)]
+pub struct TestMetricsRegistry {
+	registry: Registry,
+}
+
+impl TestMetricsRegistry {
+	/// Create an empty registry.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The registry, for the subsystem to register its metrics with.
+	pub fn registry(&self) -> &Registry {
+		&self.registry
+	}
+
+	/// The value of the counter registered under `name`, summed over all of its label values,
+	/// or `None` if no metric of that name is registered.
+	pub fn counter(&self, name: &str) -> Option<u64> {
+		self.registry.gather()
+			.into_iter()
+			.find(|family| family.get_name() == name)
+			.map(|family| family.get_metric().iter().map(|metric| metric.get_counter().get_value() as u64).sum())
+	}
+}
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Wait for the subsystem to fall silent, asserting the counter `metric_name` of `metrics`
+	/// equals the number of messages it sent satisfying `output_matcher`.
+	///
+	/// The counter must have been registered with `metrics`, and is compared as is, so it must
+	/// only count messages sent from now on. Panics with both numbers if they differ. Returns
+	/// all messages received.
+	pub async fn assert_metric_tracks_output(
+		&mut self,
+		metrics: &TestMetricsRegistry,
+		metric_name: &str,
+		output_matcher: impl Fn(&AllMessages) -> bool,
+	) -> Vec<AllMessages> {
+		let received = self.drain_settled("before the output could be counted").await;
+		let observed = received.iter().filter(|msg| output_matcher(msg)).count() as u64;
+
+		let counted = match metrics.counter(metric_name) {
+			Some(counted) => counted,
+			None => panic!("No metric {} is registered", metric_name),
+		};
+		assert_eq!(
+			counted,
+			observed,
+			"The counter {} is at {}, but the subsystem sent {} matching messages",
+			metric_name,
+			counted,
+			observed,
+		);
+
+		received
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::channel::oneshot;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use polkadot_node_subsystem::messages::ChainApiMessage;
+	use polkadot_node_subsystem_util::metrics::prometheus;
+	use polkadot_primitives::v1::Hash;
+	use crate::{comm, signal, subsystem_test_harness};
+
+	/// Queries the block number of each input, counting the queries except for `uncounted` ones.
+	fn metrics_test(uncounted: u8) {
+		let metrics = TestMetricsRegistry::new();
+		let requests = prometheus::register(
+			prometheus::Counter::<prometheus::U64>::new("test_requests_total", "Number of requests").unwrap(),
+			metrics.registry(),
+		).unwrap();
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<u8>| async move {
+				for input in 0..3 {
+					handle.send(comm(input)).await;
+				}
+				handle.assert_metric_tracks_output(
+					&metrics,
+					"test_requests_total",
+					|msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..))),
+				).await;
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+					let request = ChainApiMessage::BlockNumber(Hash::repeat_byte(msg), oneshot::channel().0);
+					ctx.send_message(request.into()).await;
+					if msg != uncounted {
+						requests.inc();
+					}
+				}
+			},
+		);
+	}
+
+	#[test]
+	fn counters_tracking_the_output_pass() {
+		metrics_test(u8::MAX);
+	}
+
+	#[test]
+	#[should_panic(expected = "The counter test_requests_total is at 2, but the subsystem sent 3 matching messages")]
+	fn drifting_counters_are_reported() {
+		metrics_test(1);
+	}
+}
@@ -0,0 +1,132 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Counting the polls of a future, to detect busy-looping.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use futures::prelude::*;
+use pin_project::pin_project;
+
+/// A shared view on the poll count of a [`PollCounting`] future.
+#[derive(Clone, Default)]
+pub struct PollCounter(Arc<AtomicUsize>);
+
+impl PollCounter {
+	/// The number of times the future has been polled so far.
+	pub fn get(&self) -> usize {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+/// A future counting how often it is polled.
+///
+/// A subsystem polled far more often than it receives inputs is likely busy-looping, e.g. on
+/// `try_recv`. Poll counts depend on how the executor and the other futures involved wake the
+/// subsystem though, so they should only ever be asserted as upper bounds.
+///
+/// The count can be read through a [`PollCounter`] once the future has been moved into a harness.
+#[pin_project]
+pub struct PollCounting<F> {
+	#[pin]
+	inner: F,
+	counter: PollCounter,
+}
+
+impl<F> PollCounting<F> {
+	/// Wrap `inner`.
+	pub fn new(inner: F) -> Self {
+		PollCounting {
+			inner,
+			counter: PollCounter::default(),
+		}
+	}
+
+	/// The number of times the future has been polled so far.
+	pub fn poll_count(&self) -> usize {
+		self.counter.get()
+	}
+
+	/// A counter which remains readable after the future has been moved.
+	pub fn counter(&self) -> PollCounter {
+		self.counter.clone()
+	}
+}
+
+impl<F: Future> Future for PollCounting<F> {
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+		let this = self.project();
+		this.counter.0.fetch_add(1, Ordering::Relaxed);
+		this.inner.poll(cx)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use crate::{TestSubsystemContextHandle, subsystem_test_harness};
+
+	#[test]
+	fn counts_polls_until_completion() {
+		let mut yielded = false;
+		let future = PollCounting::new(future::poll_fn(move |cx| {
+			if yielded {
+				Poll::Ready(())
+			} else {
+				yielded = true;
+				cx.waker().wake_by_ref();
+				Poll::Pending
+			}
+		}));
+		let counter = future.counter();
+		assert_eq!(future.poll_count(), 0);
+
+		futures::executor::block_on(future);
+		assert_eq!(counter.get(), 2);
+	}
+
+	#[test]
+	fn waiting_subsystem_is_polled_a_bounded_number_of_times() {
+		const INPUTS: usize = 11;
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				for _ in 1..INPUTS {
+					handle.send_dummy().await;
+				}
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| {
+				let subsystem = PollCounting::new(async move {
+					while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {}
+				});
+				let counter = subsystem.counter();
+
+				async move {
+					subsystem.await;
+					// Not busy-looping: a few polls per input at most.
+					assert!(counter.get() <= 4 * INPUTS, "polled {} times", counter.get());
+				}
+			},
+		);
+	}
+}
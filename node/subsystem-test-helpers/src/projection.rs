@@ -0,0 +1,109 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Projections of messages onto the values assertions are interested in.
+//!
+//! Values like the relay-parent live in different fields depending on the message variant, so
+//! assertions are parameterized by a [`Projection`] extracting them.
+
+#[cfg(any(test, feature = "structural-eq"))]
+use polkadot_node_network_protocol::request_response::Requests;
+use polkadot_node_subsystem::messages::{
+	AllMessages, AvailabilityDistributionMessage, AvailabilityRecoveryMessage, AvailabilityStoreMessage,
+	BoundToRelayParent, CollatorProtocolMessage, RuntimeApiMessage, RuntimeApiRequest,
+};
+#[cfg(any(test, feature = "structural-eq"))]
+use polkadot_node_subsystem::messages::NetworkBridgeMessage;
+use polkadot_primitives::v1::{Hash, SessionIndex, ValidatorIndex};
+
+/// Extracts a value from a message, if the message carries one.
+///
+/// Implemented for all closures of the right shape.
+pub trait Projection<T> {
+	/// Project the message onto the value.
+	fn project(&self, msg: &AllMessages) -> Option<T>;
+}
+
+impl<T, F: Fn(&AllMessages) -> Option<T>> Projection<T> for F {
+	fn project(&self, msg: &AllMessages) -> Option<T> {
+		self(msg)
+	}
+}
+
+/// The relay-parent a message is bound to, for the variants carrying one.
+pub fn relay_parent(msg: &AllMessages) -> Option<Hash> {
+	match msg {
+		AllMessages::CandidateValidation(m) => m.relay_parent(),
+		AllMessages::CandidateBacking(m) => Some(BoundToRelayParent::relay_parent(m)),
+		AllMessages::CandidateSelection(m) => Some(BoundToRelayParent::relay_parent(m)),
+		AllMessages::ChainApi(m) => m.relay_parent(),
+		AllMessages::CollatorProtocol(CollatorProtocolMessage::FetchCollation(relay_parent, ..)) =>
+			Some(*relay_parent),
+		AllMessages::CollatorProtocol(_) => None,
+		AllMessages::StatementDistribution(m) => m.relay_parent(),
+		AllMessages::AvailabilityDistribution(AvailabilityDistributionMessage::FetchPoV { relay_parent, .. }) =>
+			Some(*relay_parent),
+		AllMessages::AvailabilityDistribution(_) => None,
+		AllMessages::AvailabilityRecovery(_) => None,
+		AllMessages::BitfieldDistribution(m) => m.relay_parent(),
+		AllMessages::BitfieldSigning(m) => match *m {},
+		AllMessages::Provisioner(m) => Some(BoundToRelayParent::relay_parent(m)),
+		AllMessages::RuntimeApi(m) => m.relay_parent(),
+		AllMessages::AvailabilityStore(m) => m.relay_parent(),
+		AllMessages::NetworkBridge(m) => m.relay_parent(),
+		AllMessages::CollationGeneration(m) => m.relay_parent(),
+		AllMessages::ApprovalVoting(_) => None,
+		AllMessages::ApprovalDistribution(_) => None,
+		AllMessages::GossipSupport(m) => match *m {},
+	}
+}
+
+/// The validators a message targets, for the variants addressing validators by index.
+///
+/// Chunk requests sent through the network bridge target the validators whose chunk indices
+/// they request, which is only known with the `structural-eq` feature enabled.
+pub fn validator_indices(msg: &AllMessages) -> Option<Vec<ValidatorIndex>> {
+	match msg {
+		AllMessages::AvailabilityDistribution(AvailabilityDistributionMessage::FetchPoV { from_validator, .. }) =>
+			Some(vec![*from_validator]),
+		AllMessages::AvailabilityStore(AvailabilityStoreMessage::QueryChunk(_, index, _)) |
+		AllMessages::AvailabilityStore(AvailabilityStoreMessage::QueryChunkAvailability(_, index, _)) =>
+			Some(vec![*index]),
+		AllMessages::AvailabilityStore(AvailabilityStoreMessage::StoreAvailableData(_, Some(index), ..)) =>
+			Some(vec![*index]),
+		#[cfg(any(test, feature = "structural-eq"))]
+		AllMessages::NetworkBridge(NetworkBridgeMessage::SendRequests(requests, _)) => {
+			let indices = requests.iter().filter_map(|request| match request {
+				Requests::ChunkFetching(request) => Some(request.payload.index),
+				_ => None,
+			}).collect::<Vec<_>>();
+
+			if indices.is_empty() { None } else { Some(indices) }
+		}
+		_ => None,
+	}
+}
+
+/// The session a message refers to, for the variants carrying a session index.
+pub fn session_index(msg: &AllMessages) -> Option<SessionIndex> {
+	match msg {
+		AllMessages::RuntimeApi(RuntimeApiMessage::Request(_, RuntimeApiRequest::SessionInfo(index, _))) =>
+			Some(*index),
+		AllMessages::AvailabilityRecovery(AvailabilityRecoveryMessage::RecoverAvailableData(_, index, ..)) =>
+			Some(*index),
+		_ => None,
+	}
+}
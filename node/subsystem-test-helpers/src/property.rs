@@ -0,0 +1,126 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Property-based testing of subsystems against random input sequences.
+
+use std::fmt;
+
+use futures::prelude::*;
+use polkadot_node_subsystem::{FromOverseer, OverseerSignal};
+use proptest::collection;
+use proptest::prop_assert;
+use proptest::strategy::Strategy;
+use proptest::test_runner::{Config, TestRng, TestRunner};
+
+use crate::{TestSubsystemContext, TestSubsystemContextHandle, fixtures::signal};
+use crate::capture::{CaptureReport, run_captured};
+use crate::executor::LocalPoolExecutor;
+
+/// Check `invariant` holds for the messages the subsystem sends in reaction to random sequences
+/// of up to `max_len` inputs drawn from `inputs`.
+///
+/// Each sequence is followed by `Conclude` and run like in [`run_captured`]: on a
+/// [`LocalPoolExecutor`] with a fresh virtual clock, under the harness timeout. A sequence fails
+/// if the subsystem panics, hangs, or sends messages violating `invariant`. Failing sequences
+/// are shrunk, and the panic names the minimal one found. Sequences are generated from a fixed
+/// seed and runs are deterministic, so failures reproduce.
+///
+/// ```ignore
+/// subsystem_property(
+/// 	any::<u8>().prop_map(comm),
+/// 	10,
+/// 	|ctx| run(ctx),
+/// 	|report| report.messages.len() <= 10,
+/// );
+/// ```
+pub fn subsystem_property<M, S, Factory, Subsystem>(
+	inputs: S,
+	max_len: usize,
+	factory: Factory,
+	invariant: impl Fn(&CaptureReport) -> bool,
+)
+where
+	M: fmt::Debug,
+	S: Strategy<Value = FromOverseer<M>>,
+	Factory: Fn(TestSubsystemContext<M, LocalPoolExecutor>) -> Subsystem,
+	Subsystem: Future<Output = ()>,
+{
+	let config = Config { failure_persistence: None, ..Config::default() };
+	let rng = TestRng::deterministic_rng(config.rng_algorithm);
+	let mut runner = TestRunner::new_with_rng(config, rng);
+
+	let result = runner.run(&collection::vec(inputs, 0..=max_len), |sequence| {
+		let report = run_captured(
+			move |mut handle: TestSubsystemContextHandle<M>| async move {
+				for from_overseer in sequence {
+					handle.send(from_overseer).await;
+				}
+				handle.send(signal(OverseerSignal::Conclude)).await;
+			},
+			&factory,
+		);
+
+		prop_assert!(invariant(&report), "The invariant doesn't hold for {:?}", report.messages);
+		Ok(())
+	});
+
+	if let Err(failure) = result {
+		panic!("{}", failure);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::channel::oneshot;
+	use proptest::prelude::any;
+	use polkadot_node_subsystem::SubsystemContext;
+	use polkadot_node_subsystem::messages::ChainApiMessage;
+	use polkadot_primitives::v1::Hash;
+	use crate::comm;
+
+	/// Requests one block number per input, and a second one for inputs of at least `threshold`.
+	async fn requester(mut ctx: TestSubsystemContext<u8, LocalPoolExecutor>, threshold: u8) {
+		while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+			let requests = if msg >= threshold { 2 } else { 1 };
+			for _ in 0..requests {
+				let request = ChainApiMessage::BlockNumber(Hash::repeat_byte(msg), oneshot::channel().0);
+				ctx.send_message(request.into()).await;
+			}
+		}
+	}
+
+	#[test]
+	fn holding_invariants_pass() {
+		subsystem_property(
+			any::<u8>().prop_map(comm),
+			5,
+			|ctx| requester(ctx, u8::MAX),
+			|report| report.messages.len() <= 2 * 5,
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "minimal failing input")]
+	fn violated_invariants_are_shrunk() {
+		subsystem_property(
+			any::<u8>().prop_map(comm),
+			5,
+			|ctx| requester(ctx, 200),
+			|report| report.messages.len() < 2,
+		);
+	}
+}
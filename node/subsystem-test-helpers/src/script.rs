@@ -0,0 +1,403 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! A scripted overseer, to express tests as data.
+//!
+//! A script is a list of [`OverseerStep`]s executed in order: inputs to send to the subsystem,
+//! requests to expect and answer, and periods the subsystem must stay quiet for. It combines
+//! the input injection of the [`TestSubsystemContextHandle`] with the request/response checks of
+//! the [`ContractOverseer`](crate::ContractOverseer), without the standing rules.
+
+use std::time::Duration;
+
+use futures::future::{self, Future, FutureExt, LocalBoxFuture};
+use polkadot_node_subsystem::FromOverseer;
+use polkadot_node_subsystem::messages::AllMessages;
+use polkadot_node_subsystem_util::TimeoutExt;
+
+use crate::{TestSubsystemContext, TestSubsystemContextHandle, harness_timeout, make_subsystem_context};
+use crate::contract::expectation_timeout;
+use crate::executor::LocalPoolExecutor;
+
+/// Decides whether a request is the expected one.
+pub type Matcher = Box<dyn Fn(&AllMessages) -> bool + Send>;
+
+/// Answers a matched request.
+///
+/// It takes the request, extracts its response channel and sends the response. If the request
+/// has no response channel the reply can use, it is handed back, failing the step.
+pub type Reply = Box<dyn FnOnce(AllMessages) -> Result<(), AllMessages> + Send>;
+
+/// A step of an overseer script, see [`with_script`].
+pub enum OverseerStep<M> {
+	/// Send a message or signal to the subsystem.
+	Send(FromOverseer<M>),
+	/// Expect the next request of the subsystem to satisfy the matcher, and answer it.
+	///
+	/// Fails if nothing arrives within [`expectation_timeout`].
+	ExpectAndReply(Matcher, Reply),
+	/// Expect the subsystem not to send anything for the given wall-clock duration.
+	///
+	/// The subsystem hanging up meanwhile doesn't fail the step.
+	ExpectSilence(Duration),
+}
+
+impl<M> OverseerStep<M> {
+	/// An [`OverseerStep::ExpectAndReply`] step, boxing `matcher` and `reply`.
+	///
+	/// ```ignore
+	/// OverseerStep::expect_and_reply(
+	/// 	|msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..))),
+	/// 	|msg| match msg {
+	/// 		AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) => {
+	/// 			let _ = tx.send(Ok(Some(1)));
+	/// 			Ok(())
+	/// 		}
+	/// 		msg => Err(msg),
+	/// 	},
+	/// )
+	/// ```
+	pub fn expect_and_reply(
+		matcher: impl Fn(&AllMessages) -> bool + Send + 'static,
+		reply: impl FnOnce(AllMessages) -> Result<(), AllMessages> + Send + 'static,
+	) -> Self {
+		OverseerStep::ExpectAndReply(Box::new(matcher), Box::new(reply))
+	}
+
+	fn describe(&self) -> String {
+		match self {
+			OverseerStep::Send(FromOverseer::Signal(signal)) => format!("send {:?}", signal),
+			OverseerStep::Send(FromOverseer::Communication { .. }) => "send message".to_owned(),
+			OverseerStep::ExpectAndReply(..) => "expect and reply".to_owned(),
+			OverseerStep::ExpectSilence(duration) => format!("expect silence for {:?}", duration),
+		}
+	}
+}
+
+/// An overseer executing `script`, for use as the overseer side of
+/// [`subsystem_test_harness`](crate::subsystem_test_harness).
+///
+/// ```ignore
+/// subsystem_test_harness(
+/// 	with_script(vec![
+/// 		OverseerStep::Send(comm(msg)),
+/// 		OverseerStep::ExpectSilence(Duration::from_millis(100)),
+/// 		OverseerStep::Send(signal(OverseerSignal::Conclude)),
+/// 	]),
+/// 	|ctx| run(ctx),
+/// );
+/// ```
+pub fn with_script<M: 'static>(
+	script: Vec<OverseerStep<M>>,
+) -> impl FnOnce(TestSubsystemContextHandle<M>) -> LocalBoxFuture<'static, ()> {
+	move |mut handle| async move { run_script(&mut handle, script).await }.boxed_local()
+}
+
+/// Execute `script` as the overseer of `handle`.
+///
+/// Panics naming the index and kind of the first step which fails.
+pub async fn run_script<M>(handle: &mut TestSubsystemContextHandle<M>, script: Vec<OverseerStep<M>>) {
+	for (i, step) in script.into_iter().enumerate() {
+		run_step(handle, step, &format!("Step #{}", i)).await;
+	}
+}
+
+/// Run a test with two overseer scripts feeding the subsystem, e.g. one for messages from the
+/// network and one for signals scheduled locally.
+///
+/// The scripts are merged step by step: the first step of `script_a`, then the first step of
+/// `script_b`, then the second step of `script_a` and so on, until both are exhausted. Each step
+/// completes before the next one starts, so a request the subsystem sends is always checked
+/// against the expectation of the script whose turn it is. The test runs on a
+/// [`LocalPoolExecutor`], so together with the fixed merge order the run doesn't depend on
+/// wall-clock scheduling. Failures name the step and its script.
+pub fn run_with_two_scripts<M, TestFactory, Test>(
+	script_a: Vec<OverseerStep<M>>,
+	script_b: Vec<OverseerStep<M>>,
+	test_factory: TestFactory,
+) where
+	TestFactory: FnOnce(TestSubsystemContext<M, LocalPoolExecutor>) -> Test,
+	Test: Future<Output = ()>,
+{
+	let timeout = harness_timeout();
+	let executor = LocalPoolExecutor::new();
+	let (context, mut handle) = make_subsystem_context(executor.clone());
+
+	let overseer = async move {
+		let mut script_a = script_a.into_iter().enumerate();
+		let mut script_b = script_b.into_iter().enumerate();
+
+		loop {
+			let (a, b) = (script_a.next(), script_b.next());
+			if a.is_none() && b.is_none() {
+				break;
+			}
+
+			if let Some((i, step)) = a {
+				run_step(&mut handle, step, &format!("Step #{} of script A", i)).await;
+			}
+			if let Some((i, step)) = b {
+				run_step(&mut handle, step, &format!("Step #{} of script B", i)).await;
+			}
+		}
+	};
+	let test = test_factory(context);
+
+	executor.run_until(async move {
+		future::join(overseer, test)
+			.timeout(timeout)
+			.await
+			.expect("test timed out instead of completing")
+	});
+}
+
+/// A multi-round exchange with the subsystem, scripted fluently, see
+/// [`TestSubsystemContextHandle::dialogue`].
+///
+/// Each [`Self::expect`] may be followed by a [`Self::reply`] answering the expected message,
+/// e.g. to script a request, a partial response, a follow-up request and the final response.
+/// Nothing is executed before [`Self::run`].
+#[must_use = "dialogues do nothing unless run"]
+pub struct Dialogue<'a, M> {
+	handle: &'a mut TestSubsystemContextHandle<M>,
+	steps: Vec<OverseerStep<M>>,
+	/// The matcher of the last expectation, until it is known whether a reply follows.
+	pending: Option<Matcher>,
+}
+
+impl<'a, M> Dialogue<'a, M> {
+	/// Send `from_overseer` to the subsystem.
+	pub fn send(mut self, from_overseer: FromOverseer<M>) -> Self {
+		self.flush();
+		self.steps.push(OverseerStep::Send(from_overseer));
+		self
+	}
+
+	/// Expect the next message of the subsystem to satisfy `matcher`.
+	///
+	/// Unless followed by a [`Self::reply`], the message is dropped once matched.
+	pub fn expect(mut self, matcher: impl Fn(&AllMessages) -> bool + Send + 'static) -> Self {
+		self.flush();
+		self.pending = Some(Box::new(matcher));
+		self
+	}
+
+	/// Answer the message expected by the preceding [`Self::expect`] with `reply`, like the reply
+	/// of an [`OverseerStep::ExpectAndReply`] step.
+	///
+	/// Panics if not directly preceded by an expectation.
+	pub fn reply(
+		mut self,
+		reply: impl FnOnce(AllMessages) -> Result<(), AllMessages> + Send + 'static,
+	) -> Self {
+		let matcher = self.pending.take().expect("reply must directly follow an expectation");
+		self.steps.push(OverseerStep::ExpectAndReply(matcher, Box::new(reply)));
+		self
+	}
+
+	/// Execute the dialogue in order.
+	///
+	/// Panics naming the index and kind of the first step at which the subsystem diverged.
+	pub async fn run(mut self) {
+		self.flush();
+		for (i, step) in self.steps.into_iter().enumerate() {
+			run_step(self.handle, step, &format!("Dialogue step #{}", i)).await;
+		}
+	}
+
+	fn flush(&mut self) {
+		if let Some(matcher) = self.pending.take() {
+			self.steps.push(OverseerStep::ExpectAndReply(matcher, Box::new(|_| Ok(()))));
+		}
+	}
+}
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Start a [`Dialogue`] with the subsystem.
+	///
+	/// ```ignore
+	/// handle.dialogue()
+	/// 	.send(comm(request))
+	/// 	.expect(|msg| matches!(msg, AllMessages::ChainApi(_)))
+	/// 	.reply(answer_block_number)
+	/// 	.expect(|msg| matches!(msg, AllMessages::RuntimeApi(_)))
+	/// 	.run()
+	/// 	.await;
+	/// ```
+	pub fn dialogue(&mut self) -> Dialogue<'_, M> {
+		Dialogue {
+			handle: self,
+			steps: Vec::new(),
+			pending: None,
+		}
+	}
+}
+
+/// Execute a single `step`, panicking with `label` if it fails.
+async fn run_step<M>(handle: &mut TestSubsystemContextHandle<M>, step: OverseerStep<M>, label: &str) {
+	let description = step.describe();
+
+	match step {
+		OverseerStep::Send(from_overseer) => handle.send(from_overseer).await,
+		OverseerStep::ExpectAndReply(matcher, reply) => {
+			let timeout = expectation_timeout();
+			let msg = match handle.try_recv().timeout(timeout).await {
+				Some(Some(msg)) => msg,
+				Some(None) => step_failed(label, &description, "the subsystem hung up".to_owned()),
+				None => {
+					let reason = format!("nothing received within {:?}", timeout);
+					step_failed(label, &description, reason)
+				}
+			};
+
+			if !matcher(&msg) {
+				step_failed(label, &description, format!("received unexpected {:?}", msg));
+			}
+
+			if let Err(msg) = reply(msg) {
+				step_failed(label, &description, format!("no response channel to reply on in {:?}", msg));
+			}
+		}
+		OverseerStep::ExpectSilence(duration) => {
+			if let Some(Some(msg)) = handle.try_recv().timeout(duration).await {
+				step_failed(label, &description, format!("received {:?}", msg));
+			}
+		}
+	}
+}
+
+fn step_failed(label: &str, description: &str, reason: String) -> ! {
+	panic!("{} ({}) failed: {}", label, description, reason)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::channel::oneshot;
+	use polkadot_node_subsystem::{OverseerSignal, SubsystemContext};
+	use polkadot_node_subsystem::messages::ChainApiMessage;
+	use polkadot_primitives::v1::Hash;
+	use crate::{comm, signal, subsystem_test_harness};
+
+	fn is_block_number(msg: &AllMessages) -> bool {
+		matches!(msg, AllMessages::ChainApi(ChainApiMessage::BlockNumber(..)))
+	}
+
+	fn answer_block_number(number: u32) -> impl FnOnce(AllMessages) -> Result<(), AllMessages> {
+		move |msg| match msg {
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(_, tx)) => {
+				let _ = tx.send(Ok(Some(number)));
+				Ok(())
+			}
+			msg => Err(msg),
+		}
+	}
+
+	fn reply_block_number(number: u32) -> OverseerStep<Hash> {
+		OverseerStep::expect_and_reply(is_block_number, answer_block_number(number))
+	}
+
+	/// Looks up the block number of every hash it receives, until concluded.
+	async fn block_number_subsystem(mut ctx: impl SubsystemContext<Message = Hash>) {
+		while let Ok(FromOverseer::Communication { msg }) = ctx.recv().await {
+			let (tx, rx) = oneshot::channel();
+			ctx.send_message(ChainApiMessage::BlockNumber(msg, tx).into()).await;
+			assert_eq!(rx.await.unwrap().unwrap(), Some(7));
+		}
+	}
+
+	#[test]
+	fn scripts_drive_the_subsystem() {
+		subsystem_test_harness(
+			with_script(vec![
+				OverseerStep::ExpectSilence(Duration::from_millis(10)),
+				OverseerStep::Send(comm(Hash::repeat_byte(1))),
+				reply_block_number(7),
+				OverseerStep::Send(comm(Hash::repeat_byte(2))),
+				reply_block_number(7),
+				OverseerStep::Send(signal(OverseerSignal::Conclude)),
+			]),
+			block_number_subsystem,
+		);
+	}
+
+	#[test]
+	fn two_scripts_are_merged_step_by_step() {
+		let network = vec![
+			OverseerStep::Send(comm(Hash::repeat_byte(1))),
+			OverseerStep::Send(comm(Hash::repeat_byte(2))),
+			OverseerStep::Send(signal(OverseerSignal::Conclude)),
+		];
+		let local = vec![reply_block_number(7), reply_block_number(7)];
+
+		run_with_two_scripts(network, local, |ctx| block_number_subsystem(ctx));
+	}
+
+	#[test]
+	#[should_panic(expected = "Step #0 of script B (expect silence for 100ms) failed")]
+	fn failing_steps_name_their_script() {
+		let network = vec![OverseerStep::Send(comm(Hash::repeat_byte(1))), reply_block_number(7)];
+		let local = vec![OverseerStep::ExpectSilence(Duration::from_millis(100))];
+
+		run_with_two_scripts(network, local, |ctx| block_number_subsystem(ctx));
+	}
+
+	#[test]
+	fn dialogues_drive_multiple_rounds() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<Hash>| async move {
+				handle.dialogue()
+					.send(comm(Hash::repeat_byte(1)))
+					.expect(is_block_number)
+					.reply(answer_block_number(7))
+					.send(comm(Hash::repeat_byte(2)))
+					.expect(is_block_number)
+					.reply(answer_block_number(7))
+					.send(signal(OverseerSignal::Conclude))
+					.run()
+					.await;
+			},
+			block_number_subsystem,
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "Dialogue step #1 (expect and reply) failed: received unexpected")]
+	fn diverging_dialogue_steps_are_reported() {
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<Hash>| async move {
+				handle.dialogue()
+					.send(comm(Hash::repeat_byte(1)))
+					.expect(|msg| matches!(msg, AllMessages::ChainApi(ChainApiMessage::FinalizedBlockNumber(..))))
+					.run()
+					.await;
+			},
+			block_number_subsystem,
+		);
+	}
+
+	#[test]
+	#[should_panic(expected = "Step #1 (expect and reply) failed: the subsystem hung up")]
+	fn failing_steps_are_reported() {
+		subsystem_test_harness(
+			with_script(vec![
+				OverseerStep::Send(signal(OverseerSignal::Conclude)),
+				reply_block_number(7),
+			]),
+			block_number_subsystem,
+		);
+	}
+}
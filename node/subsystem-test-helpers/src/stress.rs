@@ -0,0 +1,125 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Stress tests driving the subsystem through many inputs in quick succession.
+
+use std::time::Duration;
+
+use polkadot_node_subsystem::{ActiveLeavesUpdate, OverseerSignal};
+use polkadot_node_subsystem::messages::AllMessages;
+use polkadot_node_subsystem_util::TimeoutExt;
+
+use crate::{TestSubsystemContextHandle, assertion_timeout};
+use crate::fixtures::signal;
+
+/// The virtual time between two leaves driven by [`TestSubsystemContextHandle::drive_leaves`].
+pub const LEAF_INTERVAL: Duration = Duration::from_secs(6);
+
+impl<M> TestSubsystemContextHandle<M> {
+	/// Deliver `count` active leaves updates in quick succession, draining the output.
+	///
+	/// The update of iteration `i` is built by `make_update(i)`, typically activating a new leaf
+	/// and deactivating an older one. Between updates the virtual clock advances by
+	/// [`LEAF_INTERVAL`], no real time passes. Panics naming the iteration if the subsystem stops
+	/// reading its input. Returns the messages drained after each update, by iteration.
+	pub async fn drive_leaves(
+		&mut self,
+		count: usize,
+		make_update: impl Fn(usize) -> ActiveLeavesUpdate,
+	) -> Vec<Vec<AllMessages>> {
+		let mut drained = Vec::with_capacity(count);
+
+		for i in 0..count {
+			let update = signal(OverseerSignal::ActiveLeaves(make_update(i)));
+
+			if self.send(update).timeout(assertion_timeout()).await.is_none() {
+				panic!("The subsystem hung at iteration #{} of {}", i, count);
+			}
+
+			drained.push(self.drain());
+			self.clock().advance(LEAF_INTERVAL);
+		}
+
+		drained
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use futures::channel::oneshot;
+	use parking_lot::Mutex;
+	use polkadot_node_subsystem::{ActivatedLeaf, FromOverseer, SubsystemContext, jaeger};
+	use polkadot_node_subsystem::messages::{RuntimeApiMessage, RuntimeApiRequest};
+	use crate::fixtures::hash;
+	use crate::subsystem_test_harness;
+
+	fn update(i: usize) -> ActiveLeavesUpdate {
+		let mut update = ActiveLeavesUpdate::start_work(ActivatedLeaf {
+			hash: hash(i as u8),
+			number: i as _,
+			span: Arc::new(jaeger::Span::Disabled),
+		});
+		if i > 0 {
+			update.deactivated.push(hash(i as u8 - 1));
+		}
+		update
+	}
+
+	#[test]
+	fn many_leaves_are_driven_through_the_subsystem() {
+		const COUNT: usize = 200;
+		let seen_at = Arc::new(Mutex::new(Vec::new()));
+		let observed = seen_at.clone();
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				let drained = handle.drive_leaves(COUNT, update).await;
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+
+				assert_eq!(drained.len(), COUNT);
+				for (i, messages) in drained.iter().enumerate() {
+					assert!(
+						matches!(
+							&messages[..],
+							[AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+								relay_parent,
+								RuntimeApiRequest::Validators(_),
+							))] if *relay_parent == hash(i as u8)
+						),
+						"Unexpected output at iteration #{}: {:?}", i, messages,
+					);
+				}
+
+				let expected = (0..COUNT as u32)
+					.map(|i| (hash(i as u8), LEAF_INTERVAL * i))
+					.collect::<Vec<_>>();
+				assert_eq!(*observed.lock(), expected);
+				assert_eq!(handle.clock().now(), LEAF_INTERVAL * COUNT as u32);
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Signal(OverseerSignal::ActiveLeaves(update))) = ctx.recv().await {
+					for leaf in update.activated {
+						seen_at.lock().push((leaf.hash, ctx.clock().now()));
+						let request = RuntimeApiRequest::Validators(oneshot::channel().0);
+						ctx.send_message(RuntimeApiMessage::Request(leaf.hash, request).into()).await;
+					}
+				}
+			},
+		);
+	}
+}
@@ -0,0 +1,407 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Structural equality for [`AllMessages`].
+//!
+//! Messages are compared field by field. Response channels can't be compared in any meaningful
+//! way, so they are treated as wildcards: two messages only differing in their responders are
+//! considered equal.
+//!
+//! The projection below matches every variant explicitly, so adding a message variant without
+//! teaching this module about it is a compile error rather than a silent equality.
+
+use std::fmt;
+
+use polkadot_node_network_protocol::request_response::{Requests, request::IncomingRequest};
+use polkadot_node_subsystem::messages::{
+	AllMessages, ApprovalDistributionMessage, ApprovalVotingMessage, AvailabilityDistributionMessage,
+	AvailabilityRecoveryMessage, AvailabilityStoreMessage, BitfieldDistributionMessage,
+	CandidateBackingMessage, CandidateSelectionMessage, CandidateValidationMessage, ChainApiMessage,
+	CollationGenerationMessage, CollatorProtocolMessage, NetworkBridgeMessage, ProvisionerMessage,
+	RuntimeApiMessage, RuntimeApiRequest, StatementDistributionMessage,
+};
+
+/// Rendering of a response channel in a [`MessageProjection`].
+pub const RESPONDER: &str = "<responder>";
+
+/// A comparable projection of an [`AllMessages`] value.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageProjection {
+	/// The `AllMessages` variant, i.e. the subsystem the message is addressed to.
+	pub subsystem: &'static str,
+	/// The variant of the subsystem's message.
+	pub variant: &'static str,
+	/// The `Debug` rendering of every field, with response channels replaced by [`RESPONDER`].
+	pub fields: Vec<String>,
+}
+
+impl fmt::Display for MessageProjection {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}::{}({})", self.subsystem, self.variant, self.fields.join(", "))
+	}
+}
+
+/// Whether two messages are equal, ignoring their response channels.
+pub fn messages_structurally_equal(a: &AllMessages, b: &AllMessages) -> bool {
+	project(a) == project(b)
+}
+
+fn field<T: fmt::Debug>(value: &T) -> String {
+	format!("{:?}", value)
+}
+
+fn responder() -> String {
+	RESPONDER.to_owned()
+}
+
+fn optional_responder<T>(tx: &Option<T>) -> String {
+	match tx {
+		Some(_) => format!("Some({})", RESPONDER),
+		None => "None".to_owned(),
+	}
+}
+
+fn incoming<Req: fmt::Debug>(req: &IncomingRequest<Req>) -> String {
+	format!("IncomingRequest {{ peer: {:?}, payload: {:?} }}", req.peer, req.payload)
+}
+
+fn outgoing(reqs: &[Requests]) -> String {
+	let rendered = reqs.iter().map(|req| match req {
+		Requests::ChunkFetching(r) => format!("ChunkFetching({:?}, {:?})", r.peer, r.payload),
+		Requests::CollationFetching(r) => format!("CollationFetching({:?}, {:?})", r.peer, r.payload),
+		Requests::PoVFetching(r) => format!("PoVFetching({:?}, {:?})", r.peer, r.payload),
+		Requests::AvailableDataFetching(r) => format!("AvailableDataFetching({:?}, {:?})", r.peer, r.payload),
+	}).collect::<Vec<_>>();
+
+	format!("[{}]", rendered.join(", "))
+}
+
+fn runtime_api_request(req: &RuntimeApiRequest) -> String {
+	let (name, mut fields) = match req {
+		RuntimeApiRequest::Authorities(_) => ("Authorities", vec![]),
+		RuntimeApiRequest::Validators(_) => ("Validators", vec![]),
+		RuntimeApiRequest::ValidatorGroups(_) => ("ValidatorGroups", vec![]),
+		RuntimeApiRequest::AvailabilityCores(_) => ("AvailabilityCores", vec![]),
+		RuntimeApiRequest::PersistedValidationData(para, assumption, _) =>
+			("PersistedValidationData", vec![field(para), field(assumption)]),
+		RuntimeApiRequest::CheckValidationOutputs(para, commitments, _) =>
+			("CheckValidationOutputs", vec![field(para), field(commitments)]),
+		RuntimeApiRequest::SessionIndexForChild(_) => ("SessionIndexForChild", vec![]),
+		RuntimeApiRequest::ValidationCode(para, assumption, _) =>
+			("ValidationCode", vec![field(para), field(assumption)]),
+		RuntimeApiRequest::HistoricalValidationCode(para, number, _) =>
+			("HistoricalValidationCode", vec![field(para), field(number)]),
+		RuntimeApiRequest::CandidatePendingAvailability(para, _) =>
+			("CandidatePendingAvailability", vec![field(para)]),
+		RuntimeApiRequest::CandidateEvents(_) => ("CandidateEvents", vec![]),
+		RuntimeApiRequest::SessionInfo(index, _) => ("SessionInfo", vec![field(index)]),
+		RuntimeApiRequest::DmqContents(para, _) => ("DmqContents", vec![field(para)]),
+		RuntimeApiRequest::InboundHrmpChannelsContents(para, _) =>
+			("InboundHrmpChannelsContents", vec![field(para)]),
+		RuntimeApiRequest::CurrentBabeEpoch(_) => ("CurrentBabeEpoch", vec![]),
+	};
+
+	fields.push(responder());
+	format!("{}({})", name, fields.join(", "))
+}
+
+/// Project a message onto its comparable parts.
+pub fn project(msg: &AllMessages) -> MessageProjection {
+	let (subsystem, (variant, fields)) = match msg {
+		AllMessages::CandidateValidation(m) => ("CandidateValidation", match m {
+			CandidateValidationMessage::ValidateFromChainState(descriptor, pov, _) =>
+				("ValidateFromChainState", vec![field(descriptor), field(pov), responder()]),
+			CandidateValidationMessage::ValidateFromExhaustive(data, code, descriptor, pov, _) =>
+				("ValidateFromExhaustive", vec![field(data), field(code), field(descriptor), field(pov), responder()]),
+		}),
+		AllMessages::CandidateBacking(m) => ("CandidateBacking", match m {
+			CandidateBackingMessage::GetBackedCandidates(hash, candidates, _) =>
+				("GetBackedCandidates", vec![field(hash), field(candidates), responder()]),
+			CandidateBackingMessage::Second(hash, receipt, pov) =>
+				("Second", vec![field(hash), field(receipt), field(pov)]),
+			CandidateBackingMessage::Statement(hash, statement) =>
+				("Statement", vec![field(hash), field(statement)]),
+		}),
+		AllMessages::CandidateSelection(m) => ("CandidateSelection", match m {
+			CandidateSelectionMessage::Collation(hash, para, collator) =>
+				("Collation", vec![field(hash), field(para), field(collator)]),
+			CandidateSelectionMessage::Invalid(hash, receipt) =>
+				("Invalid", vec![field(hash), field(receipt)]),
+			CandidateSelectionMessage::Seconded(hash, statement) =>
+				("Seconded", vec![field(hash), field(statement)]),
+		}),
+		AllMessages::ChainApi(m) => ("ChainApi", match m {
+			ChainApiMessage::BlockNumber(hash, _) => ("BlockNumber", vec![field(hash), responder()]),
+			ChainApiMessage::BlockHeader(hash, _) => ("BlockHeader", vec![field(hash), responder()]),
+			ChainApiMessage::FinalizedBlockHash(number, _) =>
+				("FinalizedBlockHash", vec![field(number), responder()]),
+			ChainApiMessage::FinalizedBlockNumber(_) => ("FinalizedBlockNumber", vec![responder()]),
+			ChainApiMessage::Ancestors { hash, k, .. } =>
+				("Ancestors", vec![field(hash), field(k), responder()]),
+		}),
+		AllMessages::CollatorProtocol(m) => ("CollatorProtocol", match m {
+			CollatorProtocolMessage::CollateOn(para) => ("CollateOn", vec![field(para)]),
+			CollatorProtocolMessage::DistributeCollation(receipt, pov, tx) =>
+				("DistributeCollation", vec![field(receipt), field(pov), optional_responder(tx)]),
+			CollatorProtocolMessage::FetchCollation(hash, collator, para, _) =>
+				("FetchCollation", vec![field(hash), field(collator), field(para), responder()]),
+			CollatorProtocolMessage::ReportCollator(collator) => ("ReportCollator", vec![field(collator)]),
+			CollatorProtocolMessage::NoteGoodCollation(collator) => ("NoteGoodCollation", vec![field(collator)]),
+			CollatorProtocolMessage::NotifyCollationSeconded(collator, statement) =>
+				("NotifyCollationSeconded", vec![field(collator), field(statement)]),
+			CollatorProtocolMessage::NetworkBridgeUpdateV1(event) =>
+				("NetworkBridgeUpdateV1", vec![field(event)]),
+			CollatorProtocolMessage::CollationFetchingRequest(req) =>
+				("CollationFetchingRequest", vec![incoming(req)]),
+		}),
+		AllMessages::StatementDistribution(m) => ("StatementDistribution", match m {
+			StatementDistributionMessage::Share(hash, statement) => ("Share", vec![field(hash), field(statement)]),
+			StatementDistributionMessage::NetworkBridgeUpdateV1(event) =>
+				("NetworkBridgeUpdateV1", vec![field(event)]),
+		}),
+		AllMessages::AvailabilityDistribution(m) => ("AvailabilityDistribution", match m {
+			AvailabilityDistributionMessage::ChunkFetchingRequest(req) =>
+				("ChunkFetchingRequest", vec![incoming(req)]),
+			AvailabilityDistributionMessage::PoVFetchingRequest(req) =>
+				("PoVFetchingRequest", vec![incoming(req)]),
+			AvailabilityDistributionMessage::FetchPoV { relay_parent, from_validator, candidate_hash, pov_hash, .. } =>
+				("FetchPoV", vec![
+					field(relay_parent),
+					field(from_validator),
+					field(candidate_hash),
+					field(pov_hash),
+					responder(),
+				]),
+		}),
+		AllMessages::AvailabilityRecovery(m) => ("AvailabilityRecovery", match m {
+			AvailabilityRecoveryMessage::RecoverAvailableData(receipt, session, group, _) =>
+				("RecoverAvailableData", vec![field(receipt), field(session), field(group), responder()]),
+			AvailabilityRecoveryMessage::AvailableDataFetchingRequest(req) =>
+				("AvailableDataFetchingRequest", vec![incoming(req)]),
+		}),
+		AllMessages::BitfieldDistribution(m) => ("BitfieldDistribution", match m {
+			BitfieldDistributionMessage::DistributeBitfield(hash, bitfield) =>
+				("DistributeBitfield", vec![field(hash), field(bitfield)]),
+			BitfieldDistributionMessage::NetworkBridgeUpdateV1(event) =>
+				("NetworkBridgeUpdateV1", vec![field(event)]),
+		}),
+		AllMessages::BitfieldSigning(m) => match *m {},
+		AllMessages::Provisioner(m) => ("Provisioner", match m {
+			ProvisionerMessage::RequestInherentData(hash, _) =>
+				("RequestInherentData", vec![field(hash), responder()]),
+			ProvisionerMessage::ProvisionableData(hash, data) =>
+				("ProvisionableData", vec![field(hash), field(data)]),
+		}),
+		AllMessages::RuntimeApi(m) => ("RuntimeApi", match m {
+			RuntimeApiMessage::Request(hash, req) => ("Request", vec![field(hash), runtime_api_request(req)]),
+		}),
+		AllMessages::AvailabilityStore(m) => ("AvailabilityStore", match m {
+			AvailabilityStoreMessage::QueryAvailableData(candidate, _) =>
+				("QueryAvailableData", vec![field(candidate), responder()]),
+			AvailabilityStoreMessage::QueryDataAvailability(candidate, _) =>
+				("QueryDataAvailability", vec![field(candidate), responder()]),
+			AvailabilityStoreMessage::QueryChunk(candidate, index, _) =>
+				("QueryChunk", vec![field(candidate), field(index), responder()]),
+			AvailabilityStoreMessage::QueryAllChunks(candidate, _) =>
+				("QueryAllChunks", vec![field(candidate), responder()]),
+			AvailabilityStoreMessage::QueryChunkAvailability(candidate, index, _) =>
+				("QueryChunkAvailability", vec![field(candidate), field(index), responder()]),
+			AvailabilityStoreMessage::StoreChunk { candidate_hash, chunk, .. } =>
+				("StoreChunk", vec![field(candidate_hash), field(chunk), responder()]),
+			AvailabilityStoreMessage::StoreAvailableData(candidate, index, n_validators, data, _) =>
+				("StoreAvailableData", vec![
+					field(candidate),
+					field(index),
+					field(n_validators),
+					field(data),
+					responder(),
+				]),
+		}),
+		AllMessages::NetworkBridge(m) => ("NetworkBridge", match m {
+			NetworkBridgeMessage::ReportPeer(peer, rep) => ("ReportPeer", vec![field(peer), field(rep)]),
+			NetworkBridgeMessage::DisconnectPeer(peer, peer_set) =>
+				("DisconnectPeer", vec![field(peer), field(peer_set)]),
+			NetworkBridgeMessage::SendValidationMessage(peers, msg) =>
+				("SendValidationMessage", vec![field(peers), field(msg)]),
+			NetworkBridgeMessage::SendCollationMessage(peers, msg) =>
+				("SendCollationMessage", vec![field(peers), field(msg)]),
+			NetworkBridgeMessage::SendValidationMessages(msgs) => ("SendValidationMessages", vec![field(msgs)]),
+			NetworkBridgeMessage::SendCollationMessages(msgs) => ("SendCollationMessages", vec![field(msgs)]),
+			NetworkBridgeMessage::SendRequests(reqs, if_disconnected) =>
+				("SendRequests", vec![outgoing(reqs), field(if_disconnected)]),
+			NetworkBridgeMessage::ConnectToValidators { validator_ids, peer_set, .. } =>
+				("ConnectToValidators", vec![field(validator_ids), field(peer_set), responder()]),
+		}),
+		AllMessages::CollationGeneration(m) => ("CollationGeneration", match m {
+			CollationGenerationMessage::Initialize(config) => ("Initialize", vec![field(config)]),
+		}),
+		AllMessages::ApprovalVoting(m) => ("ApprovalVoting", match m {
+			ApprovalVotingMessage::CheckAndImportAssignment(cert, candidate_index, _) =>
+				("CheckAndImportAssignment", vec![field(cert), field(candidate_index), responder()]),
+			ApprovalVotingMessage::CheckAndImportApproval(vote, _) =>
+				("CheckAndImportApproval", vec![field(vote), responder()]),
+			ApprovalVotingMessage::ApprovedAncestor(hash, number, _) =>
+				("ApprovedAncestor", vec![field(hash), field(number), responder()]),
+		}),
+		AllMessages::ApprovalDistribution(m) => ("ApprovalDistribution", match m {
+			ApprovalDistributionMessage::NewBlocks(metas) => ("NewBlocks", vec![field(metas)]),
+			ApprovalDistributionMessage::DistributeAssignment(cert, candidate_index) =>
+				("DistributeAssignment", vec![field(cert), field(candidate_index)]),
+			ApprovalDistributionMessage::DistributeApproval(vote) => ("DistributeApproval", vec![field(vote)]),
+			ApprovalDistributionMessage::NetworkBridgeUpdateV1(event) =>
+				("NetworkBridgeUpdateV1", vec![field(event)]),
+		}),
+		AllMessages::GossipSupport(m) => match *m {},
+	};
+
+	MessageProjection { subsystem, variant, fields }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use futures::channel::oneshot;
+	use polkadot_node_network_protocol::peer_set::PeerSet;
+	use polkadot_node_primitives::{BlockData, PoV};
+	use polkadot_node_subsystem::messages::NetworkBridgeEvent;
+	use polkadot_primitives::v1::{CandidateHash, Hash, Id as ParaId, ValidatorIndex};
+	use sc_network::PeerId;
+
+	fn pov(byte: u8) -> PoV {
+		PoV { block_data: BlockData(vec![byte]) }
+	}
+
+	/// One message for each inhabited `AllMessages` variant, with fresh responders.
+	fn samples(peer: &PeerId) -> Vec<AllMessages> {
+		let hash = Hash::repeat_byte(1);
+
+		vec![
+			AllMessages::CandidateValidation(CandidateValidationMessage::ValidateFromChainState(
+				Default::default(),
+				Arc::new(pov(1)),
+				oneshot::channel().0,
+			)),
+			AllMessages::CandidateBacking(CandidateBackingMessage::Second(hash, Default::default(), pov(1))),
+			AllMessages::CandidateSelection(CandidateSelectionMessage::Invalid(hash, Default::default())),
+			AllMessages::ChainApi(ChainApiMessage::BlockNumber(hash, oneshot::channel().0)),
+			AllMessages::CollatorProtocol(CollatorProtocolMessage::CollateOn(ParaId::from(1))),
+			AllMessages::StatementDistribution(StatementDistributionMessage::NetworkBridgeUpdateV1(
+				NetworkBridgeEvent::PeerDisconnected(peer.clone()),
+			)),
+			AllMessages::AvailabilityDistribution(AvailabilityDistributionMessage::FetchPoV {
+				relay_parent: hash,
+				from_validator: ValidatorIndex(1),
+				candidate_hash: CandidateHash(hash),
+				pov_hash: hash,
+				tx: oneshot::channel().0,
+			}),
+			AllMessages::AvailabilityRecovery(AvailabilityRecoveryMessage::RecoverAvailableData(
+				Default::default(),
+				1,
+				None,
+				oneshot::channel().0,
+			)),
+			AllMessages::BitfieldDistribution(BitfieldDistributionMessage::NetworkBridgeUpdateV1(
+				NetworkBridgeEvent::PeerDisconnected(peer.clone()),
+			)),
+			AllMessages::Provisioner(ProvisionerMessage::RequestInherentData(hash, oneshot::channel().0)),
+			AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+				hash,
+				RuntimeApiRequest::Validators(oneshot::channel().0),
+			)),
+			AllMessages::AvailabilityStore(AvailabilityStoreMessage::QueryChunk(
+				CandidateHash(hash),
+				ValidatorIndex(1),
+				oneshot::channel().0,
+			)),
+			AllMessages::NetworkBridge(NetworkBridgeMessage::DisconnectPeer(peer.clone(), PeerSet::Validation)),
+			AllMessages::ApprovalVoting(ApprovalVotingMessage::ApprovedAncestor(hash, 1, oneshot::channel().0)),
+			AllMessages::ApprovalDistribution(ApprovalDistributionMessage::NewBlocks(Vec::new())),
+		]
+	}
+
+	#[test]
+	fn every_variant_equals_its_twin_only() {
+		let peer = PeerId::random();
+
+		for (i, a) in samples(&peer).iter().enumerate() {
+			for (j, b) in samples(&peer).iter().enumerate() {
+				assert_eq!(
+					messages_structurally_equal(a, b),
+					i == j,
+					"unexpected comparison result between {:?} and {:?}",
+					a,
+					b,
+				);
+			}
+		}
+	}
+
+	#[test]
+	fn responders_are_wildcards() {
+		let (tx_a, _rx_a) = oneshot::channel();
+		let (tx_b, rx_b) = oneshot::channel();
+		drop(rx_b);
+
+		let a = AllMessages::ChainApi(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx_a));
+		let b = AllMessages::ChainApi(ChainApiMessage::BlockNumber(Hash::repeat_byte(1), tx_b));
+
+		assert!(messages_structurally_equal(&a, &b));
+	}
+
+	#[test]
+	fn fields_are_compared() {
+		let a = AllMessages::CandidateBacking(CandidateBackingMessage::Second(
+			Hash::repeat_byte(1),
+			Default::default(),
+			pov(1),
+		));
+		let b = AllMessages::CandidateBacking(CandidateBackingMessage::Second(
+			Hash::repeat_byte(1),
+			Default::default(),
+			pov(2),
+		));
+		let c = AllMessages::CandidateBacking(CandidateBackingMessage::Second(
+			Hash::repeat_byte(2),
+			Default::default(),
+			pov(1),
+		));
+
+		assert!(!messages_structurally_equal(&a, &b));
+		assert!(!messages_structurally_equal(&a, &c));
+	}
+
+	#[test]
+	fn runtime_api_requests_are_compared() {
+		let hash = Hash::repeat_byte(1);
+		let a = AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+			hash,
+			RuntimeApiRequest::SessionInfo(1, oneshot::channel().0),
+		));
+		let b = AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+			hash,
+			RuntimeApiRequest::SessionInfo(2, oneshot::channel().0),
+		));
+		let c = AllMessages::RuntimeApi(RuntimeApiMessage::Request(
+			hash,
+			RuntimeApiRequest::CandidateEvents(oneshot::channel().0),
+		));
+
+		assert!(!messages_structurally_equal(&a, &b));
+		assert!(!messages_structurally_equal(&a, &c));
+	}
+}
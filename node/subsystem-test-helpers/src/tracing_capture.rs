@@ -0,0 +1,212 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Capturing `tracing` events and spans for assertions on what a subsystem logged and did.
+
+use std::fmt;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tracing::field::{Field, Visit};
+use tracing::span::Id;
+use tracing::{Dispatch, Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// A `tracing` event captured by a [`TracingCapture`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedEvent {
+	/// The level of the event.
+	pub level: Level,
+	/// The target of the event.
+	pub target: String,
+	/// The message of the event, followed by its other fields as `name=value`.
+	pub message: String,
+}
+
+impl fmt::Display for CapturedEvent {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} {}: {}", self.level, self.target, self.message)
+	}
+}
+
+/// Collects the `tracing` events and spans of a test.
+///
+/// Events are only captured where the capture's [`Self::dispatch`] is the current default,
+/// which [`traced_subsystem_test_harness`](crate::traced_subsystem_test_harness) arranges for the
+/// whole test, tasks spawned by the subsystem included. Captures of different tests therefore
+/// don't mix, even if the tests run in parallel.
+#[derive(Clone, Default)]
+pub struct TracingCapture {
+	events: Arc<Mutex<Vec<CapturedEvent>>>,
+	spans: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl TracingCapture {
+	/// Create a capture which hasn't seen any events yet.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// A dispatcher recording all events into this capture.
+	pub fn dispatch(&self) -> Dispatch {
+		Dispatch::new(tracing_subscriber::registry().with(CaptureLayer {
+			events: self.events.clone(),
+			spans: self.spans.clone(),
+		}))
+	}
+
+	/// All events captured so far.
+	pub fn events(&self) -> Vec<CapturedEvent> {
+		self.events.lock().clone()
+	}
+
+	/// The names of the spans entered so far, in the order they were first entered.
+	///
+	/// A span entered repeatedly, like the span of an instrumented future on every poll, is only
+	/// listed once. Spans of concurrent tasks appear interleaved, so assert on the relative order
+	/// of spans of the same task only.
+	pub fn spans(&self) -> Vec<&'static str> {
+		self.spans.lock().clone()
+	}
+
+	/// Assert an event of `level` containing `substring` has been captured.
+	pub fn assert_logged(&self, level: Level, substring: &str) {
+		let events = self.events.lock();
+		if !events.iter().any(|event| event.level == level && event.message.contains(substring)) {
+			panic!(
+				"No {} event containing {:?} was logged, captured events:\n{}",
+				level,
+				substring,
+				events.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"),
+			);
+		}
+	}
+}
+
+struct CaptureLayer {
+	events: Arc<Mutex<Vec<CapturedEvent>>>,
+	spans: Arc<Mutex<Vec<&'static str>>>,
+}
+
+/// Marks a span as entered before, in the span's extensions.
+struct Entered;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for CaptureLayer {
+	fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+		let span = match ctx.span(id) {
+			Some(span) => span,
+			None => return,
+		};
+
+		let mut extensions = span.extensions_mut();
+		if extensions.get_mut::<Entered>().is_none() {
+			extensions.insert(Entered);
+			self.spans.lock().push(span.name());
+		}
+	}
+
+	fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+		let mut visitor = MessageVisitor::default();
+		event.record(&mut visitor);
+
+		let metadata = event.metadata();
+		self.events.lock().push(CapturedEvent {
+			level: *metadata.level(),
+			target: metadata.target().to_owned(),
+			message: visitor.message,
+		});
+	}
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+	message: String,
+}
+
+impl MessageVisitor {
+	fn push(&mut self, field: &Field, value: fmt::Arguments) {
+		if !self.message.is_empty() {
+			self.message.push(' ');
+		}
+
+		if field.name() == "message" {
+			self.message.push_str(&value.to_string());
+		} else {
+			self.message.push_str(&format!("{}={}", field.name(), value));
+		}
+	}
+}
+
+impl Visit for MessageVisitor {
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.push(field, format_args!("{}", value));
+	}
+
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		self.push(field, format_args!("{:?}", value));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::channel::oneshot;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use tracing::Instrument;
+	use crate::{TestSubsystemContextHandle, traced_subsystem_test_harness};
+
+	fn logging_subsystem() -> TracingCapture {
+		traced_subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+			},
+			|mut ctx| async move {
+				let (tx, rx) = oneshot::channel();
+				ctx.spawn("fetch", Box::pin(async move {
+					tracing::error!(target: "test", attempt = 2, "failed to fetch chunk");
+					let _ = tx.send(());
+				}.instrument(tracing::info_span!("fetch_chunk")))).await.unwrap();
+				rx.await.unwrap();
+
+				let span = tracing::info_span!("fallback");
+				let _guard = span.enter();
+				tracing::warn!(target: "test", "continuing without chunk");
+				assert!(matches!(ctx.recv().await, Ok(FromOverseer::Signal(OverseerSignal::Conclude))));
+			},
+		)
+	}
+
+	#[test]
+	fn events_of_the_subsystem_and_its_tasks_are_captured() {
+		let capture = logging_subsystem();
+
+		capture.assert_logged(Level::ERROR, "failed to fetch");
+		capture.assert_logged(Level::ERROR, "attempt=2");
+		capture.assert_logged(Level::WARN, "continuing");
+	}
+
+	#[test]
+	fn spans_are_listed_once_in_entry_order() {
+		assert_eq!(logging_subsystem().spans(), vec!["fetch_chunk", "fallback"]);
+	}
+
+	#[test]
+	#[should_panic(expected = "No ERROR event containing \"continuing\"")]
+	fn levels_are_distinguished() {
+		logging_subsystem().assert_logged(Level::ERROR, "continuing");
+	}
+}
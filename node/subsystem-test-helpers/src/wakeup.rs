@@ -0,0 +1,134 @@
+// Copyright 2021 Parity Technologies (UK) Ltd.
+// This file is part of Polkadot.
+
+// Polkadot is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Polkadot is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Polkadot.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Counting the wakeups of a task, to detect spurious wakeups.
+//!
+//! How often a task is woken depends on the executor and on every future it awaits, so exact
+//! counts are not portable. Assert them as upper bounds, e.g. the number of inputs plus a small
+//! constant, to catch waker storms without tying tests to implementation details.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+
+use futures::prelude::*;
+use futures::task::{ArcWake, waker};
+use pin_project::pin_project;
+
+/// A shared count of wakeups.
+#[derive(Clone, Default)]
+pub struct WakeupCounter(Arc<AtomicUsize>);
+
+impl WakeupCounter {
+	/// The number of wakeups so far.
+	pub fn get(&self) -> usize {
+		self.0.load(Ordering::Relaxed)
+	}
+
+	/// Wrap `inner` into a waker counting its wakeups on this counter before forwarding them.
+	pub fn wrap(&self, inner: Waker) -> Waker {
+		waker(Arc::new(CountingWaker { inner, counter: self.clone() }))
+	}
+}
+
+struct CountingWaker {
+	inner: Waker,
+	counter: WakeupCounter,
+}
+
+impl ArcWake for CountingWaker {
+	fn wake_by_ref(arc_self: &Arc<Self>) {
+		arc_self.counter.0.fetch_add(1, Ordering::Relaxed);
+		arc_self.inner.wake_by_ref();
+	}
+}
+
+/// A future counting how often it is woken, from whatever source.
+#[pin_project]
+pub struct WakeupCounting<F> {
+	#[pin]
+	inner: F,
+	counter: WakeupCounter,
+}
+
+impl<F> WakeupCounting<F> {
+	/// Wrap `inner`.
+	pub fn new(inner: F) -> Self {
+		WakeupCounting {
+			inner,
+			counter: WakeupCounter::default(),
+		}
+	}
+
+	/// A counter which remains readable after the future has been moved.
+	pub fn counter(&self) -> WakeupCounter {
+		self.counter.clone()
+	}
+}
+
+impl<F: Future> Future for WakeupCounting<F> {
+	type Output = F::Output;
+
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+		let this = self.project();
+		let waker = this.counter.wrap(cx.waker().clone());
+		this.inner.poll(&mut Context::from_waker(&waker))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use polkadot_node_subsystem::{FromOverseer, OverseerSignal, SubsystemContext};
+	use crate::{TestSubsystemContextHandle, subsystem_test_harness};
+
+	#[test]
+	fn counts_each_wake() {
+		let mut woken = 0;
+		let future = WakeupCounting::new(future::poll_fn(move |cx| {
+			if woken == 3 {
+				Poll::Ready(())
+			} else {
+				woken += 1;
+				cx.waker().wake_by_ref();
+				Poll::Pending
+			}
+		}));
+		let counter = future.counter();
+
+		futures::executor::block_on(future);
+		assert_eq!(counter.get(), 3);
+	}
+
+	#[test]
+	fn rendezvous_wakes_the_subsystem_at_most_once_per_input() {
+		const INPUTS: usize = 11;
+
+		subsystem_test_harness(
+			|mut handle: TestSubsystemContextHandle<()>| async move {
+				for _ in 1..INPUTS {
+					handle.send_dummy().await;
+				}
+				handle.send(FromOverseer::Signal(OverseerSignal::Conclude)).await;
+				assert!(handle.wakeup_count() <= INPUTS + 1, "woken {} times", handle.wakeup_count());
+			},
+			|mut ctx| async move {
+				while let Ok(FromOverseer::Communication { .. }) = ctx.recv().await {}
+			},
+		);
+	}
+}